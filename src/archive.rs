@@ -1,12 +1,20 @@
-use chrono::Local;
-use glob::Pattern;
-use std::fs::File;
-use std::io::{Read, Write};
+use crate::cancel;
+use crate::exclude::{Exclusion, Filter, Rules};
+use crate::progress::{Progress, Stage, Unit};
+use chrono::{Datelike, Local, TimeZone, Timelike};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use serde::Deserialize;
+use std::fs::{File, Metadata};
+use std::io::{Cursor, Read, Write};
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::SystemTime;
 use thiserror::Error;
 use walkdir::WalkDir;
 use zip::write::FileOptions;
-use zip::ZipWriter;
+use zip::{DateTime, ZipArchive, ZipWriter};
 
 #[derive(Error, Debug)]
 pub enum ArchiveError {
@@ -18,99 +26,689 @@ pub enum ArchiveError {
     DirNotFound(String),
     #[error("failed to get current directory")]
     NoCwd,
+    #[error("{0} is not a tar format")]
+    NotTar(Format),
 }
 
-/// Create a zip archive of a directory
+/// Archive formats quick-copy can create, send and extract
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Format {
+    #[default]
+    #[serde(rename = "zip")]
+    #[value(name = "zip")]
+    Zip,
+    #[serde(rename = "tar.gz", alias = "tgz")]
+    #[value(name = "tar.gz", alias = "tgz")]
+    TarGz,
+    #[serde(rename = "tar.zst")]
+    #[value(name = "tar.zst")]
+    TarZst,
+}
+
+impl Format {
+    pub fn extension(self) -> &'static str {
+        match self {
+            Format::Zip => "zip",
+            Format::TarGz => "tar.gz",
+            Format::TarZst => "tar.zst",
+        }
+    }
+
+    /// Format of an archive, from the extension of its name
+    pub fn from_name(name: &str) -> Option<Self> {
+        [Format::Zip, Format::TarGz, Format::TarZst]
+            .into_iter()
+            .find(|f| name.ends_with(&format!(".{}", f.extension())))
+    }
+
+    /// Programs that create this format on a remote host
+    pub fn packers(self) -> &'static [&'static str] {
+        match self {
+            Format::Zip => &["zip"],
+            Format::TarGz => &["tar"],
+            Format::TarZst => &["tar", "zstd"],
+        }
+    }
+
+    /// Programs that extract this format on a remote host
+    pub fn unpackers(self) -> &'static [&'static str] {
+        match self {
+            Format::Zip => &["unzip"],
+            Format::TarGz => &["tar"],
+            Format::TarZst => &["tar", "zstd"],
+        }
+    }
+
+    /// Format a directory is streamed in: a zip can't be written in one pass, so it goes as tar.gz
+    pub fn for_stream(self) -> Self {
+        match self {
+            Format::Zip => Format::TarGz,
+            format => format,
+        }
+    }
+}
+
+impl std::fmt::Display for Format {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.extension())
+    }
+}
+
+/// Bytes an archive adds per entry for headers and the central directory
+const ENTRY_OVERHEAD: u64 = 512;
+
+/// Largest entry a zip can describe without Zip64 extra fields
+const ZIP32_LIMIT: u64 = u32::MAX as u64;
+
+/// Files up to this size are compressed in memory on worker threads; bigger ones stream through on their own
+const PARALLEL_FILE_LIMIT: u64 = 8 << 20;
+
+/// Most file bytes compressed ahead of the writer at once, which bounds the memory workers hold
+const BATCH_BYTES: u64 = 64 << 20;
+
+/// Threads to compress with when none are configured: one per core
+pub fn default_jobs() -> usize {
+    std::thread::available_parallelism().map(|n| n.get()).unwrap_or(1)
+}
+
+/// A file, directory or symlink selected for an archive
+struct Entry {
+    path: PathBuf,
+    relative: String,
+    kind: EntryKind,
+    size: u64,
+    /// Permission bits, without the file type
+    mode: u32,
+    mtime: SystemTime,
+}
+
+enum EntryKind {
+    File,
+    Dir,
+    /// A symlink kept as a link, pointing at this target
+    Symlink(PathBuf),
+}
+
+/// Create an archive of a directory in `staging_dir`
+///
+/// Entries keep their permissions and modification times. Symlinks are
+/// stored as links unless `follow_symlinks` is set, in which case what they
+/// point at is archived in their place. Compression is spread over `jobs`
+/// threads for zip and tar.zst; the archive comes out the same either way.
 pub fn create_archive(
     source_dir: &Path,
-    excludes: &[String],
+    rules: &Rules,
     staging_dir: &str,
     custom_name: Option<&str>,
+    format: Format,
+    follow_symlinks: bool,
+    jobs: usize,
 ) -> Result<PathBuf, ArchiveError> {
     if !source_dir.is_dir() {
         return Err(ArchiveError::DirNotFound(source_dir.display().to_string()));
     }
 
-    let dir_name = source_dir
-        .file_name()
-        .and_then(|n| n.to_str())
-        .unwrap_or("archive");
+    let archive_name = archive_name(source_dir, custom_name, format.extension());
 
-    let timestamp = Local::now().format("%Y%m%d-%H%M%S");
-    let archive_name = custom_name
-        .map(|n| n.to_string())
-        .unwrap_or_else(|| format!("{}-{}.zip", dir_name, timestamp));
+    // Walk first so progress has a total to measure against
+    let entries = collect_entries(source_dir, rules, follow_symlinks);
+    let total = entries.iter().map(|e| e.size).sum();
 
     let archive_path = PathBuf::from(staging_dir).join(&archive_name);
+    let partial = cancel::Artifact::local(&archive_path);
     let file = File::create(&archive_path)?;
+
+    let mut progress = Progress::start(Stage::Archive, Unit::Bytes, Some(total));
+    match format {
+        Format::Zip => write_zip(&entries, file, jobs, &mut progress)?,
+        _ => write_tar(&entries, format, follow_symlinks, jobs, file, &mut progress)?,
+    }
+    progress.finish();
+    partial.keep();
+    Ok(archive_path)
+}
+
+/// Write entries into a zip, streaming each file through in bounded chunks
+///
+/// Small files are compressed a batch at a time on `jobs` threads, then
+/// copied in as already-compressed data in walk order, so the result doesn't
+/// depend on how many threads there were. Zip64 is switched on per file for
+/// ones over 4 GB; the zip writer adds the Zip64 end records itself once the
+/// archive as a whole passes that size.
+fn write_zip(entries: &[Entry], file: File, jobs: usize, progress: &mut Progress) -> Result<(), ArchiveError> {
     let mut zip = ZipWriter::new(file);
+    for batch in batches(entries) {
+        let compressed = compress_batch(batch, jobs)?;
+        for (entry, compressed) in batch.iter().zip(compressed) {
+            let options = zip_options(entry);
+            match (&entry.kind, compressed) {
+                (EntryKind::Dir, _) => zip.add_directory(entry.relative.as_str(), options)?,
+                (EntryKind::Symlink(target), _) => {
+                    zip.add_symlink(entry.relative.as_str(), target.to_string_lossy(), options)?
+                }
+                (EntryKind::File, Some(part)) => {
+                    let mut part = ZipArchive::new(Cursor::new(part))?;
+                    zip.raw_copy_file(part.by_index_raw(0)?)?;
+                    progress.inc(entry.size);
+                }
+                (EntryKind::File, None) => {
+                    let f = File::open(&entry.path)?;
+                    // Size the header from the open file, in case it grew since the walk
+                    let size = f.metadata()?.len();
+                    zip.start_file(entry.relative.as_str(), options.large_file(size > ZIP32_LIMIT))?;
+                    std::io::copy(&mut Counted::new(f.take(size), progress), &mut zip)?;
+                }
+            }
+        }
+    }
+    zip.finish()?;
+    Ok(())
+}
+
+fn zip_options(entry: &Entry) -> FileOptions<'static, ()> {
+    FileOptions::default()
+        .compression_method(zip::CompressionMethod::Deflated)
+        .unix_permissions(entry.mode)
+        .last_modified_time(zip_time(entry.mtime))
+}
 
-    let exclude_patterns: Vec<Pattern> = excludes
+/// Whether an entry is small enough to compress on a worker thread
+fn is_parallel(entry: &Entry) -> bool {
+    matches!(entry.kind, EntryKind::File) && entry.size <= PARALLEL_FILE_LIMIT
+}
+
+/// Split entries into runs holding at most [`BATCH_BYTES`] of files to compress in parallel
+fn batches(entries: &[Entry]) -> Vec<&[Entry]> {
+    let mut batches = Vec::new();
+    let (mut start, mut bytes) = (0, 0);
+    for (i, entry) in entries.iter().enumerate() {
+        if !is_parallel(entry) {
+            continue;
+        }
+        if bytes + entry.size > BATCH_BYTES && i > start {
+            batches.push(&entries[start..i]);
+            (start, bytes) = (i, 0);
+        }
+        bytes += entry.size;
+    }
+    if start < entries.len() {
+        batches.push(&entries[start..]);
+    }
+    batches
+}
+
+/// Compress the small files of a batch on up to `jobs` threads
+///
+/// Each comes back as a one-entry zip in memory, at its position in the
+/// batch; entries left to the writer are `None`.
+fn compress_batch(batch: &[Entry], jobs: usize) -> Result<Vec<Option<Vec<u8>>>, ArchiveError> {
+    let work: Vec<usize> = (0..batch.len()).filter(|&i| is_parallel(&batch[i])).collect();
+    let next = AtomicUsize::new(0);
+    let claim = || work.get(next.fetch_add(1, Ordering::Relaxed)).copied();
+
+    let done: Vec<_> = std::thread::scope(|scope| {
+        let workers: Vec<_> = (0..jobs.clamp(1, work.len().max(1)))
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut done = Vec::new();
+                    while let Some(i) = claim() {
+                        done.push((i, compress_entry(&batch[i])));
+                    }
+                    done
+                })
+            })
+            .collect();
+        workers
+            .into_iter()
+            .flat_map(|w| w.join().expect("compression thread panicked"))
+            .collect()
+    });
+
+    let mut compressed = vec![None; batch.len()];
+    for (i, part) in done {
+        compressed[i] = Some(part?);
+    }
+    Ok(compressed)
+}
+
+/// Compress one file into a zip of its own, to be copied into the real one
+fn compress_entry(entry: &Entry) -> Result<Vec<u8>, ArchiveError> {
+    let mut part = ZipWriter::new(Cursor::new(Vec::new()));
+    part.start_file(entry.relative.as_str(), zip_options(entry))?;
+    std::io::copy(&mut File::open(&entry.path)?, &mut part)?;
+    Ok(part.finish()?.into_inner())
+}
+
+/// Upper bound on the size of an archive of `source_dir`, from the walk that would build it
+///
+/// Compression rarely makes data bigger, so the uncompressed total plus
+/// headers is a safe figure to check free space against.
+pub fn estimate_size(source_dir: &Path, rules: &Rules, follow_symlinks: bool) -> Result<u64, ArchiveError> {
+    if !source_dir.is_dir() {
+        return Err(ArchiveError::DirNotFound(source_dir.display().to_string()));
+    }
+    Ok(collect_entries(source_dir, rules, follow_symlinks)
         .iter()
-        .filter_map(|p| Pattern::new(p).ok())
-        .collect();
+        .map(|e| e.size + ENTRY_OVERHEAD)
+        .sum())
+}
 
-    let options = FileOptions::<()>::default()
-        .compression_method(zip::CompressionMethod::Deflated)
-        .unix_permissions(0o644);
+/// Write a compressed tar of a directory to `writer` while walking it
+///
+/// Nothing is staged on disk, so the archive can go straight into an ssh
+/// channel. Progress counts source bytes as they are sent; returns their total.
+/// Permissions, times and symlinks are kept as [`create_archive`] keeps them.
+pub fn stream_tar(
+    source_dir: &Path,
+    rules: &Rules,
+    format: Format,
+    follow_symlinks: bool,
+    jobs: usize,
+    writer: impl Write,
+) -> Result<u64, ArchiveError> {
+    if !source_dir.is_dir() {
+        return Err(ArchiveError::DirNotFound(source_dir.display().to_string()));
+    }
+
+    let entries = collect_entries(source_dir, rules, follow_symlinks);
+    let total = entries.iter().map(|e| e.size).sum();
+
+    let mut progress = Progress::start(Stage::Upload, Unit::Bytes, Some(total));
+    write_tar(&entries, format, follow_symlinks, jobs, writer, &mut progress)?;
+    progress.finish();
+    Ok(total)
+}
+
+/// Write entries into a compressed tar; zstd compresses on `jobs` threads, gzip on one
+fn write_tar(
+    entries: &[Entry],
+    format: Format,
+    follow_symlinks: bool,
+    jobs: usize,
+    writer: impl Write,
+    progress: &mut Progress,
+) -> Result<(), ArchiveError> {
+    let mut tar = tar::Builder::new(Compressor::new(format, writer, jobs)?);
+    tar.follow_symlinks(follow_symlinks);
+    for entry in entries {
+        match &entry.kind {
+            EntryKind::Dir => tar.append_dir(&entry.relative, &entry.path)?,
+            EntryKind::Symlink(target) => {
+                let mut header = tar::Header::new_gnu();
+                header.set_entry_type(tar::EntryType::Symlink);
+                header.set_size(0);
+                header.set_mode(entry.mode);
+                header.set_mtime(unix_secs(entry.mtime));
+                tar.append_link(&mut header, &entry.relative, target)?;
+            }
+            EntryKind::File => {
+                let f = File::open(&entry.path)?;
+                let mut header = tar::Header::new_gnu();
+                header.set_metadata(&f.metadata()?);
+                let size = header.size()?;
+                tar.append_data(&mut header, &entry.relative, Counted::new(f.take(size), progress))?;
+            }
+        }
+    }
+
+    tar.into_inner()?.finish()?.flush()?;
+    Ok(())
+}
+
+/// A reader that counts the bytes read through it as progress
+struct Counted<'a, R> {
+    inner: R,
+    progress: &'a mut Progress,
+}
+
+impl<'a, R: Read> Counted<'a, R> {
+    fn new(inner: R, progress: &'a mut Progress) -> Self {
+        Self { inner, progress }
+    }
+}
+
+impl<R: Read> Read for Counted<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.progress.inc(n as u64);
+        Ok(n)
+    }
+}
+
+/// Unpack a compressed tar read from `reader` into a directory
+pub fn unpack_tar(reader: impl Read, format: Format, dest_dir: &Path) -> Result<(), ArchiveError> {
+    std::fs::create_dir_all(dest_dir)?;
+    tar::Archive::new(decompressor(format, reader)?).unpack(dest_dir)?;
+    Ok(())
+}
+
+/// The compression around a tar stream
+enum Compressor<W: Write> {
+    Gzip(GzEncoder<W>),
+    Zstd(zstd::Encoder<'static, W>),
+}
+
+impl<W: Write> Compressor<W> {
+    fn new(format: Format, writer: W, jobs: usize) -> Result<Self, ArchiveError> {
+        Ok(match format {
+            Format::TarZst => {
+                let mut encoder = zstd::Encoder::new(writer, zstd::DEFAULT_COMPRESSION_LEVEL)?;
+                if jobs > 1 {
+                    encoder.multithread(jobs as u32)?;
+                }
+                Compressor::Zstd(encoder)
+            }
+            Format::TarGz => Compressor::Gzip(GzEncoder::new(writer, Compression::default())),
+            // A zip needs to seek back to finish each entry, so it is never streamed
+            Format::Zip => return Err(ArchiveError::NotTar(format)),
+        })
+    }
+
+    /// Write the end of the compressed stream, returning the writer
+    fn finish(self) -> std::io::Result<W> {
+        match self {
+            Compressor::Gzip(encoder) => encoder.finish(),
+            Compressor::Zstd(encoder) => encoder.finish(),
+        }
+    }
+}
+
+impl<W: Write> Write for Compressor<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Compressor::Gzip(encoder) => encoder.write(buf),
+            Compressor::Zstd(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Compressor::Gzip(encoder) => encoder.flush(),
+            Compressor::Zstd(encoder) => encoder.flush(),
+        }
+    }
+}
 
-    for entry in WalkDir::new(source_dir)
-        .follow_links(false)
-        .into_iter()
-        .filter_map(|e| e.ok())
-    {
+fn decompressor<'a>(format: Format, reader: impl Read + 'a) -> std::io::Result<Box<dyn Read + 'a>> {
+    Ok(match format {
+        Format::TarZst => Box::new(zstd::Decoder::new(reader)?),
+        _ => Box::new(GzDecoder::new(reader)),
+    })
+}
+
+/// Name for an archive of `source_dir`: `<dirname>-<timestamp>.<extension>` unless given
+///
+/// A given name has any archive extension it ends in replaced with this one,
+/// so the name always tells what the archive holds and how to unpack it.
+pub fn archive_name(source_dir: &Path, custom_name: Option<&str>, extension: &str) -> String {
+    let dir_name = source_dir
+        .file_name()
+        .and_then(|n| n.to_str())
+        .unwrap_or("archive");
+
+    match custom_name {
+        Some(name) => {
+            let stem = Format::from_name(name)
+                .and_then(|f| name.strip_suffix(&format!(".{}", f.extension())))
+                .unwrap_or(name);
+            format!("{}.{}", stem, extension)
+        }
+        None => format!("{}-{}.{}", dir_name, timestamp(), extension),
+    }
+}
+
+/// Walk a directory and return the entries that survive the exclude rules
+fn collect_entries(source_dir: &Path, rules: &Rules, follow_symlinks: bool) -> Vec<Entry> {
+    walk(source_dir, rules, follow_symlinks).0
+}
+
+/// What an archive of `source_dir` would leave out, and the rule behind each
+///
+/// An excluded directory is listed once, without its contents.
+pub fn exclusions(source_dir: &Path, rules: &Rules, follow_symlinks: bool) -> Result<Vec<Exclusion>, ArchiveError> {
+    if !source_dir.is_dir() {
+        return Err(ArchiveError::DirNotFound(source_dir.display().to_string()));
+    }
+    Ok(walk(source_dir, rules, follow_symlinks).1)
+}
+
+/// Walk a directory, splitting it into the entries to archive and the ones excluded
+fn walk(source_dir: &Path, rules: &Rules, follow_symlinks: bool) -> (Vec<Entry>, Vec<Exclusion>) {
+    let mut filter = Filter::new(source_dir, rules);
+
+    let mut entries = Vec::new();
+    let mut excluded = Vec::new();
+    let mut walker = WalkDir::new(source_dir)
+        .follow_links(follow_symlinks)
+        .sort_by_file_name()
+        .into_iter();
+    while let Some(entry) = walker.next() {
+        let Ok(entry) = entry else {
+            continue;
+        };
         let path = entry.path();
         let relative = path
             .strip_prefix(source_dir)
             .unwrap_or(path);
-
-        // Skip if matches any exclude pattern
         let relative_str = relative.to_string_lossy();
-        if exclude_patterns.iter().any(|p| p.matches(&relative_str)) {
+
+        // Nothing under an excluded directory is looked at, as in git
+        let is_dir = entry.file_type().is_dir();
+        if let Some(exclusion) = filter.excluded(path, is_dir) {
+            if is_dir {
+                walker.skip_current_dir();
+            }
+            excluded.push(exclusion);
             continue;
         }
 
-        // Also check path components for directory excludes
-        let should_skip = relative.components().any(|c| {
-            let comp = c.as_os_str().to_string_lossy();
-            exclude_patterns.iter().any(|p| {
-                let pattern_str = p.as_str();
-                // Handle directory patterns like ".git/" or "node_modules/"
-                if pattern_str.ends_with('/') {
-                    comp == pattern_str.trim_end_matches('/')
-                } else {
-                    p.matches(&comp)
-                }
-            })
+        // Without follow_links the metadata is the link's own, so links show up as links
+        let Ok(metadata) = entry.metadata() else {
+            continue;
+        };
+        let (kind, size) = if metadata.file_type().is_symlink() {
+            match std::fs::read_link(path) {
+                Ok(target) => (EntryKind::Symlink(target), 0),
+                Err(_) => continue,
+            }
+        } else if metadata.is_file() {
+            (EntryKind::File, metadata.len())
+        } else if metadata.is_dir() && !relative.as_os_str().is_empty() {
+            (EntryKind::Dir, 0)
+        } else {
+            continue;
+        };
+
+        entries.push(Entry {
+            path: path.to_path_buf(),
+            relative: relative_str.to_string(),
+            kind,
+            size,
+            mode: mode(&metadata),
+            mtime: metadata.modified().unwrap_or_else(|_| SystemTime::now()),
         });
+    }
 
-        if should_skip {
-            continue;
+    (entries, excluded)
+}
+
+/// Extract an archive into a directory, restoring permissions, times and symlinks
+///
+/// The format is taken from the archive's extension; names without a known
+/// one are read as zip.
+pub fn extract_archive(archive_path: &Path, dest_dir: &Path) -> Result<(), ArchiveError> {
+    let format = Format::from_name(&archive_path.to_string_lossy()).unwrap_or_default();
+    match format {
+        Format::Zip => extract_zip(archive_path, dest_dir),
+        _ => extract_tar(archive_path, format, dest_dir),
+    }
+}
+
+/// Extract a tar, leaving directories until last as `tar::Archive::unpack` does, so their times stick
+fn extract_tar(archive_path: &Path, format: Format, dest_dir: &Path) -> Result<(), ArchiveError> {
+    std::fs::create_dir_all(dest_dir)?;
+    let mut archive = tar::Archive::new(decompressor(format, File::open(archive_path)?)?);
+
+    let mut dirs = Vec::new();
+    let mut progress = Progress::start(Stage::Extract, Unit::Files, None);
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        if entry.header().entry_type().is_dir() {
+            dirs.push(entry);
+        } else {
+            entry.unpack_in(dest_dir)?;
         }
+        progress.inc(1);
+    }
+    // Deepest first, so setting a parent's time isn't undone by its children
+    dirs.sort_by(|a, b| b.path_bytes().cmp(&a.path_bytes()));
+    for mut dir in dirs {
+        dir.unpack_in(dest_dir)?;
+    }
+
+    progress.finish();
+    Ok(())
+}
+
+/// Extract a zip archive
+///
+/// Symlinks are only created once every file is written, so no entry can be
+/// written through a link to outside `dest_dir`. Directories get their
+/// permissions and times last, since writing into them changes both.
+fn extract_zip(archive_path: &Path, dest_dir: &Path) -> Result<(), ArchiveError> {
+    let file = File::open(archive_path)?;
+    let mut archive = ZipArchive::new(file)?;
+
+    let mut links = Vec::new();
+    let mut dirs = Vec::new();
+    let mut progress = Progress::start(Stage::Extract, Unit::Files, Some(archive.len() as u64));
+    for i in 0..archive.len() {
+        let mut entry = archive.by_index(i)?;
 
-        if path.is_file() {
-            zip.start_file(relative.to_string_lossy(), options)?;
-            let mut f = File::open(path)?;
-            let mut buffer = Vec::new();
-            f.read_to_end(&mut buffer)?;
-            zip.write_all(&buffer)?;
-        } else if path.is_dir() && !relative.as_os_str().is_empty() {
-            zip.add_directory(relative.to_string_lossy(), options)?;
+        let outpath = match entry.enclosed_name() {
+            Some(path) => dest_dir.join(path),
+            None => continue,
+        };
+        // Archives from zip tools that record no unix mode fall back to the umask
+        let mode = entry.unix_mode().map(|m| m & 0o7777);
+        let mtime = entry.last_modified().and_then(system_time);
+
+        if entry.is_dir() {
+            std::fs::create_dir_all(&outpath)?;
+            dirs.push((outpath, mode, mtime));
+        } else if entry.is_symlink() {
+            let mut target = String::new();
+            entry.read_to_string(&mut target)?;
+            links.push((outpath, target));
+        } else {
+            if let Some(p) = outpath.parent() {
+                if !p.exists() {
+                    std::fs::create_dir_all(p)?;
+                }
+            }
+            let mut outfile = File::create(&outpath)?;
+            std::io::copy(&mut entry, &mut outfile)?;
+            if let Some(mtime) = mtime {
+                outfile.set_modified(mtime)?;
+            }
+            if let Some(mode) = mode {
+                set_mode(&outpath, mode)?;
+            }
         }
+        progress.inc(1);
     }
 
-    zip.finish()?;
-    Ok(archive_path)
+    for (path, target) in links {
+        if let Some(p) = path.parent() {
+            std::fs::create_dir_all(p)?;
+        }
+        // Replace whatever an earlier extraction left there
+        let _ = std::fs::remove_file(&path);
+        symlink(Path::new(&target), &path)?;
+    }
+    // Deepest first, so setting a parent's time isn't undone by its children
+    for (path, mode, mtime) in dirs.into_iter().rev() {
+        if let Some(mtime) = mtime {
+            // Directories can't be opened for writing everywhere, so this is best effort
+            let _ = File::open(&path).and_then(|d| d.set_modified(mtime));
+        }
+        if let Some(mode) = mode {
+            set_mode(&path, mode)?;
+        }
+    }
+
+    progress.finish();
+    Ok(())
 }
 
-/// Get current working directory name
-pub fn cwd_name() -> Result<String, ArchiveError> {
-    std::env::current_dir()
-        .map_err(|_| ArchiveError::NoCwd)?
-        .file_name()
-        .and_then(|n| n.to_str())
-        .map(|s| s.to_string())
-        .ok_or(ArchiveError::NoCwd)
+/// A modification time as zip records it: local time, to two seconds, from 1980
+fn zip_time(time: SystemTime) -> DateTime {
+    let t = chrono::DateTime::<Local>::from(time);
+    DateTime::from_date_and_time(
+        t.year().clamp(1980, 2107) as u16,
+        t.month() as u8,
+        t.day() as u8,
+        t.hour() as u8,
+        t.minute() as u8,
+        t.second() as u8,
+    )
+    .unwrap_or_default()
+}
+
+/// The instant a zip timestamp stands for, read as local time like [`zip_time`] writes it
+fn system_time(time: DateTime) -> Option<SystemTime> {
+    Local
+        .with_ymd_and_hms(
+            time.year().into(),
+            time.month().into(),
+            time.day().into(),
+            time.hour().into(),
+            time.minute().into(),
+            time.second().into(),
+        )
+        .earliest()
+        .map(SystemTime::from)
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(SystemTime::UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+#[cfg(unix)]
+fn mode(metadata: &Metadata) -> u32 {
+    use std::os::unix::fs::PermissionsExt;
+    metadata.permissions().mode() & 0o7777
+}
+
+#[cfg(not(unix))]
+fn mode(metadata: &Metadata) -> u32 {
+    match (metadata.is_dir(), metadata.permissions().readonly()) {
+        (true, _) => 0o755,
+        (false, true) => 0o444,
+        (false, false) => 0o644,
+    }
+}
+
+#[cfg(unix)]
+fn set_mode(path: &Path, mode: u32) -> std::io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))
+}
+
+#[cfg(not(unix))]
+fn set_mode(_path: &Path, _mode: u32) -> std::io::Result<()> {
+    Ok(())
+}
+
+#[cfg(unix)]
+fn symlink(target: &Path, path: &Path) -> std::io::Result<()> {
+    std::os::unix::fs::symlink(target, path)
+}
+
+/// Without unix symlinks, write the target into a plain file as `unzip` does
+#[cfg(not(unix))]
+fn symlink(target: &Path, path: &Path) -> std::io::Result<()> {
+    std::fs::write(path, target.to_string_lossy().as_bytes())
 }
 
 /// Generate timestamp for archive naming
@@ -133,4 +731,172 @@ mod tests {
         assert!(ts.len() == 15); // YYYYMMDD-HHMMSS
         assert!(ts.contains('-'));
     }
+
+    #[test]
+    fn test_custom_name_carries_format_extension() {
+        let dir = Path::new("/src/app");
+        assert_eq!(archive_name(dir, Some("backup"), Format::TarGz.extension()), "backup.tar.gz");
+        assert_eq!(archive_name(dir, Some("backup.zip"), Format::Zip.extension()), "backup.zip");
+        assert_eq!(archive_name(dir, Some("backup.zip"), Format::TarZst.extension()), "backup.tar.zst");
+        assert_eq!(archive_name(dir, Some("backup.tar.gz"), Format::Zip.extension()), "backup.zip");
+        assert_eq!(archive_name(dir, Some("v1.2"), Format::Zip.extension()), "v1.2.zip");
+
+        // What extract goes by matches what was written
+        let name = archive_name(dir, Some("backup"), Format::TarGz.extension());
+        assert_eq!(Format::from_name(&name), Some(Format::TarGz));
+    }
+
+    #[test]
+    fn test_tar_stream_round_trip_respects_excludes() {
+        let tmp = tempfile::tempdir().unwrap();
+        let src = tmp.path().join("src");
+        std::fs::create_dir_all(src.join("node_modules/pkg")).unwrap();
+        std::fs::create_dir_all(src.join("nested")).unwrap();
+        std::fs::write(src.join("nested/keep.txt"), b"kept").unwrap();
+        std::fs::write(src.join("node_modules/pkg/index.js"), b"skipped").unwrap();
+        std::fs::write(src.join("scratch.tmp"), b"skipped").unwrap();
+
+        let mut stream = Vec::new();
+        let rules = Rules {
+            patterns: vec!["node_modules/".to_string(), "*.tmp".to_string()],
+            ignore_files: false,
+        };
+        let bytes = stream_tar(&src, &rules, Format::TarZst, false, 2, &mut stream).unwrap();
+        assert_eq!(bytes, 4);
+
+        let dest = tmp.path().join("dest");
+        unpack_tar(stream.as_slice(), Format::TarZst, &dest).unwrap();
+        assert_eq!(std::fs::read(dest.join("nested/keep.txt")).unwrap(), b"kept");
+        assert!(!dest.join("node_modules").exists());
+        assert!(!dest.join("scratch.tmp").exists());
+
+        // A zip can't be written in one pass, so streaming one is refused rather than sent as something else
+        assert!(matches!(
+            stream_tar(&src, &rules, Format::Zip, false, 1, std::io::sink()),
+            Err(ArchiveError::NotTar(Format::Zip))
+        ));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_round_trip_keeps_modes_times_and_links() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let tmp = tempfile::tempdir().unwrap();
+        let src = tmp.path().join("src");
+        std::fs::create_dir_all(src.join("bin")).unwrap();
+        std::fs::write(src.join("bin/run.sh"), b"#!/bin/sh\n").unwrap();
+        std::fs::set_permissions(src.join("bin/run.sh"), std::fs::Permissions::from_mode(0o755)).unwrap();
+        let mtime = SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1_600_000_000);
+        File::options().write(true).open(src.join("bin/run.sh")).unwrap().set_modified(mtime).unwrap();
+        std::os::unix::fs::symlink("bin/run.sh", src.join("run")).unwrap();
+
+        let staging = tmp.path().display().to_string();
+        let extract = |format: Format, follow: bool, name: &str| {
+            let archive = create_archive(&src, &Rules::default(), &staging, Some(name), format, follow, 2).unwrap();
+            let dest = tmp.path().join(name.replace('.', "-"));
+            extract_archive(&archive, &dest).unwrap();
+            dest
+        };
+
+        for (format, name) in [(Format::Zip, "links.zip"), (Format::TarGz, "links.tar.gz")] {
+            let dest = extract(format, false, name);
+            let script = std::fs::metadata(dest.join("bin/run.sh")).unwrap();
+            assert_eq!(script.permissions().mode() & 0o777, 0o755);
+            assert_eq!(script.modified().unwrap(), mtime);
+            assert_eq!(std::fs::read_link(dest.join("run")).unwrap(), Path::new("bin/run.sh"));
+        }
+
+        let dest = extract(Format::Zip, true, "followed.zip");
+        assert!(!std::fs::symlink_metadata(dest.join("run")).unwrap().file_type().is_symlink());
+        assert_eq!(std::fs::read(dest.join("run")).unwrap(), b"#!/bin/sh\n");
+    }
+
+    #[test]
+    fn test_parallel_zip_matches_single_threaded() {
+        let tmp = tempfile::tempdir().unwrap();
+        let src = tmp.path().join("src");
+        for i in 0..40 {
+            let dir = src.join(format!("pkg{}", i % 4));
+            std::fs::create_dir_all(&dir).unwrap();
+            std::fs::write(dir.join(format!("mod{}.rs", i)), format!("fn f{}() {{}}\n", i).repeat(i * 50)).unwrap();
+        }
+        // Too big to compress on a worker, so it streams between the others
+        File::create(src.join("pkg1/weights.bin")).unwrap().set_len(PARALLEL_FILE_LIMIT + 1).unwrap();
+
+        let staging = tmp.path().display().to_string();
+        let one = create_archive(&src, &Rules::default(), &staging, Some("one.zip"), Format::Zip, false, 1).unwrap();
+        let many = create_archive(&src, &Rules::default(), &staging, Some("many.zip"), Format::Zip, false, 4).unwrap();
+        assert_eq!(std::fs::read(&one).unwrap(), std::fs::read(&many).unwrap());
+
+        let dest = tmp.path().join("dest");
+        extract_archive(&many, &dest).unwrap();
+        assert_eq!(
+            std::fs::read(dest.join("pkg3/mod7.rs")).unwrap(),
+            "fn f7() {}\n".repeat(350).into_bytes()
+        );
+        assert_eq!(std::fs::metadata(dest.join("pkg1/weights.bin")).unwrap().len(), PARALLEL_FILE_LIMIT + 1);
+    }
+
+    /// Peak resident memory of this process so far, in bytes
+    #[cfg(target_os = "linux")]
+    fn peak_rss() -> u64 {
+        let status = std::fs::read_to_string("/proc/self/status").unwrap();
+        let line = status.lines().find(|l| l.starts_with("VmHWM:")).unwrap();
+        let kb: u64 = line.split_whitespace().nth(1).unwrap().parse().unwrap();
+        kb * 1024
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_large_files_stream_with_flat_memory() {
+        // Peak memory is counted per process, so measure in one that runs this test alone
+        const CHILD: &str = "QC_TEST_MEMORY_CHILD";
+        if std::env::var_os(CHILD).is_none() {
+            let output = std::process::Command::new(std::env::current_exe().unwrap())
+                .args(["--exact", "archive::tests::test_large_files_stream_with_flat_memory", "--test-threads=1"])
+                .env(CHILD, "1")
+                .output()
+                .unwrap();
+            let report = String::from_utf8_lossy(&output.stdout);
+            assert!(output.status.success() && report.contains("1 passed"), "child test run failed:\n{}", report);
+            return;
+        }
+
+        // Sparse, so it takes no disk space but far more memory than we allow to grow
+        let tmp = tempfile::tempdir().unwrap();
+        let src = tmp.path().join("src");
+        std::fs::create_dir(&src).unwrap();
+        let len = 512 << 20;
+        File::create(src.join("checkpoint.bin")).unwrap().set_len(len).unwrap();
+
+        let staging = tmp.path().display().to_string();
+        let before = peak_rss();
+        for (format, name) in [(Format::Zip, "big.zip"), (Format::TarZst, "big.tar.zst")] {
+            create_archive(&src, &Rules::default(), &staging, Some(name), format, false, 2).unwrap();
+        }
+        let stream = std::io::sink();
+        assert_eq!(stream_tar(&src, &Rules::default(), Format::TarGz, false, 1, stream).unwrap(), len);
+        assert!(peak_rss() - before < 64 << 20, "archiving grew memory by {} bytes", peak_rss() - before);
+
+        let zip = ZipArchive::new(File::open(tmp.path().join("big.zip")).unwrap()).unwrap();
+        assert_eq!(zip.decompressed_size(), Some(u128::from(len)));
+    }
+
+    /// Files over 4 GB need Zip64; sparse, so it takes no disk space
+    #[test]
+    fn test_zip64_for_files_over_4gb() {
+        let tmp = tempfile::tempdir().unwrap();
+        let src = tmp.path().join("src");
+        std::fs::create_dir(&src).unwrap();
+        let len = ZIP32_LIMIT + (1 << 20);
+        File::create(src.join("checkpoint.bin")).unwrap().set_len(len).unwrap();
+
+        let staging = tmp.path().display().to_string();
+        let path = create_archive(&src, &Rules::default(), &staging, Some("big.zip"), Format::Zip, false, 1).unwrap();
+        let mut zip = ZipArchive::new(File::open(path).unwrap()).unwrap();
+        let mut entry = zip.by_name("checkpoint.bin").unwrap();
+        assert_eq!(entry.size(), len);
+        assert_eq!(std::io::copy(&mut entry, &mut std::io::sink()).unwrap(), len);
+    }
 }
@@ -0,0 +1,182 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Mutex;
+
+/// Exit status after an interrupt, matching shells' 128 + SIGINT
+pub const EXIT_CANCELLED: u8 = 130;
+
+type Action = Box<dyn FnOnce() + Send>;
+
+struct Registered {
+    label: String,
+    action: Action,
+}
+
+static CANCELLED: AtomicBool = AtomicBool::new(false);
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+static ARTIFACTS: Mutex<BTreeMap<u64, Registered>> = Mutex::new(BTreeMap::new());
+static CHILDREN: Mutex<BTreeMap<u64, u32>> = Mutex::new(BTreeMap::new());
+
+fn lock<T>(m: &Mutex<T>) -> std::sync::MutexGuard<'_, T> {
+    m.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Something the current command created and must remove if it fails or is interrupted
+///
+/// Dropping the guard runs the cleanup; call [`Artifact::keep`] once the
+/// artifact is meant to outlive the command.
+#[must_use]
+pub struct Artifact {
+    id: u64,
+}
+
+impl Artifact {
+    /// Register a cleanup action, described by `label` when reporting a cancellation
+    pub fn new(label: impl Into<String>, action: impl FnOnce() + Send + 'static) -> Self {
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        lock(&ARTIFACTS).insert(
+            id,
+            Registered {
+                label: label.into(),
+                action: Box::new(action),
+            },
+        );
+        Self { id }
+    }
+
+    /// A local file to delete
+    pub fn local(path: &Path) -> Self {
+        let owned = path.to_path_buf();
+        Self::new(path.display().to_string(), move || {
+            let _ = std::fs::remove_file(owned);
+        })
+    }
+
+    /// Disarm the cleanup; the artifact is a result of the command
+    pub fn keep(self) {
+        lock(&ARTIFACTS).remove(&self.id);
+        std::mem::forget(self);
+    }
+}
+
+impl Drop for Artifact {
+    fn drop(&mut self) {
+        let registered = lock(&ARTIFACTS).remove(&self.id);
+        if let Some(r) = registered {
+            (r.action)();
+        }
+    }
+}
+
+/// A running child process to kill on interrupt
+pub struct ChildGuard {
+    id: u64,
+}
+
+impl ChildGuard {
+    pub fn new(child: &std::process::Child) -> Self {
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        lock(&CHILDREN).insert(id, child.id());
+
+        // Lost the race with the handler: don't leave this one running
+        if is_cancelled() {
+            kill(child.id());
+        }
+        Self { id }
+    }
+}
+
+impl Drop for ChildGuard {
+    fn drop(&mut self) {
+        lock(&CHILDREN).remove(&self.id);
+    }
+}
+
+pub fn is_cancelled() -> bool {
+    CANCELLED.load(Ordering::SeqCst)
+}
+
+/// Install the interrupt handler; `json` selects how the cancellation is reported
+pub fn install(json: bool) {
+    let _ = ctrlc::set_handler(move || interrupt(json));
+}
+
+/// Stop children, clean up artifacts newest-first, report, and exit
+fn interrupt(json: bool) {
+    if CANCELLED.swap(true, Ordering::SeqCst) {
+        return;
+    }
+
+    let pids: Vec<u32> = lock(&CHILDREN).values().copied().collect();
+    for pid in pids {
+        kill(pid);
+    }
+
+    let artifacts = std::mem::take(&mut *lock(&ARTIFACTS));
+    let mut removed = Vec::new();
+    for (_, r) in artifacts.into_iter().rev() {
+        (r.action)();
+        if !r.label.is_empty() {
+            removed.push(r.label);
+        }
+    }
+
+    report(json, &removed);
+    std::process::exit(EXIT_CANCELLED.into());
+}
+
+fn report(json: bool, removed: &[String]) {
+    if json {
+        #[derive(serde::Serialize)]
+        struct Cancelled<'a> {
+            cancelled: bool,
+            cleaned_up: &'a [String],
+        }
+
+        let output = Cancelled {
+            cancelled: true,
+            cleaned_up: removed,
+        };
+        if let Ok(json) = serde_json::to_string_pretty(&output) {
+            println!("{}", json);
+        }
+    } else {
+        eprintln!("\ninterrupted");
+        for path in removed {
+            eprintln!("  removed {}", path);
+        }
+    }
+}
+
+/// Ask a child process to stop
+#[cfg(unix)]
+pub fn kill(pid: u32) {
+    // SAFETY: kill(2) has no memory-safety preconditions
+    unsafe {
+        libc::kill(pid as libc::pid_t, libc::SIGTERM);
+    }
+}
+
+#[cfg(not(unix))]
+pub fn kill(_pid: u32) {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_artifact_removed_unless_kept() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dropped = tmp.path().join("partial.zip");
+        let kept = tmp.path().join("result.zip");
+        std::fs::write(&dropped, b"x").unwrap();
+        std::fs::write(&kept, b"x").unwrap();
+
+        drop(Artifact::local(&dropped));
+        Artifact::local(&kept).keep();
+
+        assert!(!dropped.exists());
+        assert!(kept.exists());
+    }
+}
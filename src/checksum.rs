@@ -0,0 +1,187 @@
+use crate::progress::{Progress, Stage, Unit};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest as _, Sha256};
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::Path;
+
+const CHUNK_SIZE: usize = 256 * 1024;
+
+/// Hash used to verify that a transfer arrived intact
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Checksum {
+    #[default]
+    Sha256,
+    Blake3,
+}
+
+impl Checksum {
+    /// Program that prints this digest on a remote host
+    pub fn tool(self) -> &'static str {
+        match self {
+            Checksum::Sha256 => "sha256sum",
+            Checksum::Blake3 => "b3sum",
+        }
+    }
+}
+
+/// A digest of transferred data
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Digest {
+    pub algorithm: Checksum,
+    /// Lowercase hex
+    pub value: String,
+}
+
+impl std::fmt::Display for Checksum {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Checksum::Sha256 => "sha256",
+            Checksum::Blake3 => "blake3",
+        })
+    }
+}
+
+impl std::fmt::Display for Digest {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.algorithm, self.value)
+    }
+}
+
+enum Hasher {
+    Sha256(Sha256),
+    Blake3(Box<blake3::Hasher>),
+}
+
+impl Hasher {
+    fn new(algorithm: Checksum) -> Self {
+        match algorithm {
+            Checksum::Sha256 => Hasher::Sha256(Sha256::new()),
+            Checksum::Blake3 => Hasher::Blake3(Box::new(blake3::Hasher::new())),
+        }
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Sha256(h) => h.update(data),
+            Hasher::Blake3(h) => {
+                h.update(data);
+            }
+        }
+    }
+
+    fn finish(self) -> String {
+        match self {
+            Hasher::Sha256(h) => format!("{:x}", h.finalize()),
+            Hasher::Blake3(h) => h.finalize().to_hex().to_string(),
+        }
+    }
+}
+
+/// Hash a local file
+pub fn file_digest(path: &Path, algorithm: Checksum) -> std::io::Result<Digest> {
+    let mut file = File::open(path)?;
+    let total = file.metadata().map(|m| m.len()).ok();
+    let mut progress = Progress::start(Stage::Verify, Unit::Bytes, total);
+
+    let mut hasher = Hasher::new(algorithm);
+    let mut buf = vec![0u8; CHUNK_SIZE];
+    loop {
+        let n = match file.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        hasher.update(&buf[..n]);
+        progress.inc(n as u64);
+    }
+
+    progress.finish();
+    Ok(Digest {
+        algorithm,
+        value: hasher.finish(),
+    })
+}
+
+/// Passes writes through, hashing them on the way when an algorithm is given
+pub struct HashWriter<W> {
+    inner: W,
+    hasher: Option<(Checksum, Hasher)>,
+}
+
+impl<W: Write> HashWriter<W> {
+    pub fn new(inner: W, algorithm: Option<Checksum>) -> Self {
+        Self {
+            inner,
+            hasher: algorithm.map(|a| (a, Hasher::new(a))),
+        }
+    }
+
+    /// Digest of everything written
+    pub fn finish(self) -> Option<Digest> {
+        self.hasher.map(|(algorithm, hasher)| Digest {
+            algorithm,
+            value: hasher.finish(),
+        })
+    }
+}
+
+impl<W: Write> Write for HashWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        if let Some((_, ref mut hasher)) = self.hasher {
+            hasher.update(&buf[..n]);
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Read the digest from `sha256sum`/`b3sum` output ("<hex>  <path>")
+pub fn parse_digest(output: &str, algorithm: Checksum) -> Option<Digest> {
+    // Both tools escape unusual file names by prefixing the line with '\'
+    let value = output.split_whitespace().next()?.trim_start_matches('\\');
+
+    // Both digests are 256 bits
+    if value.len() == 64 && value.chars().all(|c| c.is_ascii_hexdigit()) {
+        Some(Digest {
+            algorithm,
+            value: value.to_ascii_lowercase(),
+        })
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_file_digest_matches_known_values() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("abc");
+        std::fs::write(&path, b"abc").unwrap();
+
+        let sha = file_digest(&path, Checksum::Sha256).unwrap();
+        assert_eq!(
+            sha.value,
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        let b3 = file_digest(&path, Checksum::Blake3).unwrap();
+        assert_eq!(
+            b3.value,
+            "6437b3ac38465133ffb63b75273a8db548c558465d79db03fd359c6cd5bd9d85"
+        );
+
+        // Tool output for the same file parses back to the same digest
+        let line = format!("{}  /srv/it's here/abc\n", sha.value);
+        assert_eq!(parse_digest(&line, Checksum::Sha256), Some(sha));
+        assert_eq!(parse_digest("sha256sum: no such file", Checksum::Sha256), None);
+    }
+}
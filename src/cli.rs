@@ -1,16 +1,19 @@
+use crate::archive::Format;
+use crate::checksum::Checksum;
+use crate::config::{Rate, TransferMethod};
 use clap::{Parser, Subcommand};
 use std::path::PathBuf;
 
 #[derive(Parser)]
 #[command(name = "quick-copy")]
-#[command(author, version, about = "Fast SSH/SCP copy tool with named targets")]
+#[command(author, version, about = "Fast SSH copy tool with named targets")]
 #[command(propagate_version = true)]
 pub struct Cli {
     /// Path to config file
     #[arg(short, long, global = true)]
     pub config: Option<PathBuf>,
 
-    /// Show verbose output including ssh/scp commands
+    /// Show verbose output including ssh and rsync commands
     #[arg(short, long, global = true)]
     pub verbose: bool,
 
@@ -22,6 +25,22 @@ pub struct Cli {
     #[arg(long, global = true)]
     pub dry_run: bool,
 
+    /// Transfer file data by streaming it through cat over ssh, with scp, or with rsync (overrides config)
+    #[arg(long, global = true, value_enum)]
+    pub via: Option<TransferMethod>,
+
+    /// Compare checksums of the sent and received data (--verify=blake3 to pick the hash)
+    #[arg(long, global = true, value_enum, value_name = "ALGORITHM", num_args = 0..=1, require_equals = true)]
+    pub verify: Option<Option<Checksum>>,
+
+    /// Cap transfer speed, e.g. 10M or 500K (overrides config; 0 for no limit)
+    #[arg(long, global = true, value_name = "RATE")]
+    pub limit: Option<Rate>,
+
+    /// Start even when the free-space check says the data won't fit
+    #[arg(long, global = true)]
+    pub force: bool,
+
     #[command(subcommand)]
     pub command: Commands,
 }
@@ -42,23 +61,39 @@ pub enum Commands {
         overwrite: bool,
     },
 
-    /// Zip and copy current directory to a remote destination
+    /// Archive and copy current directory to a remote destination
     #[command(alias = "d")]
     Dir {
         /// Destination (host, host:alias, or host:/path)
         dest: String,
 
-        /// Custom archive name (default: <dirname>-<timestamp>.zip)
+        /// Custom archive name; its extension is set to the format's (default: <dirname>-<timestamp>.<format>)
         #[arg(short, long)]
         name: Option<String>,
 
-        /// Additional exclude patterns (glob syntax)
+        /// Additional exclude patterns (.gitignore syntax)
         #[arg(short, long)]
         exclude: Vec<String>,
 
+        /// Archive files that .gitignore and .qcignore files leave out
+        #[arg(long)]
+        no_ignore: bool,
+
         /// Extract the archive on the remote after upload
         #[arg(long)]
         extract: bool,
+
+        /// Stream a tar straight over ssh instead of staging an archive locally
+        #[arg(long)]
+        stream: bool,
+
+        /// Archive format (overrides config; zip streams as tar.gz)
+        #[arg(long, value_enum)]
+        format: Option<Format>,
+
+        /// Threads to compress with (overrides config; default: one per core)
+        #[arg(short, long)]
+        jobs: Option<usize>,
     },
 
     /// Dump file or directory to shared storage (Ganymede by default)
@@ -69,6 +104,22 @@ pub enum Commands {
         /// Target share (default: from config)
         #[arg(long)]
         to: Option<String>,
+
+        /// Stream directories as a tar straight over ssh instead of staging an archive locally
+        #[arg(long)]
+        stream: bool,
+
+        /// Archive format for directories (overrides config; zip streams as tar.gz)
+        #[arg(long, value_enum)]
+        format: Option<Format>,
+
+        /// Threads to compress directories with (overrides config; default: one per core)
+        #[arg(short, long)]
+        jobs: Option<usize>,
+
+        /// Archive files that .gitignore and .qcignore files leave out
+        #[arg(long)]
+        no_ignore: bool,
     },
 
     /// List configured hosts and path aliases
@@ -81,15 +132,29 @@ pub enum Commands {
         test: Vec<String>,
     },
 
+    /// Fetch a host's key, show its fingerprint and pin it for quick-copy
+    Trust {
+        /// Configured host to trust
+        host: String,
+
+        /// Record the key without asking (check the fingerprint first)
+        #[arg(long)]
+        yes: bool,
+    },
+
     /// Pull a remote directory to current working directory
     #[command(alias = "p")]
     Pull {
         /// Source (host:alias or host:/path)
         source: String,
 
-        /// Keep the zip archive without extracting
+        /// Keep the archive without extracting
         #[arg(long)]
         no_extract: bool,
+
+        /// Format to archive the remote directory in (overrides config)
+        #[arg(long, value_enum)]
+        format: Option<Format>,
     },
 
     /// Show version information
@@ -1,11 +1,19 @@
-use crate::archive;
+use crate::archive::{self, Format};
+use crate::cancel::Artifact;
+use crate::checksum::Digest;
 use crate::config::Config;
-use crate::output::TransferResult;
+use crate::exclude::Rules;
+use crate::output::{self, TransferResult};
+use crate::preflight::{self, Need};
+use crate::probe;
 use crate::resolve;
-use crate::transfer;
+use crate::transfer::{self, Resume, TransferError, Transport};
+use std::io::Write;
+use std::path::Path;
 use thiserror::Error;
 
 #[derive(Error, Debug)]
+#[allow(clippy::enum_variant_names)]
 pub enum DirError {
     #[error("{0}")]
     ArchiveError(#[from] archive::ArchiveError),
@@ -15,11 +23,13 @@ pub enum DirError {
     TransferError(#[from] transfer::TransferError),
 }
 
+#[allow(clippy::too_many_arguments)]
 pub fn run(
     dest: &str,
     name: Option<&str>,
     extra_excludes: &[String],
     extract: bool,
+    stream: bool,
     config: &Config,
     verbose: bool,
     dry_run: bool,
@@ -27,21 +37,33 @@ pub fn run(
     // Get current directory
     let cwd = std::env::current_dir().map_err(|_| archive::ArchiveError::NoCwd)?;
 
-    // Combine excludes from config and CLI
-    let mut excludes = config.defaults.zip.exclude.clone();
-    excludes.extend(extra_excludes.iter().cloned());
+    // Combine excludes from config and CLI with the tree's ignore files
+    let rules = config.exclude_rules(extra_excludes);
 
     // Resolve destination
     let resolved = resolve::resolve(dest, config)?;
+    let format = config.format();
 
     if dry_run {
-        println!("[DRY RUN] Would zip and copy directory:");
+        println!("[DRY RUN] Would archive and copy directory:");
         println!("  Source: {}", cwd.display());
         println!("  Destination: {}@{}:{}", resolved.user, resolved.host, resolved.path);
-        println!("  Excludes: {:?}", excludes);
+        println!("  Excludes: {:?}", rules.patterns);
+        if stream {
+            println!("  Stream: Yes ({} over ssh, no staging archive)", format.for_stream());
+        } else {
+            println!("  Format: {}", format);
+        }
         if extract {
             println!("  Extract: Yes (would extract after upload)");
         }
+        if let Some(algorithm) = config.verify() {
+            println!("  Verify: {} checksum after upload", algorithm);
+        }
+        if let Some(rate) = resolved.bandwidth_limit {
+            println!("  Limit: {}/s", output::format_bytes(rate));
+        }
+        print_exclusions(&cwd, &rules, config)?;
 
         return Ok(TransferResult {
             source: cwd.display().to_string(),
@@ -51,6 +73,60 @@ pub fn run(
             duration_ms: 0,
             mode: "dir (dry-run)".to_string(),
             archive_path: None,
+            digest: None,
+            attempts: 0,
+            bandwidth_limit: resolved.bandwidth_limit,
+        });
+    }
+
+    let transport = transfer::connect(&resolved, verbose);
+    let caps = probe::capabilities(&transport, &resolved, config, verbose);
+    let mut stream = stream;
+    if let Some(ref caps) = caps {
+        caps.require_verify(config.verify())?;
+        let can_unpack = |format: Format| format.unpackers().iter().all(|tool| caps.has(tool));
+        if extract && !stream && !can_unpack(format) && can_unpack(format.for_stream()) {
+            eprintln!(
+                "{} not found on {}, streaming a {} to extract instead",
+                format.unpackers().join(" and "),
+                resolved.host,
+                format.for_stream()
+            );
+            stream = true;
+        }
+        if extract {
+            let unpack_format = if stream { format.for_stream() } else { format };
+            for tool in unpack_format.unpackers() {
+                caps.require(tool, "--extract")?;
+            }
+        }
+    }
+
+    // A staged archive needs room locally, and an extracted one sits beside its contents
+    let estimate = archive::estimate_size(&cwd, &rules, config.defaults.archive.follow_symlinks)?;
+    let mut needs = Vec::new();
+    if !stream {
+        needs.push(Need::local(Path::new(&config.defaults.staging_dir), estimate));
+    }
+    let remote_bytes = if extract && !stream { estimate * 2 } else { estimate };
+    needs.push(Need::remote(&transport, &resolved.host, &resolved.path, remote_bytes));
+    preflight::check(&needs, config.overrides.force)?;
+
+    if stream {
+        let start = std::time::Instant::now();
+        let streamed = stream_dir(&transport, &cwd, &rules, &resolved.path, name, extract, config)?;
+
+        return Ok(TransferResult {
+            source: cwd.display().to_string(),
+            dest_host: resolved.host,
+            dest_path: streamed.dest_path,
+            bytes: streamed.bytes,
+            duration_ms: start.elapsed().as_millis() as u64,
+            mode: "dir (stream)".to_string(),
+            archive_path: streamed.archive_path,
+            digest: streamed.digest,
+            attempts: transport.attempts(),
+            bandwidth_limit: resolved.bandwidth_limit,
         });
     }
 
@@ -58,21 +134,36 @@ pub fn run(
     if verbose {
         eprintln!("Creating archive of {}...", cwd.display());
     }
-    let archive_path = archive::create_archive(&cwd, &excludes, &config.defaults.staging_dir, name)?;
+    let archive_path = archive::create_archive(
+        &cwd,
+        &rules,
+        &config.defaults.staging_dir,
+        name,
+        format,
+        config.defaults.archive.follow_symlinks,
+        config.jobs(),
+    )?;
+    let staged = Artifact::local(&archive_path);
 
     // Build remote path
     let archive_name = archive_path
         .file_name()
         .and_then(|n| n.to_str())
-        .unwrap_or("archive.zip");
+        .unwrap_or("archive");
     let remote_path = format!("{}/{}", resolved.path, archive_name);
 
     // Ensure remote directory exists
-    transfer::ensure_remote_dir(&resolved, &remote_path, verbose)?;
+    transport.ensure_dir(&remote_path)?;
 
     // Transfer
     let start = std::time::Instant::now();
-    transfer::scp_file(&archive_path, &resolved, &remote_path, verbose)?;
+    let digest = transfer::upload_atomic(
+        &transport,
+        &archive_path,
+        &remote_path,
+        Resume::Delta,
+        config.verify(),
+    )?;
     let duration = start.elapsed();
 
     let size = archive_path.metadata().map(|m| m.len()).unwrap_or(0);
@@ -82,13 +173,13 @@ pub fn run(
         if verbose {
             eprintln!("Extracting on remote...");
         }
-        transfer::remote_unzip(&resolved, &remote_path, verbose)?
+        transport.extract(&remote_path)?
     } else {
         remote_path.clone()
     };
 
     // Clean up local archive
-    let _ = std::fs::remove_file(&archive_path);
+    drop(staged);
 
     Ok(TransferResult {
         source: cwd.display().to_string(),
@@ -98,5 +189,87 @@ pub fn run(
         duration_ms: duration.as_millis() as u64,
         mode: "dir".to_string(),
         archive_path: Some(remote_path),
+        digest,
+        attempts: transport.attempts(),
+        bandwidth_limit: resolved.bandwidth_limit,
+    })
+}
+
+/// List what an archive of `source_dir` would leave out, and why, for a dry run
+pub fn print_exclusions(source_dir: &Path, rules: &Rules, config: &Config) -> Result<(), archive::ArchiveError> {
+    let excluded = archive::exclusions(source_dir, rules, config.defaults.archive.follow_symlinks)?;
+    if excluded.is_empty() {
+        return Ok(());
+    }
+    println!("  Excluded:");
+    for exclusion in excluded {
+        println!("    {}  ({} in {})", exclusion.path, exclusion.rule, exclusion.source);
+    }
+    Ok(())
+}
+
+/// Where a streamed directory ended up
+pub struct Streamed {
+    pub dest_path: String,
+    /// The uploaded archive, unless it was extracted on the fly
+    pub archive_path: Option<String>,
+    /// Total size of the files sent
+    pub bytes: u64,
+    pub digest: Option<Digest>,
+}
+
+/// Send `source_dir` as a compressed tar written straight into the ssh channel
+///
+/// The configured format is used unless it is zip, which goes as tar.gz.
+///
+/// With `extract`, the remote unpacks the stream as it arrives, so no archive
+/// exists on either end; otherwise the archive is saved under `remote_dir`.
+pub fn stream_dir(
+    transport: &dyn Transport,
+    source_dir: &Path,
+    rules: &Rules,
+    remote_dir: &str,
+    name: Option<&str>,
+    extract: bool,
+    config: &Config,
+) -> Result<Streamed, TransferError> {
+    let format = config.format().for_stream();
+    let archive_name = archive::archive_name(source_dir, name, format.extension());
+    let remote_path = format!("{}/{}", remote_dir, archive_name);
+
+    let mut bytes = 0;
+    let mut write = |out: &mut dyn Write| -> Result<(), TransferError> {
+        bytes = archive::stream_tar(
+            source_dir,
+            rules,
+            format,
+            config.defaults.archive.follow_symlinks,
+            config.jobs(),
+            out,
+        )?;
+        Ok(())
+    };
+
+    if extract {
+        if config.verify().is_some() {
+            eprintln!("warning: --verify has nothing to compare when a stream is extracted on the fly, skipping");
+        }
+        let extract_dir = transfer::extract_dir(&remote_path);
+        transport.extract_stream(extract_dir, format, &mut write)?;
+        return Ok(Streamed {
+            dest_path: extract_dir.to_string(),
+            archive_path: None,
+            bytes,
+            digest: None,
+        });
+    }
+
+    transport.ensure_dir(&remote_path)?;
+    let digest = transfer::upload_stream_atomic(transport, &remote_path, config.verify(), &mut write)?;
+    Ok(Streamed {
+        dest_path: remote_path.clone(),
+        archive_path: Some(remote_path),
+        bytes,
+        digest,
     })
 }
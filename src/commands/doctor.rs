@@ -1,6 +1,8 @@
-use crate::config::Config;
-use crate::resolve;
-use crate::transfer;
+use crate::config::{Config, TransferMethod, TransportKind};
+use crate::output;
+use crate::probe::{self, Capabilities};
+use crate::resolve::{self, ResolvedDest};
+use crate::transfer::{self, RemoteCommand, TransferError, Transport};
 use std::process::Command;
 
 pub fn run(test_hosts: &[String], config: &Config, verbose: bool) -> bool {
@@ -11,9 +13,10 @@ pub fn run(test_hosts: &[String], config: &Config, verbose: bool) -> bool {
     // Check required tools
     println!("Checking tools:");
     all_ok &= check_tool("ssh", true);
-    all_ok &= check_tool("scp", true);
     all_ok &= check_tool("zip", false); // Optional for receiving
     all_ok &= check_tool("unzip", false); // Optional
+    all_ok &= check_tool("scp", false); // Optional, for --via scp and rsync's fallback
+    all_ok &= check_tool("rsync", false); // Optional, for --via rsync
 
     println!();
 
@@ -40,9 +43,36 @@ pub fn run(test_hosts: &[String], config: &Config, verbose: bool) -> bool {
         for host_name in test_hosts {
             match resolve::resolve(host_name, config) {
                 Ok(resolved) => {
+                    // Test each hop on the way, so a failure points at the right one
+                    for n in 0..resolved.jump.len() {
+                        let hop = resolved.hop(n);
+                        print!("  via {}... ", hop.host);
+                        match transfer::connect(&hop, verbose).test_connectivity() {
+                            Ok(_) => println!("ok"),
+                            Err(e) => {
+                                println!("FAILED");
+                                if verbose {
+                                    eprintln!("    {}", e);
+                                }
+                                all_ok = false;
+                            }
+                        }
+                    }
+
                     print!("  {} ({})... ", host_name, resolved.host);
-                    match transfer::test_connectivity(&resolved, verbose) {
-                        Ok(_) => println!("ok"),
+                    let transport = transfer::connect(&resolved, verbose);
+                    match transport.test_connectivity() {
+                        Ok(_) => {
+                            println!("ok");
+                            if resolved.transport != TransportKind::Local {
+                                // Always probe afresh, so doctor also refreshes the cache
+                                match probe::refresh(&transport, &resolved) {
+                                    Ok(caps) => print_capabilities(&caps, &resolved),
+                                    Err(e) => println!("    probe: FAILED ({})", e),
+                                }
+                            }
+                            all_ok &= check_sudo(&transport, &resolved, config);
+                        }
                         Err(e) => {
                             println!("FAILED");
                             if verbose {
@@ -71,6 +101,54 @@ pub fn run(test_hosts: &[String], config: &Config, verbose: bool) -> bool {
     all_ok
 }
 
+fn print_capabilities(caps: &Capabilities, dest: &ResolvedDest) {
+    println!("    os: {} {}, shell: {}", caps.os, caps.arch, caps.shell);
+
+    let (found, missing): (Vec<&str>, Vec<&str>) = probe::TOOLS.iter().partition(|t| caps.has(t));
+    print!("    tools: {}", found.join(" "));
+    if !missing.is_empty() {
+        print!(" (missing: {})", missing.join(" "));
+    }
+    println!();
+
+    if dest.via == TransferMethod::Rsync && !caps.has("rsync") {
+        println!("    rsync: not found (will fall back to scp)");
+    }
+    if let Some(free) = caps.free_space(&dest.path) {
+        println!("    free: {} at {}", output::format_bytes(free), dest.path);
+    }
+}
+
+/// Check that every user the host's paths `become` can be reached with sudo, without a password
+fn check_sudo(transport: &dyn Transport, dest: &ResolvedDest, config: &Config) -> bool {
+    let mut users: Vec<&str> = dest.sudo.iter().map(|b| b.user.as_str()).collect();
+    if let Some(host) = config.hosts.get(&dest.name) {
+        users.extend(host.become_user.as_deref());
+        users.extend(host.paths.values().filter_map(|p| p.become_user.as_deref()));
+    }
+    users.sort_unstable();
+    users.dedup();
+
+    let mut ok = true;
+    for user in users {
+        let check = RemoteCommand::new("sudo").args(["-n", "-u", user, "true"]);
+        match transport.execute(&check, "sudo", dest.timeouts.command) {
+            Ok(_) => println!("    sudo to {}: ok", user),
+            Err(e) => {
+                // The error names the destination's own user, which may not be this one
+                let reason = match e {
+                    TransferError::SudoFailed { detail, .. } => detail,
+                    e => e.to_string(),
+                };
+                println!("    sudo to {}: FAILED ({})", user, reason);
+                println!("      the login user needs a NOPASSWD sudoers rule for {}", user);
+                ok = false;
+            }
+        }
+    }
+    ok
+}
+
 fn check_tool(name: &str, required: bool) -> bool {
     let status = Command::new("which").arg(name).output();
 
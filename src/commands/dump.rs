@@ -1,8 +1,12 @@
 use crate::archive;
+use crate::cancel::Artifact;
+use crate::commands::dir;
 use crate::config::Config;
-use crate::output::TransferResult;
+use crate::output::{self, TransferResult};
+use crate::preflight::{self, Need};
+use crate::probe;
 use crate::resolve;
-use crate::transfer;
+use crate::transfer::{self, Resume, Transport};
 use std::path::Path;
 use thiserror::Error;
 
@@ -21,6 +25,7 @@ pub enum DumpError {
 pub fn run(
     path: Option<&Path>,
     to: Option<&str>,
+    stream: bool,
     config: &Config,
     verbose: bool,
     dry_run: bool,
@@ -39,6 +44,8 @@ pub fn run(
     let share_dest = to.unwrap_or(&config.shares.default);
     let resolved = resolve::resolve(share_dest, config)?;
 
+    let rules = config.exclude_rules(&[]);
+
     // Build dump layout path
     let layout_path = build_layout_path(&config.shares.layout);
     let base_remote_path = format!("{}/{}", resolved.path, layout_path);
@@ -49,11 +56,22 @@ pub fn run(
         println!("  Share: {}", share_dest);
         println!("  Destination: {}@{}:{}", resolved.user, resolved.host, base_remote_path);
         println!("  Layout: {}", config.shares.layout);
-        if source_path.is_dir() {
-            println!("  Type: Directory (would create zip archive)");
+        if source_path.is_dir() && stream {
+            println!("  Type: Directory (would stream a {} archive)", config.format().for_stream());
+        } else if source_path.is_dir() {
+            println!("  Type: Directory (would create {} archive)", config.format());
         } else {
             println!("  Type: File");
         }
+        if let Some(algorithm) = config.verify() {
+            println!("  Verify: {} checksum after upload", algorithm);
+        }
+        if let Some(rate) = resolved.bandwidth_limit {
+            println!("  Limit: {}/s", output::format_bytes(rate));
+        }
+        if source_path.is_dir() {
+            dir::print_exclusions(&source_path, &rules, config)?;
+        }
 
         return Ok(TransferResult {
             source: source_path.display().to_string(),
@@ -63,22 +81,67 @@ pub fn run(
             duration_ms: 0,
             mode: "dump (dry-run)".to_string(),
             archive_path: None,
+            digest: None,
+            attempts: 0,
+            bandwidth_limit: resolved.bandwidth_limit,
+        });
+    }
+
+    let transport = transfer::connect(&resolved, verbose);
+    if let Some(caps) = probe::capabilities(&transport, &resolved, config, verbose) {
+        caps.require_verify(config.verify())?;
+    }
+
+    let needs = if source_path.is_dir() {
+        let estimate = archive::estimate_size(&source_path, &rules, config.defaults.archive.follow_symlinks)?;
+        let mut needs = vec![Need::remote(&transport, &resolved.host, &base_remote_path, estimate)];
+        if !stream {
+            needs.push(Need::local(Path::new(&config.defaults.staging_dir), estimate));
+        }
+        needs
+    } else {
+        let size = source_path.metadata().map(|m| m.len()).unwrap_or(0);
+        vec![Need::remote(&transport, &resolved.host, &base_remote_path, size)]
+    };
+    preflight::check(&needs, config.overrides.force)?;
+
+    if stream && source_path.is_dir() {
+        let start = std::time::Instant::now();
+        let streamed = dir::stream_dir(&transport, &source_path, &rules, &base_remote_path, None, false, config)?;
+
+        return Ok(TransferResult {
+            source: source_path.display().to_string(),
+            dest_host: resolved.host,
+            dest_path: streamed.dest_path,
+            bytes: streamed.bytes,
+            duration_ms: start.elapsed().as_millis() as u64,
+            mode: "dump (stream)".to_string(),
+            archive_path: streamed.archive_path,
+            digest: streamed.digest,
+            attempts: transport.attempts(),
+            bandwidth_limit: resolved.bandwidth_limit,
         });
     }
 
     let (local_file, remote_path, is_archive) = if source_path.is_dir() {
-        // Zip directory
-        let excludes = config.defaults.zip.exclude.clone();
+        // Archive directory
         if verbose {
             eprintln!("Creating archive of {}...", source_path.display());
         }
-        let archive_path =
-            archive::create_archive(&source_path, &excludes, &config.defaults.staging_dir, None)?;
+        let archive_path = archive::create_archive(
+            &source_path,
+            &rules,
+            &config.defaults.staging_dir,
+            None,
+            config.format(),
+            config.defaults.archive.follow_symlinks,
+            config.jobs(),
+        )?;
 
         let archive_name = archive_path
             .file_name()
             .and_then(|n| n.to_str())
-            .unwrap_or("archive.zip");
+            .unwrap_or("archive");
 
         let remote = format!("{}/{}", base_remote_path, archive_name);
         (archive_path, remote, true)
@@ -92,20 +155,22 @@ pub fn run(
         (source_path.clone(), remote, false)
     };
 
+    // Only an archive we staged is ours to remove
+    let staged = is_archive.then(|| Artifact::local(&local_file));
+
     // Ensure remote directory exists
-    transfer::ensure_remote_dir(&resolved, &remote_path, verbose)?;
+    transport.ensure_dir(&remote_path)?;
 
     // Transfer
     let start = std::time::Instant::now();
-    transfer::scp_file(&local_file, &resolved, &remote_path, verbose)?;
+    let resume = if is_archive { Resume::Delta } else { Resume::Append };
+    let digest = transfer::upload_atomic(&transport, &local_file, &remote_path, resume, config.verify())?;
     let duration = start.elapsed();
 
     let size = local_file.metadata().map(|m| m.len()).unwrap_or(0);
 
     // Clean up archive if we created one
-    if is_archive {
-        let _ = std::fs::remove_file(&local_file);
-    }
+    drop(staged);
 
     Ok(TransferResult {
         source: source_path.display().to_string(),
@@ -115,6 +180,9 @@ pub fn run(
         duration_ms: duration.as_millis() as u64,
         mode: "dump".to_string(),
         archive_path: if is_archive { Some(remote_path) } else { None },
+        digest,
+        attempts: transport.attempts(),
+        bandwidth_limit: resolved.bandwidth_limit,
     })
 }
 
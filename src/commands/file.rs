@@ -1,7 +1,9 @@
 use crate::config::Config;
-use crate::output::TransferResult;
+use crate::output::{self, TransferResult};
+use crate::preflight::{self, Need};
+use crate::probe;
 use crate::resolve;
-use crate::transfer;
+use crate::transfer::{self, Resume, Transport};
 use std::path::Path;
 use thiserror::Error;
 
@@ -55,6 +57,12 @@ pub fn run(
         if !overwrite {
             println!("  Check: Would verify remote file doesn't exist");
         }
+        if let Some(algorithm) = config.verify() {
+            println!("  Verify: {} checksum after upload", algorithm);
+        }
+        if let Some(rate) = resolved.bandwidth_limit {
+            println!("  Limit: {}/s", output::format_bytes(rate));
+        }
 
         return Ok(TransferResult {
             source: path.display().to_string(),
@@ -64,20 +72,32 @@ pub fn run(
             duration_ms: 0,
             mode: "file (dry-run)".to_string(),
             archive_path: None,
+            digest: None,
+            attempts: 0,
+            bandwidth_limit: resolved.bandwidth_limit,
         });
     }
 
+    let transport = transfer::connect(&resolved, verbose);
+    if let Some(caps) = probe::capabilities(&transport, &resolved, config, verbose) {
+        caps.require_verify(config.verify())?;
+    }
+    let need = Need::remote(&transport, &resolved.host, &resolved.path, size);
+    preflight::check(&[need], config.overrides.force)?;
+
     // Check if remote exists (unless overwrite)
-    if !overwrite && transfer::remote_file_exists(&resolved, &remote_path, verbose)? {
+    if !overwrite && transport.exists(&remote_path)? {
         return Err(FileError::RemoteExists(remote_path));
     }
 
     // Ensure remote directory exists
-    transfer::ensure_remote_dir(&resolved, &remote_path, verbose)?;
+    transport.ensure_dir(&remote_path)?;
 
     // Transfer
     let start = std::time::Instant::now();
-    transfer::scp_file(path, &resolved, &remote_path, verbose)?;
+    // A fresh upload can resume by appending; an overwrite must not trust what is there
+    let resume = if overwrite { Resume::Delta } else { Resume::Append };
+    let digest = transfer::upload_atomic(&transport, path, &remote_path, resume, config.verify())?;
     let duration = start.elapsed();
 
     Ok(TransferResult {
@@ -88,5 +108,8 @@ pub fn run(
         duration_ms: duration.as_millis() as u64,
         mode: "file".to_string(),
         archive_path: None,
+        digest,
+        attempts: transport.attempts(),
+        bandwidth_limit: resolved.bandwidth_limit,
     })
 }
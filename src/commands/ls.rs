@@ -23,13 +23,15 @@ fn print_human(config: &Config) {
             println!("    paths:");
             let mut paths: Vec<_> = host.paths.iter().collect();
             paths.sort_by_key(|(alias, _)| *alias);
-            for (alias, path) in paths {
+            for (alias, entry) in paths {
                 let marker = if alias == &config.defaults.default_path_alias {
                     " (default)"
                 } else {
                     ""
                 };
-                println!("      {}:{} -> {}{}", name, alias, path, marker);
+                let become_user = entry.become_user.as_ref().or(host.become_user.as_ref());
+                let as_user = become_user.map(|u| format!(" (as {})", u)).unwrap_or_default();
+                println!("      {}:{} -> {}{}{}", name, alias, entry.path, as_user, marker);
             }
         }
         println!();
@@ -59,6 +61,8 @@ fn print_json(config: &Config) {
     struct PathInfo<'a> {
         alias: &'a str,
         path: &'a str,
+        #[serde(rename = "become", skip_serializing_if = "Option::is_none")]
+        become_user: Option<&'a str>,
         is_default: bool,
     }
 
@@ -69,9 +73,10 @@ fn print_json(config: &Config) {
             let mut paths: Vec<_> = host
                 .paths
                 .iter()
-                .map(|(alias, path)| PathInfo {
+                .map(|(alias, entry)| PathInfo {
                     alias,
-                    path,
+                    path: &entry.path,
+                    become_user: entry.become_user.as_deref().or(host.become_user.as_deref()),
                     is_default: alias == &config.defaults.default_path_alias,
                 })
                 .collect();
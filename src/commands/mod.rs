@@ -4,3 +4,4 @@ pub mod dump;
 pub mod file;
 pub mod ls;
 pub mod pull;
+pub mod trust;
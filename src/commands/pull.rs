@@ -1,7 +1,11 @@
+use crate::archive;
+use crate::cancel::Artifact;
 use crate::config::Config;
-use crate::output::TransferResult;
+use crate::output::{self, TransferResult};
+use crate::preflight::{self, Need};
+use crate::probe;
 use crate::resolve;
-use crate::transfer;
+use crate::transfer::{self, Transport};
 use std::path::PathBuf;
 use thiserror::Error;
 
@@ -26,6 +30,7 @@ pub fn run(
 ) -> Result<TransferResult, PullError> {
     // Resolve source
     let resolved = resolve::resolve(source, config)?;
+    let format = config.format();
 
     // Get current directory
     let cwd = std::env::current_dir().map_err(|_| PullError::NoCwd)?;
@@ -37,7 +42,13 @@ pub fn run(
         if extract {
             println!("  Extract: Yes (would extract after download)");
         } else {
-            println!("  Extract: No (would keep as zip)");
+            println!("  Extract: No (would keep as {})", format);
+        }
+        if let Some(algorithm) = config.verify() {
+            println!("  Verify: {} checksum after download", algorithm);
+        }
+        if let Some(rate) = resolved.bandwidth_limit {
+            println!("  Limit: {}/s", output::format_bytes(rate));
         }
 
         return Ok(TransferResult {
@@ -51,29 +62,51 @@ pub fn run(
             duration_ms: 0,
             mode: "pull (dry-run)".to_string(),
             archive_path: None,
+            digest: None,
+            attempts: 0,
+            bandwidth_limit: resolved.bandwidth_limit,
         });
     }
 
     // Combine excludes from config
-    let excludes = config.defaults.zip.exclude.clone();
+    let excludes = config.defaults.archive.exclude.clone();
+
+    let transport = transfer::connect(&resolved, verbose);
+    if let Some(caps) = probe::capabilities(&transport, &resolved, config, verbose) {
+        caps.require_verify(config.verify())?;
+        for tool in format.packers() {
+            caps.require(tool, "pull")?;
+        }
+    }
+
+    // The remote archive is staged before download; an extracted copy sits beside the download
+    if let Ok(size) = transport.disk_usage(&resolved.path) {
+        let local_bytes = if extract { size * 2 } else { size };
+        let needs = [
+            Need::remote(&transport, &resolved.host, &config.defaults.staging_dir, size),
+            Need::local(&cwd, local_bytes),
+        ];
+        preflight::check(&needs, config.overrides.force)?;
+    }
 
     // Create archive on remote
     if verbose {
         eprintln!("Creating archive on remote {}...", resolved.host);
     }
-    let remote_archive_path = transfer::remote_zip(
-        &resolved,
+    let remote_archive_path = transport.archive(
         &resolved.path,
         &excludes,
         &config.defaults.staging_dir,
-        verbose,
+        format,
+        config.defaults.archive.follow_symlinks,
     )?;
+    let remote_archive = transport.track(&remote_archive_path);
 
     // Get archive filename
     let archive_name = remote_archive_path
-        .split('/')
-        .last()
-        .unwrap_or("archive.zip");
+        .rsplit('/')
+        .next()
+        .unwrap_or("archive");
     let local_archive_path = cwd.join(archive_name);
 
     // Transfer from remote to local
@@ -81,7 +114,23 @@ pub fn run(
         eprintln!("Downloading archive...");
     }
     let start = std::time::Instant::now();
-    transfer::scp_from_remote(&resolved, &remote_archive_path, &local_archive_path, verbose)?;
+    let partial = Artifact::local(&local_archive_path);
+    transport.download(&remote_archive_path, &local_archive_path)?;
+
+    // Check the download before extracting anything from it
+    let digest = match config.verify() {
+        Some(algorithm) => {
+            let sent = transport.checksum(&remote_archive_path, algorithm)?;
+            let received = transfer::local_digest(&local_archive_path, algorithm)?;
+            Some(transfer::compare_digests(
+                &local_archive_path.display().to_string(),
+                sent,
+                received,
+            )?)
+        }
+        None => None,
+    };
+    partial.keep();
     let duration = start.elapsed();
 
     let size = local_archive_path.metadata().map(|m| m.len()).unwrap_or(0);
@@ -100,7 +149,8 @@ pub fn run(
     if verbose {
         eprintln!("Cleaning up remote archive...");
     }
-    let _ = transfer::remote_rm(&resolved, &remote_archive_path, verbose);
+    let _ = transport.remove(&remote_archive_path);
+    remote_archive.keep();
 
     Ok(TransferResult {
         source: format!("{}:{}", resolved.host, resolved.path),
@@ -113,49 +163,20 @@ pub fn run(
         duration_ms: duration.as_millis() as u64,
         mode: "pull".to_string(),
         archive_path: Some(local_archive_path.display().to_string()),
+        digest,
+        attempts: transport.attempts(),
+        bandwidth_limit: resolved.bandwidth_limit,
     })
 }
 
-/// Extract a zip file locally
+/// Extract a downloaded archive locally, in the format its extension names
 fn extract_local(archive_path: &PathBuf) -> Result<String, PullError> {
-    use std::fs::File;
-    use zip::ZipArchive;
-
-    let file = File::open(archive_path)
-        .map_err(|e| PullError::ExtractError(format!("failed to open archive: {}", e)))?;
-
-    let mut archive = ZipArchive::new(file)
-        .map_err(|e| PullError::ExtractError(format!("invalid zip archive: {}", e)))?;
-
     let extract_dir = archive_path
         .parent()
         .ok_or_else(|| PullError::ExtractError("invalid archive path".to_string()))?;
 
-    for i in 0..archive.len() {
-        let mut file = archive.by_index(i)
-            .map_err(|e| PullError::ExtractError(format!("failed to read entry: {}", e)))?;
-
-        let outpath = match file.enclosed_name() {
-            Some(path) => extract_dir.join(path),
-            None => continue,
-        };
-
-        if file.name().ends_with('/') {
-            std::fs::create_dir_all(&outpath)
-                .map_err(|e| PullError::ExtractError(format!("failed to create directory: {}", e)))?;
-        } else {
-            if let Some(p) = outpath.parent() {
-                if !p.exists() {
-                    std::fs::create_dir_all(p)
-                        .map_err(|e| PullError::ExtractError(format!("failed to create directory: {}", e)))?;
-                }
-            }
-            let mut outfile = File::create(&outpath)
-                .map_err(|e| PullError::ExtractError(format!("failed to create file: {}", e)))?;
-            std::io::copy(&mut file, &mut outfile)
-                .map_err(|e| PullError::ExtractError(format!("failed to extract file: {}", e)))?;
-        }
-    }
+    archive::extract_archive(archive_path, extract_dir)
+        .map_err(|e| PullError::ExtractError(e.to_string()))?;
 
     // Remove the archive after extraction
     let _ = std::fs::remove_file(archive_path);
@@ -0,0 +1,101 @@
+use crate::config::{Config, TransportKind};
+use crate::hostkey;
+use crate::resolve::{self, HostKeyCheck};
+use crate::transfer::TransferError;
+use std::io::{IsTerminal, Write};
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum TrustError {
+    #[error("{0}")]
+    ResolveError(#[from] resolve::ResolveError),
+    #[error("{0}")]
+    TransferError(#[from] TransferError),
+    #[error("host '{0}' uses transport: local, so it has no host key to trust")]
+    LocalHost(String),
+    #[error("{host} presented a key that is not among the fingerprints in its host_keys (possible man-in-the-middle); nothing was recorded")]
+    NotPinned { host: String },
+    #[error("no terminal to confirm trusting {0} on. Check the fingerprint, then rerun with --yes")]
+    NeedsConfirmation(String),
+    #[error("did not trust {0}")]
+    Declined(String),
+    #[error("failed to record host key in {path}: {source}")]
+    RecordFailed { path: String, source: std::io::Error },
+    #[error("can't trust {host}: {source}")]
+    NoKnownHostsFile { host: String, source: std::io::Error },
+}
+
+/// Fetch a host's key, show its fingerprint and record it so every connection is checked against it
+///
+/// Keys go in the host's `known_hosts` file when it has one, or in the file
+/// quick-copy manages. Keys recorded for the host before are replaced, which
+/// is what re-provisioned machines need.
+pub fn run(host_name: &str, yes: bool, config: &Config, verbose: bool) -> Result<(), TrustError> {
+    let (name, _) = resolve::parse_destination(host_name);
+    // The path plays no part in the host key, so don't require a default alias
+    let dest = resolve::resolve(&format!("{}:/", name), config)?;
+    if dest.transport == TransportKind::Local {
+        return Err(TrustError::LocalHost(name));
+    }
+
+    let mut keys = hostkey::fetch(&dest, verbose)?;
+    println!("{} ({}) presented:", name, hostkey::known_hosts_name(&dest.host, dest.port));
+    for key in &keys {
+        println!("  {} {}", key.key_type, key.fingerprint());
+    }
+
+    let (file, fingerprints) = match &dest.host_key {
+        HostKeyCheck::Pinned { known_hosts, fingerprints } => (known_hosts.clone(), fingerprints.clone()),
+        HostKeyCheck::System => {
+            let file = hostkey::managed_file().map_err(|source| TrustError::NoKnownHostsFile {
+                host: name.clone(),
+                source,
+            })?;
+            (file, Vec::new())
+        }
+    };
+
+    let previous = hostkey::recorded(&file, &dest.host, dest.port);
+    if !fingerprints.is_empty() {
+        keys.retain(|key| fingerprints.contains(&key.fingerprint()));
+        if keys.is_empty() {
+            return Err(TrustError::NotPinned { host: dest.host });
+        }
+        println!("matches host_keys in the config");
+    } else if previous == keys {
+        println!("already trusted in {}", file.display());
+        return Ok(());
+    } else if !yes {
+        confirm(&name, !previous.is_empty())?;
+    }
+
+    hostkey::record(&file, &dest.host, dest.port, &keys).map_err(|source| TrustError::RecordFailed {
+        path: file.display().to_string(),
+        source,
+    })?;
+    if !previous.is_empty() && previous != keys {
+        println!("replaced the key recorded before");
+    }
+    println!("recorded in {}", file.display());
+    Ok(())
+}
+
+/// Ask on the terminal before trusting a key nothing vouches for
+fn confirm(name: &str, replacing: bool) -> Result<(), TrustError> {
+    if !std::io::stdin().is_terminal() {
+        return Err(TrustError::NeedsConfirmation(name.to_string()));
+    }
+
+    if replacing {
+        println!("this differs from the key trusted before; only continue if {} was reinstalled", name);
+    }
+    print!("Trust this key for {}? [y/N] ", name);
+    let _ = std::io::stdout().flush();
+
+    let mut answer = String::new();
+    let _ = std::io::stdin().read_line(&mut answer);
+    match answer.trim().to_lowercase().as_str() {
+        "y" | "yes" => Ok(()),
+        _ => Err(TrustError::Declined(name.to_string())),
+    }
+}
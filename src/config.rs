@@ -1,5 +1,8 @@
-use serde::Deserialize;
-use std::collections::HashMap;
+use crate::archive::{self, Format};
+use crate::checksum::Checksum;
+use crate::exclude::Rules;
+use serde::{Deserialize, Deserializer};
+use std::collections::{BTreeMap, HashMap};
 use std::path::PathBuf;
 use thiserror::Error;
 
@@ -15,6 +18,7 @@ pub enum ConfigError {
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct Config {
+    #[allow(dead_code)] // reserved for future schema migrations
     pub version: u32,
     #[serde(default)]
     pub defaults: Defaults,
@@ -22,6 +26,21 @@ pub struct Config {
     pub hosts: HashMap<String, Host>,
     #[serde(default)]
     pub shares: Shares,
+    /// Command-line settings that take precedence over the file
+    #[serde(skip)]
+    pub overrides: Overrides,
+}
+
+/// Settings given on the command line that win over host and default config
+#[derive(Debug, Clone, Default)]
+pub struct Overrides {
+    pub via: Option<TransferMethod>,
+    pub verify: Option<Checksum>,
+    pub limit: Option<Rate>,
+    pub force: bool,
+    pub format: Option<Format>,
+    pub jobs: Option<usize>,
+    pub no_ignore: bool,
 }
 
 #[derive(Debug, Deserialize, Clone, Default)]
@@ -32,10 +51,35 @@ pub struct Defaults {
     pub port: u16,
     #[serde(default = "default_path_alias")]
     pub default_path_alias: String,
-    #[serde(default)]
-    pub zip: ZipDefaults,
+    /// How directories are archived; `zip` is the name this had before other formats
+    #[serde(default, alias = "zip")]
+    pub archive: ArchiveDefaults,
     #[serde(default = "default_staging_dir")]
     pub staging_dir: String,
+    /// Where uploads for `become` wait on the remote, as the login user, before being copied into place
+    #[serde(default = "default_staging_dir")]
+    pub remote_staging_dir: String,
+    #[serde(default)]
+    pub via: TransferMethod,
+    /// Verify every transfer with this checksum
+    #[serde(default)]
+    pub verify: Option<Checksum>,
+    /// Extra `-o key=value` options for every ssh call
+    #[serde(default, deserialize_with = "ssh_options")]
+    pub ssh_options: BTreeMap<String, String>,
+    #[serde(default)]
+    pub retry: Retry,
+    #[serde(default)]
+    pub timeouts: Timeouts,
+    /// Cap on transfer speed for every host
+    #[serde(default)]
+    pub bandwidth_limit: Option<Rate>,
+    /// Seconds a host's probed capabilities stay cached (0 probes every time)
+    #[serde(default)]
+    pub probe_ttl: Option<u64>,
+    /// known_hosts file to check every host's key against, instead of ssh's own
+    #[serde(default)]
+    pub known_hosts: Option<PathBuf>,
 }
 
 fn default_user() -> String {
@@ -55,11 +99,16 @@ fn default_staging_dir() -> String {
 }
 
 #[derive(Debug, Deserialize, Clone, Default)]
-pub struct ZipDefaults {
+pub struct ArchiveDefaults {
     #[serde(default)]
     pub exclude: Vec<String>,
     #[serde(default)]
     pub follow_symlinks: bool,
+    #[serde(default)]
+    pub format: Format,
+    /// Threads to compress with; one per core when unset or 0
+    #[serde(default)]
+    pub jobs: Option<usize>,
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -74,7 +123,222 @@ pub struct Host {
     #[serde(default)]
     pub role: Option<String>,
     #[serde(default)]
-    pub paths: HashMap<String, String>,
+    pub paths: HashMap<String, PathAlias>,
+    #[serde(default)]
+    pub transport: TransportKind,
+    /// Share one ssh connection across all calls in a command (default: true)
+    #[serde(default)]
+    pub multiplex: Option<bool>,
+    #[serde(default)]
+    pub via: Option<TransferMethod>,
+    /// Configured hosts to hop through to reach this one, in order
+    #[serde(default, deserialize_with = "one_or_many")]
+    pub jump: Vec<String>,
+    /// Extra `-o key=value` options for ssh calls to this host, over those in defaults
+    #[serde(default, deserialize_with = "ssh_options")]
+    pub ssh_options: BTreeMap<String, String>,
+    /// Retry settings for this host, over those in defaults
+    #[serde(default)]
+    pub retry: Retry,
+    /// Time limits for this host, over those in defaults
+    #[serde(default)]
+    pub timeouts: Timeouts,
+    /// Cap on transfer speed to and from this host
+    #[serde(default)]
+    pub bandwidth_limit: Option<Rate>,
+    /// SHA-256 fingerprints of the keys this host may present, as ssh-keygen -l prints them
+    #[serde(default, deserialize_with = "one_or_many")]
+    pub host_keys: Vec<String>,
+    /// known_hosts file to check this host's key against, instead of ssh's own
+    #[serde(default)]
+    pub known_hosts: Option<PathBuf>,
+    /// User to write into this host's paths as, through non-interactive sudo
+    #[serde(default, rename = "become")]
+    pub become_user: Option<String>,
+    /// Where uploads for `become` wait on this host, over the one in defaults
+    #[serde(default)]
+    pub remote_staging_dir: Option<String>,
+}
+
+/// A named path on a host, optionally written as another user
+///
+/// Written as a plain path, or as `{ path: ..., become: user }` for a path
+/// only that user can write to.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PathAlias {
+    pub path: String,
+    /// Overrides the host's `become` for this path
+    pub become_user: Option<String>,
+}
+
+impl<'de> Deserialize<'de> for PathAlias {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Raw {
+            Path(String),
+            Full {
+                path: String,
+                #[serde(default, rename = "become")]
+                become_user: Option<String>,
+            },
+        }
+
+        Ok(match Raw::deserialize(deserializer)? {
+            Raw::Path(path) => PathAlias { path, become_user: None },
+            Raw::Full { path, become_user } => PathAlias { path, become_user },
+        })
+    }
+}
+
+/// A transfer rate in bytes per second, written like `10M`, `512K` or `1.5MB/s`
+///
+/// Units are binary (K = 1024). Zero means unlimited, so `--limit 0` lifts a
+/// configured limit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rate(pub u64);
+
+impl Rate {
+    /// Bytes per second, or `None` when unlimited
+    pub fn bytes_per_sec(self) -> Option<u64> {
+        (self.0 > 0).then_some(self.0)
+    }
+}
+
+impl std::str::FromStr for Rate {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid rate '{}' (expected e.g. 500K, 10M or 1G)", s);
+
+        let lower = s.trim().to_lowercase();
+        let lower = lower.strip_suffix("/s").unwrap_or(&lower);
+        let (number, unit) = lower.split_at(lower.find(|c: char| !c.is_ascii_digit() && c != '.').unwrap_or(lower.len()));
+        let multiplier = match unit.trim_start() {
+            "" | "b" => 1.0,
+            "k" | "kb" | "kib" => 1024.0,
+            "m" | "mb" | "mib" => 1024.0 * 1024.0,
+            "g" | "gb" | "gib" => 1024.0 * 1024.0 * 1024.0,
+            _ => return Err(invalid()),
+        };
+
+        let value: f64 = number.parse().map_err(|_| invalid())?;
+        let bytes = (value * multiplier) as u64;
+        // Anything under a byte per second would read as no limit at all
+        if !value.is_finite() || (value > 0.0 && bytes == 0) {
+            return Err(invalid());
+        }
+        Ok(Rate(bytes))
+    }
+}
+
+impl<'de> Deserialize<'de> for Rate {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Raw {
+            Bytes(u64),
+            Text(String),
+        }
+
+        match Raw::deserialize(deserializer)? {
+            Raw::Bytes(n) => Ok(Rate(n)),
+            Raw::Text(s) => s.parse().map_err(serde::de::Error::custom),
+        }
+    }
+}
+
+/// How failed steps are retried; unset fields fall back to defaults
+#[derive(Debug, Deserialize, Clone, Copy, Default)]
+pub struct Retry {
+    /// Total tries per step, including the first (1 disables retrying)
+    pub attempts: Option<u32>,
+    /// Seconds to wait before the first retry, doubling after each one
+    pub backoff: Option<f64>,
+    /// Upper bound on the wait between tries, in seconds
+    pub max_delay: Option<f64>,
+}
+
+/// Time limits for remote steps, in seconds; unset fields fall back to defaults
+/// and 0 waits forever
+#[derive(Debug, Deserialize, Clone, Copy, Default)]
+pub struct Timeouts {
+    /// Establishing the ssh connection
+    pub connect: Option<f64>,
+    /// Quick remote commands: mkdir, rm, mv and existence checks
+    pub command: Option<f64>,
+    /// Creating the remote archive for `pull`
+    pub zip: Option<f64>,
+    /// Extracting an uploaded archive with `--extract`
+    pub unzip: Option<f64>,
+    /// Longest a data transfer may go without moving a byte
+    pub stall: Option<f64>,
+}
+
+/// Accept either a single name or a list of names
+fn one_or_many<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(name) => vec![name],
+        OneOrMany::Many(names) => names,
+    })
+}
+
+/// Read an option map whose values may be written as numbers or booleans
+fn ssh_options<'de, D: Deserializer<'de>>(deserializer: D) -> Result<BTreeMap<String, String>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Scalar {
+        String(String),
+        Int(i64),
+        Bool(bool),
+    }
+
+    let raw = BTreeMap::<String, Scalar>::deserialize(deserializer)?;
+    Ok(raw
+        .into_iter()
+        .map(|(key, value)| {
+            let value = match value {
+                Scalar::String(s) => s,
+                Scalar::Int(n) => n.to_string(),
+                // ssh spells booleans yes/no
+                Scalar::Bool(b) => if b { "yes" } else { "no" }.to_string(),
+            };
+            (key, value)
+        })
+        .collect())
+}
+
+/// How quick-copy reaches a host
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum TransportKind {
+    /// ssh to a remote machine
+    #[default]
+    Ssh,
+    /// Plain filesystem operations, e.g. for a mounted NAS share
+    Local,
+    /// In-process SSH/SFTP (requires the `native-ssh` cargo feature)
+    Native,
+}
+
+/// Program used to move file data over ssh
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum TransferMethod {
+    /// Stream file data through `cat` on the remote, over the ssh connection
+    #[default]
+    Ssh,
+    /// Copy files with `scp`
+    Scp,
+    /// Resumable transfers; falls back to scp if rsync is missing on either end
+    Rsync,
 }
 
 #[derive(Debug, Deserialize, Clone, Default)]
@@ -137,12 +401,37 @@ impl Config {
         paths
     }
 
-    pub fn get_host(&self, name: &str) -> Option<&Host> {
-        self.hosts.get(name)
+    /// Checksum to verify transfers with, if any
+    pub fn verify(&self) -> Option<Checksum> {
+        self.overrides.verify.or(self.defaults.verify)
+    }
+
+    /// Format to archive directories in
+    pub fn format(&self) -> Format {
+        self.overrides.format.unwrap_or(self.defaults.archive.format)
     }
 
-    pub fn host_names(&self) -> Vec<&String> {
-        self.hosts.keys().collect()
+    /// Threads to compress archives with
+    pub fn jobs(&self) -> usize {
+        self.overrides
+            .jobs
+            .or(self.defaults.archive.jobs)
+            .filter(|&jobs| jobs > 0)
+            .unwrap_or_else(archive::default_jobs)
+    }
+
+    /// What to leave out of archived directories: the configured excludes, `extra`, and ignore files
+    pub fn exclude_rules(&self, extra: &[String]) -> Rules {
+        let mut patterns = self.defaults.archive.exclude.clone();
+        patterns.extend(extra.iter().cloned());
+        Rules {
+            patterns,
+            ignore_files: !self.overrides.no_ignore,
+        }
+    }
+
+    pub fn get_host(&self, name: &str) -> Option<&Host> {
+        self.hosts.get(name)
     }
 
     /// Find similar host names for typo suggestions
@@ -153,3 +442,22 @@ impl Config {
             .find(|h| h.to_lowercase().starts_with(&name_lower) || name_lower.starts_with(&h.to_lowercase()))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_rate() {
+        assert_eq!("10M".parse(), Ok(Rate(10 * 1024 * 1024)));
+        assert_eq!("512KiB/s".parse(), Ok(Rate(512 * 1024)));
+        assert_eq!("1.5g".parse(), Ok(Rate(3 * 512 * 1024 * 1024)));
+        assert_eq!("2048".parse(), Ok(Rate(2048)));
+        assert_eq!("100 kb".parse(), Ok(Rate(100 * 1024)));
+        assert_eq!("0".parse(), Ok(Rate(0)));
+
+        for bad in ["fast", "10bbb", "5kk", "10i", "1e3", "-5M", "", "M", "0.5", "0.0001K"] {
+            assert!(bad.parse::<Rate>().is_err(), "{:?} should be rejected", bad);
+        }
+    }
+}
@@ -0,0 +1,207 @@
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use ignore::Match;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+/// Ignore file with rules for quick-copy only, read like a `.gitignore`
+pub const QCIGNORE: &str = ".qcignore";
+
+const GITIGNORE: &str = ".gitignore";
+
+/// What to leave out when archiving a directory
+///
+/// Patterns use `.gitignore` syntax: a leading or inner `/` anchors a pattern
+/// to the archived directory, a trailing `/` matches only directories, `**`
+/// spans directories and `!` brings back what an earlier rule left out.
+#[derive(Debug, Clone, Default)]
+pub struct Rules {
+    /// Patterns from the config's `exclude` and `--exclude`
+    pub patterns: Vec<String>,
+    /// Honour `.gitignore` and `.qcignore` files in the tree and above it, up to the repository root
+    pub ignore_files: bool,
+}
+
+/// An entry left out of an archive, and the rule that did it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Exclusion {
+    /// Path relative to the archived directory, with a trailing `/` for directories
+    pub path: String,
+    pub rule: String,
+    /// The ignore file the rule came from, or `exclude` for configured patterns
+    pub source: String,
+}
+
+/// Ignore files found in one directory
+struct DirRules {
+    /// The directory, canonicalized for ones above the tree
+    dir: PathBuf,
+    qcignore: Option<Gitignore>,
+    gitignore: Option<Gitignore>,
+}
+
+/// The rules in force while walking one tree
+///
+/// Configured patterns win over `.qcignore` files, which win over
+/// `.gitignore` files. Among files of one kind the deepest decides, and within
+/// a file the last matching line does, as in git.
+pub struct Filter {
+    root: PathBuf,
+    canonical_root: PathBuf,
+    patterns: Gitignore,
+    ignore_files: bool,
+    /// Ignore files of directories above the tree, nearest first
+    outer: Vec<DirRules>,
+    /// Ignore files of directories in the tree, loaded as the walk reaches them
+    inner: HashMap<PathBuf, DirRules>,
+}
+
+impl Filter {
+    pub fn new(root: &Path, rules: &Rules) -> Self {
+        let mut builder = GitignoreBuilder::new(root);
+        for pattern in &rules.patterns {
+            // A pattern git wouldn't accept is skipped, as a bad glob was before
+            let _ = builder.add_line(None, pattern);
+        }
+        let canonical_root = root.canonicalize().unwrap_or_else(|_| root.to_path_buf());
+
+        let mut outer = Vec::new();
+        if rules.ignore_files {
+            // Rules from a parent directory only apply if it is in the same repository
+            let parents: Vec<&Path> = canonical_root.ancestors().skip(1).collect();
+            if let Some(top) = canonical_root.ancestors().position(|dir| dir.join(".git").exists()) {
+                outer = parents[..top].iter().map(|dir| DirRules::load(dir)).collect();
+            }
+        }
+
+        Self {
+            root: root.to_path_buf(),
+            canonical_root,
+            patterns: builder.build().unwrap_or_else(|_| Gitignore::empty()),
+            ignore_files: rules.ignore_files,
+            outer,
+            inner: HashMap::new(),
+        }
+    }
+
+    /// The rule that leaves `path`, somewhere in the tree, out of the archive
+    pub fn excluded(&mut self, path: &Path, is_dir: bool) -> Option<Exclusion> {
+        let relative = path.strip_prefix(&self.root).ok()?;
+        if relative.as_os_str().is_empty() {
+            return None;
+        }
+
+        let mut decision = self.patterns.matched(path, is_dir);
+        if self.ignore_files {
+            // Directories holding the path, deepest first, ending at the root
+            let dirs: Vec<PathBuf> = relative
+                .ancestors()
+                .skip(1)
+                .map(|a| self.root.join(a))
+                .collect();
+            for dir in &dirs {
+                self.inner.entry(dir.clone()).or_insert_with(|| DirRules::load(dir));
+            }
+            let inner = dirs.iter().map(|dir| &self.inner[dir]);
+            let all: Vec<&DirRules> = inner.chain(&self.outer).collect();
+            let outer_path = self.canonical_root.join(relative);
+
+            let kinds: [fn(&DirRules) -> Option<&Gitignore>; 2] =
+                [|d| d.qcignore.as_ref(), |d| d.gitignore.as_ref()];
+            for pick in kinds {
+                for rules in &all {
+                    if !decision.is_none() {
+                        break;
+                    }
+                    if let Some(ignore) = pick(rules) {
+                        let path = if rules.dir.starts_with(&self.root) { path } else { &outer_path };
+                        decision = ignore.matched(path, is_dir);
+                    }
+                }
+            }
+        }
+
+        let Match::Ignore(glob) = decision else {
+            return None;
+        };
+        let source = match glob.from() {
+            Some(file) => file
+                .strip_prefix(&self.root)
+                .unwrap_or(file)
+                .display()
+                .to_string(),
+            None => "exclude".to_string(),
+        };
+        let mut path = relative.to_string_lossy().to_string();
+        if is_dir {
+            path.push('/');
+        }
+        Some(Exclusion {
+            path,
+            rule: glob.original().to_string(),
+            source,
+        })
+    }
+}
+
+impl DirRules {
+    fn load(dir: &Path) -> Self {
+        let read = |name: &str| {
+            let file = dir.join(name);
+            if !file.is_file() {
+                return None;
+            }
+            let mut builder = GitignoreBuilder::new(dir);
+            // Unreadable lines are skipped, as git skips them
+            let _ = builder.add(&file);
+            builder.build().ok()
+        };
+        Self {
+            dir: dir.to_path_buf(),
+            qcignore: read(QCIGNORE),
+            gitignore: read(GITIGNORE),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ignore_files_follow_git_precedence() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path().join("repo");
+        std::fs::create_dir_all(root.join(".git")).unwrap();
+        std::fs::create_dir_all(root.join("app/src/gen")).unwrap();
+        std::fs::write(root.join(".gitignore"), "*.log\n/build/\n").unwrap();
+        std::fs::write(root.join("app/.gitignore"), "!keep.log\ngen/\ndocs/**/*.pdf\n").unwrap();
+        std::fs::write(root.join("app/.qcignore"), "*.ckpt\n").unwrap();
+
+        // Archiving a subdirectory still applies the repository root's rules
+        let rules = Rules {
+            patterns: vec!["/secrets.txt".to_string()],
+            ignore_files: true,
+        };
+        let app = root.join("app");
+        let mut filter = Filter::new(&app, &rules);
+        let mut check = |path: &str, is_dir: bool| filter.excluded(&app.join(path), is_dir);
+
+        let log = check("src/debug.log", false).unwrap();
+        assert_eq!((log.rule.as_str(), log.path.as_str()), ("*.log", "src/debug.log"));
+        assert!(log.source.ends_with("repo/.gitignore"));
+        assert!(check("src/keep.log", false).is_none());
+
+        let gen = check("src/gen", true).unwrap();
+        assert_eq!((gen.path.as_str(), gen.source.as_str()), ("src/gen/", ".gitignore"));
+        assert!(check("src/gen", false).is_none());
+
+        assert_eq!(check("model.ckpt", false).unwrap().source, ".qcignore");
+        assert_eq!(check("secrets.txt", false).unwrap().source, "exclude");
+        assert!(check("src/secrets.txt", false).is_none());
+        assert!(check("build", true).is_none());
+        assert_eq!(check("docs/a/b/guide.pdf", false).unwrap().rule, "docs/**/*.pdf");
+
+        let mut plain = Filter::new(&app, &Rules::default());
+        assert!(plain.excluded(&app.join("src/debug.log"), false).is_none());
+    }
+}
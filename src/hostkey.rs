@@ -0,0 +1,238 @@
+use crate::resolve::ResolvedDest;
+use crate::transfer::{self, TransferError};
+use base64::engine::general_purpose::{STANDARD, STANDARD_NO_PAD};
+use base64::Engine;
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+/// known_hosts file `qc trust` records keys in for hosts that name no file of their own
+///
+/// Fails when there is no config directory, rather than falling back to a
+/// file relative to wherever qc happens to run.
+pub fn managed_file() -> std::io::Result<PathBuf> {
+    let dir = dirs::config_dir().ok_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            "no config directory to keep quick-copy's known_hosts in; set `known_hosts` for the host",
+        )
+    })?;
+    Ok(dir.join("quick-copy").join("known_hosts"))
+}
+
+/// Expand a leading `~/`, as ssh does for its own known_hosts paths
+pub fn expand_home(path: &Path) -> PathBuf {
+    match (path.strip_prefix("~"), dirs::home_dir()) {
+        (Ok(rest), Some(home)) => home.join(rest),
+        _ => path.to_path_buf(),
+    }
+}
+
+/// How a host is written in known_hosts, and in `ssh-keygen -R`
+pub fn known_hosts_name(host: &str, port: u16) -> String {
+    if port == 22 {
+        host.to_string()
+    } else {
+        format!("[{}]:{}", host, port)
+    }
+}
+
+/// A host's public key
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HostKey {
+    /// e.g. `ssh-ed25519`
+    pub key_type: String,
+    /// The key blob, base64-encoded as in known_hosts
+    pub key: String,
+}
+
+impl HostKey {
+    /// SHA-256 fingerprint as `ssh-keygen -l` prints it
+    pub fn fingerprint(&self) -> String {
+        let blob = STANDARD.decode(&self.key).unwrap_or_default();
+        fingerprint(&blob)
+    }
+}
+
+/// SHA-256 fingerprint of a raw key blob
+pub fn fingerprint(blob: &[u8]) -> String {
+    format!("SHA256:{}", STANDARD_NO_PAD.encode(Sha256::digest(blob)))
+}
+
+/// The host names and key on a known_hosts line, skipping comments and markers
+fn parse_line(line: &str) -> Option<(&str, HostKey)> {
+    let mut fields = line.split_whitespace();
+    let names = fields.next().filter(|n| !n.starts_with('#') && !n.starts_with('@'))?;
+    let key_type = fields.next()?;
+    let key = fields.next()?;
+    Some((
+        names,
+        HostKey {
+            key_type: key_type.to_string(),
+            key: key.to_string(),
+        },
+    ))
+}
+
+fn names_match(names: &str, name: &str) -> bool {
+    names.split(',').any(|n| n == name)
+}
+
+/// Keys recorded for the host in `file`
+///
+/// Only plain entries are read; quick-copy never hashes the ones it writes.
+pub fn recorded(file: &Path, host: &str, port: u16) -> Vec<HostKey> {
+    let name = known_hosts_name(host, port);
+    std::fs::read_to_string(file)
+        .unwrap_or_default()
+        .lines()
+        .filter_map(parse_line)
+        .filter(|(names, _)| names_match(names, &name))
+        .map(|(_, key)| key)
+        .collect()
+}
+
+/// Replace whatever `file` records for the host with `keys`
+pub fn record(file: &Path, host: &str, port: u16, keys: &[HostKey]) -> std::io::Result<()> {
+    let name = known_hosts_name(host, port);
+    let existing = match std::fs::read_to_string(file) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e),
+    };
+
+    let mut contents: String = existing
+        .lines()
+        .filter(|line| !parse_line(line).is_some_and(|(names, _)| names_match(names, &name)))
+        .map(|line| format!("{}\n", line))
+        .collect();
+    for key in keys {
+        contents.push_str(&format!("{} {} {}\n", name, key.key_type, key.key));
+    }
+
+    if let Some(dir) = file.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    // Write beside the file and rename, so ssh never reads a half-written one
+    let temp = file.with_extension("qc-part");
+    std::fs::write(&temp, contents)?;
+    std::fs::rename(&temp, file)
+}
+
+/// The key the host presents, fetched over the same route transfers take
+///
+/// ssh records the key in a scratch known_hosts file during the handshake,
+/// whether or not authentication then succeeds, so this works before any
+/// login is set up. Jump hosts on the way are still checked as configured.
+pub fn fetch(dest: &ResolvedDest, verbose: bool) -> Result<Vec<HostKey>, TransferError> {
+    let dir = scratch_dir().map_err(|source| TransferError::IoError {
+        op: "create a scratch directory for the host key".to_string(),
+        source,
+    })?;
+    let keys = fetch_into(dest, &dir.join("known_hosts"), verbose);
+    let _ = std::fs::remove_dir_all(&dir);
+    keys
+}
+
+/// A fresh directory only this user can reach, so no one else can plant or
+/// read the scratch known_hosts inside it
+fn scratch_dir() -> std::io::Result<PathBuf> {
+    let dir = std::env::temp_dir().join(format!("qc-hostkey-{}", std::process::id()));
+    // Fails if the directory exists, so one left there by someone else is never used
+    std::fs::create_dir(&dir)?;
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(&dir, std::fs::Permissions::from_mode(0o700))?;
+    }
+
+    Ok(dir)
+}
+
+fn fetch_into(dest: &ResolvedDest, scratch: &Path, verbose: bool) -> Result<Vec<HostKey>, TransferError> {
+    let mut cmd = Command::new("ssh");
+    cmd.args(dest.route_args());
+    for option in [
+        format!("UserKnownHostsFile={}", ssh_path(scratch)),
+        "GlobalKnownHostsFile=/dev/null".to_string(),
+        "StrictHostKeyChecking=accept-new".to_string(),
+        "HashKnownHosts=no".to_string(),
+        "BatchMode=yes".to_string(),
+        // A shared connection would skip the handshake that records the key
+        "ControlMaster=no".to_string(),
+        "ControlPath=none".to_string(),
+    ] {
+        cmd.arg("-o").arg(option);
+    }
+    cmd.arg(dest.ssh_target()).arg("exit");
+    cmd.stdin(Stdio::null());
+    if verbose {
+        eprintln!("+ ssh {} (fetching host key)", dest.ssh_target());
+    }
+
+    let output = cmd.output().map_err(|source| TransferError::ExecError {
+        cmd: "ssh".to_string(),
+        source,
+    })?;
+    let keys = recorded(scratch, &dest.host, dest.port);
+
+    if keys.is_empty() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(transfer::command_failed("ssh", output.status.code().unwrap_or(-1), &stderr, dest));
+    }
+    Ok(keys)
+}
+
+/// A path as an ssh option value: ssh expands `%` tokens and splits on spaces
+pub fn ssh_path(path: &Path) -> String {
+    let path = path.display().to_string().replace('%', "%%");
+    if path.contains(char::is_whitespace) {
+        format!("\"{}\"", path)
+    } else {
+        path
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Fingerprint as printed by `ssh-keygen -lf` for this key
+    const KEY: &str = "AAAAC3NzaC1lZDI1NTE5AAAAILrttkpG7Jq0i2gQtL0u28sknhxBrxbzViabh/UTARIT";
+    const FINGERPRINT: &str = "SHA256:eR9PCyCbKpfInJHWZo4l3+gnDFnRWuYFBpe4QgydOuI";
+
+    #[test]
+    fn test_record_replaces_host_entries() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("known_hosts");
+        std::fs::write(&file, "# kept\nother.local ssh-ed25519 AAAAother\n[nas.local]:2222 ssh-rsa AAAAold\n").unwrap();
+
+        let key = HostKey {
+            key_type: "ssh-ed25519".to_string(),
+            key: KEY.to_string(),
+        };
+        record(&file, "nas.local", 2222, std::slice::from_ref(&key)).unwrap();
+
+        assert_eq!(recorded(&file, "nas.local", 2222), vec![key.clone()]);
+        assert_eq!(recorded(&file, "nas.local", 22), vec![]);
+        assert_eq!(recorded(&file, "other.local", 22).len(), 1);
+        assert!(std::fs::read_to_string(&file).unwrap().starts_with("# kept\n"));
+        assert_eq!(key.fingerprint(), FINGERPRINT);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_scratch_dir_is_private_and_never_reused() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = scratch_dir().unwrap();
+        let mode = std::fs::metadata(&dir).unwrap().permissions().mode();
+        // One already there, whoever made it, is refused rather than written into
+        let again = scratch_dir();
+        std::fs::remove_dir(&dir).unwrap();
+
+        assert_eq!(mode & 0o777, 0o700);
+        assert_eq!(again.unwrap_err().kind(), std::io::ErrorKind::AlreadyExists);
+    }
+}
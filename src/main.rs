@@ -1,8 +1,15 @@
 mod archive;
+mod cancel;
+mod checksum;
 mod cli;
 mod commands;
 mod config;
+mod exclude;
+mod hostkey;
 mod output;
+mod preflight;
+mod probe;
+mod progress;
 mod resolve;
 mod transfer;
 
@@ -22,7 +29,7 @@ fn main() -> ExitCode {
     }
 
     // Load config
-    let config = match Config::load(cli.config.clone()) {
+    let mut config = match Config::load(cli.config.clone()) {
         Ok(c) => c,
         Err(e) => {
             eprintln!("error: {}", e);
@@ -32,12 +39,24 @@ fn main() -> ExitCode {
         }
     };
 
+    config.overrides.via = cli.via;
+    config.overrides.limit = cli.limit;
+    config.overrides.force = cli.force;
+    // A bare --verify uses the configured algorithm, or SHA-256
+    config.overrides.verify = cli.verify.map(|algorithm| {
+        algorithm.or(config.defaults.verify).unwrap_or_default()
+    });
+
     let output = if cli.json {
+        progress::init(progress::Mode::Json);
         Output::Json
     } else {
+        progress::init(progress::Mode::Human);
         Output::Human
     };
 
+    cancel::install(cli.json);
+
     match cli.command {
         Commands::File { path, dest, overwrite } => {
             match commands::file::run(&path, &dest, overwrite, &config, cli.verbose, cli.dry_run) {
@@ -46,51 +65,75 @@ fn main() -> ExitCode {
                     ExitCode::SUCCESS
                 }
                 Err(e) => {
-                    eprintln!("error: {}", e);
+                    output.error(&e);
                     ExitCode::FAILURE
                 }
             }
         }
 
-        Commands::Dir { dest, name, exclude, extract } => {
-            match commands::dir::run(&dest, name.as_deref(), &exclude, extract, &config, cli.verbose, cli.dry_run) {
+        Commands::Dir { dest, name, exclude, no_ignore, extract, stream, format, jobs } => {
+            config.overrides.format = format;
+            config.overrides.jobs = jobs;
+            config.overrides.no_ignore = no_ignore;
+            match commands::dir::run(
+                &dest,
+                name.as_deref(),
+                &exclude,
+                extract,
+                stream,
+                &config,
+                cli.verbose,
+                cli.dry_run,
+            ) {
                 Ok(result) => {
                     output.print(&result);
                     ExitCode::SUCCESS
                 }
                 Err(e) => {
-                    eprintln!("error: {}", e);
+                    output.error(&e);
                     ExitCode::FAILURE
                 }
             }
         }
 
-        Commands::Dump { path, to } => {
-            match commands::dump::run(path.as_deref(), to.as_deref(), &config, cli.verbose, cli.dry_run) {
+        Commands::Dump { path, to, stream, format, jobs, no_ignore } => {
+            config.overrides.format = format;
+            config.overrides.jobs = jobs;
+            config.overrides.no_ignore = no_ignore;
+            match commands::dump::run(path.as_deref(), to.as_deref(), stream, &config, cli.verbose, cli.dry_run) {
                 Ok(result) => {
                     output.print(&result);
                     ExitCode::SUCCESS
                 }
                 Err(e) => {
-                    eprintln!("error: {}", e);
+                    output.error(&e);
                     ExitCode::FAILURE
                 }
             }
         }
 
-        Commands::Pull { source, no_extract } => {
+        Commands::Pull { source, no_extract, format } => {
+            config.overrides.format = format;
             match commands::pull::run(&source, !no_extract, &config, cli.verbose, cli.dry_run) {
                 Ok(result) => {
                     output.print(&result);
                     ExitCode::SUCCESS
                 }
                 Err(e) => {
-                    eprintln!("error: {}", e);
+                    output.error(&e);
                     ExitCode::FAILURE
                 }
             }
         }
 
+        Commands::Trust { host, yes } => match commands::trust::run(&host, yes, &config, cli.verbose) {
+            Ok(()) => ExitCode::SUCCESS,
+            Err(e) => {
+                output.error(&e);
+                ExitCode::FAILURE
+            }
+        },
+
         Commands::Ls => {
             commands::ls::run(&config, cli.json);
             ExitCode::SUCCESS
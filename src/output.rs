@@ -1,3 +1,5 @@
+use crate::checksum::Digest;
+use crate::transfer::TransferError;
 use serde::Serialize;
 
 #[derive(Debug, Serialize)]
@@ -10,6 +12,14 @@ pub struct TransferResult {
     pub mode: String,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub archive_path: Option<String>,
+    /// Checksum both ends agreed on, when verification was requested
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub digest: Option<Digest>,
+    /// Tries needed by the step that was retried most (1 when nothing failed)
+    pub attempts: u32,
+    /// Bandwidth cap in bytes per second, when one applied
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bandwidth_limit: Option<u64>,
 }
 
 impl TransferResult {
@@ -22,6 +32,15 @@ impl TransferResult {
         if self.duration_ms > 0 {
             println!("time: {}ms", self.duration_ms);
         }
+        if let Some(ref digest) = self.digest {
+            println!("hash: {}", digest);
+        }
+        if self.attempts > 1 {
+            println!("tries: {}", self.attempts);
+        }
+        if let Some(rate) = self.bandwidth_limit {
+            println!("limit: {}/s", format_bytes(rate));
+        }
         println!("ok");
     }
 
@@ -44,9 +63,38 @@ impl Output {
             Output::Json => result.print_json(),
         }
     }
+
+    /// Report a failed command; with `--json` it is also written to stdout
+    pub fn error(&self, err: &(dyn std::error::Error + 'static)) {
+        eprintln!("error: {}", err);
+        if let Output::Json = self {
+            let report = ErrorReport {
+                error: err.to_string(),
+                kind: error_kind(err),
+            };
+            if let Ok(json) = serde_json::to_string_pretty(&report) {
+                println!("{}", json);
+            }
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ErrorReport {
+    error: String,
+    /// What went wrong in a word, when the failure is a known one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    kind: Option<&'static str>,
+}
+
+/// The kind of the transfer failure behind `err`, if there is one
+fn error_kind(err: &(dyn std::error::Error + 'static)) -> Option<&'static str> {
+    std::iter::successors(Some(err), |e| e.source())
+        .find_map(|e| e.downcast_ref::<TransferError>())
+        .map(TransferError::kind)
 }
 
-fn format_bytes(bytes: u64) -> String {
+pub fn format_bytes(bytes: u64) -> String {
     const KB: u64 = 1024;
     const MB: u64 = KB * 1024;
     const GB: u64 = MB * 1024;
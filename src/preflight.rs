@@ -0,0 +1,116 @@
+use crate::output::format_bytes;
+use crate::probe::Capabilities;
+use crate::transfer::{TransferError, Transport};
+use std::path::Path;
+
+/// Disk space a command is about to use in one place
+pub struct Need {
+    location: String,
+    bytes: u64,
+    /// Free space there, when it could be measured
+    available: Option<u64>,
+}
+
+impl Need {
+    /// `bytes` written under a local directory
+    pub fn local(path: &Path, bytes: u64) -> Self {
+        Need {
+            location: path.display().to_string(),
+            bytes,
+            available: local_free(path),
+        }
+    }
+
+    /// `bytes` written under `path` on the destination, measured by the probe script
+    pub fn remote(transport: &dyn Transport, host: &str, path: &str, bytes: u64) -> Self {
+        let available = transport
+            .probe(path)
+            .ok()
+            .and_then(|output| Capabilities::parse(&output, host, path).free_bytes);
+        Need {
+            location: format!("{}:{}", host, path),
+            bytes,
+            available,
+        }
+    }
+}
+
+/// Refuse to start when any location is short on space
+///
+/// Runs before any bytes move, so a transfer that cannot fit fails in
+/// seconds instead of after filling the disk. Places whose free space is
+/// unknown are let through. With `force` a shortfall is only a warning.
+pub fn check(needs: &[Need], force: bool) -> Result<(), TransferError> {
+    for need in needs {
+        let Some(available) = need.available.filter(|a| *a < need.bytes) else { continue };
+        if force {
+            eprintln!(
+                "warning: {} has {} free, about {} needed; continuing because of --force",
+                need.location,
+                format_bytes(available),
+                format_bytes(need.bytes)
+            );
+            continue;
+        }
+        return Err(TransferError::InsufficientSpace {
+            location: need.location.clone(),
+            needed: need.bytes,
+            available,
+        });
+    }
+    Ok(())
+}
+
+/// Free space for unprivileged writes under `path`, or its nearest existing parent
+#[cfg(unix)]
+fn local_free(path: &Path) -> Option<u64> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let existing = path
+        .ancestors()
+        .map(|p| if p.as_os_str().is_empty() { Path::new(".") } else { p })
+        .find(|p| p.is_dir())?;
+    let c_path = std::ffi::CString::new(existing.as_os_str().as_bytes()).ok()?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    // SAFETY: c_path is NUL-terminated and stat is a valid statvfs to fill in
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) } != 0 {
+        return None;
+    }
+    // The field types vary by platform; they are u64 on Linux
+    #[allow(clippy::useless_conversion)]
+    Some(u64::from(stat.f_bavail) * u64::from(stat.f_frsize))
+}
+
+#[cfg(not(unix))]
+fn local_free(_path: &Path) -> Option<u64> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shortfall_refuses_unless_forced() {
+        let dir = tempfile::tempdir().unwrap();
+        let fits = Need::local(dir.path(), 1);
+        assert!(fits.available.is_some());
+
+        let too_big = Need {
+            location: "nas:/scratch".to_string(),
+            bytes: 10 * 1024 * 1024,
+            available: Some(1024 * 1024),
+        };
+        let unknown = Need {
+            location: "nas:/other".to_string(),
+            bytes: u64::MAX,
+            available: None,
+        };
+
+        assert!(check(&[fits, unknown], false).is_ok());
+        let err = check(std::slice::from_ref(&too_big), false).unwrap_err();
+        assert!(matches!(err, TransferError::InsufficientSpace { .. }));
+        assert!(err.to_string().contains("nas:/scratch"));
+        assert!(check(&[too_big], true).is_ok());
+    }
+}
@@ -0,0 +1,221 @@
+use crate::checksum::Checksum;
+use crate::config::{Config, TransportKind};
+use crate::resolve::ResolvedDest;
+use crate::transfer::{TransferError, Transport};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// How long a probe result is trusted before the host is probed again
+const DEFAULT_PROBE_TTL_SECS: u64 = 24 * 60 * 60;
+
+/// Remote tools commands can make use of
+pub const TOOLS: &[&str] = &["zip", "unzip", "tar", "zstd", "rsync", "sha256sum", "b3sum"];
+
+/// Shell script reporting the OS, shell, tools and free space under `$1`, one `key=value` per line
+///
+/// Free space is measured on the nearest existing parent, since the target
+/// directory is often only created by the upload.
+pub fn script() -> String {
+    format!(
+        r#"echo "os=$(uname -s 2>/dev/null)"
+echo "arch=$(uname -m 2>/dev/null)"
+echo "shell=${{SHELL##*/}}"
+for tool in {}; do
+  command -v "$tool" >/dev/null 2>&1 && echo "tool=$tool"
+done
+dir=$1
+while [ -n "$dir" ] && [ ! -d "$dir" ]; do dir=$(dirname "$dir"); done
+[ -n "$dir" ] && df -Pk "$dir" 2>/dev/null | awk 'NR == 2 {{ print "free_kb=" $4 }}'
+exit 0"#,
+        TOOLS.join(" ")
+    )
+}
+
+/// What a host offers, as found by the probe
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Capabilities {
+    pub host: String,
+    pub os: String,
+    pub arch: String,
+    /// The login shell, which runs every remote command
+    pub shell: String,
+    pub tools: BTreeSet<String>,
+    /// The path free space was measured for
+    pub path: String,
+    pub free_bytes: Option<u64>,
+    /// Unix time of the probe
+    pub probed_at: u64,
+}
+
+impl Capabilities {
+    /// Parse the output of [`script`]
+    pub fn parse(output: &str, host: &str, path: &str) -> Self {
+        let mut caps = Capabilities {
+            host: host.to_string(),
+            os: String::new(),
+            arch: String::new(),
+            shell: String::new(),
+            tools: BTreeSet::new(),
+            path: path.to_string(),
+            free_bytes: None,
+            probed_at: now(),
+        };
+
+        for line in output.lines() {
+            let Some((key, value)) = line.trim().split_once('=') else { continue };
+            match key {
+                "os" => caps.os = value.to_string(),
+                "arch" => caps.arch = value.to_string(),
+                "shell" => caps.shell = value.to_string(),
+                "tool" => {
+                    caps.tools.insert(value.to_string());
+                }
+                "free_kb" => caps.free_bytes = value.parse::<u64>().ok().map(|kb| kb * 1024),
+                _ => {}
+            }
+        }
+        caps
+    }
+
+    pub fn has(&self, tool: &str) -> bool {
+        self.tools.contains(tool)
+    }
+
+    /// Fail early when `tool` is missing, rather than halfway through a transfer
+    pub fn require(&self, tool: &'static str, needed_for: &'static str) -> Result<(), TransferError> {
+        if self.has(tool) {
+            return Ok(());
+        }
+        Err(TransferError::RemoteToolMissing {
+            tool,
+            host: self.host.clone(),
+            needed_for,
+        })
+    }
+
+    /// Fail early when the hash tool `--verify` runs on the destination is missing
+    pub fn require_verify(&self, verify: Option<Checksum>) -> Result<(), TransferError> {
+        match verify {
+            Some(algorithm) => self.require(algorithm.tool(), "--verify"),
+            None => Ok(()),
+        }
+    }
+
+    /// Free space at `path`, if that is where it was measured
+    pub fn free_space(&self, path: &str) -> Option<u64> {
+        self.free_bytes.filter(|_| self.path == path)
+    }
+}
+
+/// Capabilities of the destination, from the cache when it is fresh enough
+///
+/// Local destinations do their archiving and hashing in-process, so there is
+/// nothing to probe. A failed probe is not an error: commands fall back to
+/// finding out the hard way, as they did before probing existed.
+pub fn capabilities(
+    transport: &dyn Transport,
+    dest: &ResolvedDest,
+    config: &Config,
+    verbose: bool,
+) -> Option<Capabilities> {
+    if dest.transport == TransportKind::Local {
+        return None;
+    }
+
+    let ttl = config.defaults.probe_ttl.unwrap_or(DEFAULT_PROBE_TTL_SECS);
+    if let Some(cached) = load(dest).filter(|c| now().saturating_sub(c.probed_at) < ttl) {
+        return Some(cached);
+    }
+
+    match refresh(transport, dest) {
+        Ok(caps) => Some(caps),
+        Err(e) => {
+            if verbose {
+                eprintln!("could not probe {}: {}", dest.host, e);
+            }
+            None
+        }
+    }
+}
+
+/// Probe the destination now and cache the result
+pub fn refresh(transport: &dyn Transport, dest: &ResolvedDest) -> Result<Capabilities, TransferError> {
+    let output = transport.probe(&dest.path)?;
+    let caps = Capabilities::parse(&output, &dest.host, &dest.path);
+    store(dest, &caps);
+    Ok(caps)
+}
+
+/// Cache file for a host, keyed by how it is reached
+fn cache_path(dest: &ResolvedDest) -> Option<PathBuf> {
+    let key: String = format!("{}@{}_{}", dest.user, dest.host, dest.port)
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || "@._-".contains(c) { c } else { '_' })
+        .collect();
+    Some(dirs::cache_dir()?.join("quick-copy").join("hosts").join(format!("{}.json", key)))
+}
+
+fn load(dest: &ResolvedDest) -> Option<Capabilities> {
+    let contents = std::fs::read_to_string(cache_path(dest)?).ok()?;
+    serde_json::from_str(&contents).ok()
+}
+
+/// Save a probe result; the cache is only an optimisation, so failures are ignored
+fn store(dest: &ResolvedDest, caps: &Capabilities) {
+    let Some(path) = cache_path(dest) else { return };
+    let Ok(json) = serde_json::to_string_pretty(caps) else { return };
+    if let Some(dir) = path.parent() {
+        let _ = std::fs::create_dir_all(dir);
+    }
+    let _ = std::fs::write(path, json);
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_probe_output() {
+        let output = "os=Linux\narch=x86_64\nshell=bash\ntool=zip\ntool=tar\ntool=sha256sum\nfree_kb=2048\n";
+        let caps = Capabilities::parse(output, "nas.local", "/scratch");
+
+        assert_eq!(caps.os, "Linux");
+        assert_eq!(caps.shell, "bash");
+        assert!(caps.has("zip") && caps.has("tar"));
+        assert!(!caps.has("unzip"));
+        assert_eq!(caps.free_space("/scratch"), Some(2 * 1024 * 1024));
+        assert_eq!(caps.free_space("/elsewhere"), None);
+
+        assert!(caps.require("zip", "pull").is_ok());
+        assert!(matches!(
+            caps.require("unzip", "--extract"),
+            Err(TransferError::RemoteToolMissing { tool: "unzip", .. })
+        ));
+    }
+
+    #[test]
+    fn test_script_runs_under_sh() {
+        let dir = tempfile::tempdir().unwrap();
+        let missing = dir.path().join("not/yet/created");
+
+        let output = std::process::Command::new("sh")
+            .args(["-c", &script(), "qc-probe"])
+            .arg(&missing)
+            .output()
+            .unwrap();
+        let caps = Capabilities::parse(&String::from_utf8_lossy(&output.stdout), "localhost", "/");
+
+        assert!(output.status.success());
+        assert!(!caps.os.is_empty());
+        assert!(caps.free_bytes.is_some());
+    }
+}
@@ -0,0 +1,230 @@
+use indicatif::{ProgressBar, ProgressStyle};
+use serde::Serialize;
+use std::io::{Read, Write};
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+
+/// Minimum time between JSON progress events for one stage
+const JSON_INTERVAL: Duration = Duration::from_millis(500);
+
+const CHUNK_SIZE: usize = 256 * 1024;
+
+/// Where progress goes for this process
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// Progress bars on a terminal stderr
+    Human,
+    /// Newline-delimited JSON events on stderr
+    Json,
+}
+
+static MODE: OnceLock<Mode> = OnceLock::new();
+
+/// Select how progress is reported; call once at startup
+pub fn init(mode: Mode) {
+    let _ = MODE.set(mode);
+}
+
+fn mode() -> Mode {
+    MODE.get().copied().unwrap_or(Mode::Human)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Stage {
+    Archive,
+    Upload,
+    Download,
+    Extract,
+    Verify,
+}
+
+impl Stage {
+    fn label(self) -> &'static str {
+        match self {
+            Stage::Archive => "archiving",
+            Stage::Upload => "uploading",
+            Stage::Download => "downloading",
+            Stage::Extract => "extracting",
+            Stage::Verify => "verifying",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Unit {
+    Bytes,
+    Files,
+}
+
+#[derive(Serialize)]
+struct Event {
+    event: &'static str,
+    stage: Stage,
+    unit: Unit,
+    done: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    total: Option<u64>,
+    /// Units per second since the stage started
+    rate: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    eta_secs: Option<u64>,
+}
+
+/// Progress of one stage of a command
+pub struct Progress {
+    stage: Stage,
+    unit: Unit,
+    total: Option<u64>,
+    done: u64,
+    started: Instant,
+    last_event: Instant,
+    bar: Option<ProgressBar>,
+    finished: bool,
+}
+
+impl Progress {
+    /// Start reporting a stage; `total` is `None` when it isn't known up front
+    pub fn start(stage: Stage, unit: Unit, total: Option<u64>) -> Self {
+        let bar = match mode() {
+            Mode::Human => Some(Self::bar(stage, unit, total)),
+            Mode::Json => None,
+        };
+
+        let now = Instant::now();
+        let progress = Self {
+            stage,
+            unit,
+            total,
+            done: 0,
+            started: now,
+            last_event: now,
+            bar,
+            finished: false,
+        };
+        progress.emit("start");
+        progress
+    }
+
+    fn bar(stage: Stage, unit: Unit, total: Option<u64>) -> ProgressBar {
+        let (bar, template) = match (total, unit) {
+            (Some(t), Unit::Bytes) => (
+                ProgressBar::new(t),
+                "{msg:>11} [{bar:30}] {bytes}/{total_bytes} {bytes_per_sec} ETA {eta}",
+            ),
+            (Some(t), Unit::Files) => (ProgressBar::new(t), "{msg:>11} [{bar:30}] {pos}/{len} files ETA {eta}"),
+            (None, Unit::Bytes) => (ProgressBar::new_spinner(), "{msg:>11} {spinner} {bytes} {bytes_per_sec}"),
+            (None, Unit::Files) => (ProgressBar::new_spinner(), "{msg:>11} {spinner} {pos} files"),
+        };
+
+        if let Ok(style) = ProgressStyle::with_template(template) {
+            bar.set_style(style.progress_chars("=> "));
+        }
+        bar.set_message(stage.label());
+        bar
+    }
+
+    /// Record `n` more units done
+    pub fn inc(&mut self, n: u64) {
+        self.set(self.done + n);
+    }
+
+    /// Record the absolute amount done
+    pub fn set(&mut self, done: u64) {
+        self.done = done;
+        if let Some(ref bar) = self.bar {
+            bar.set_position(done);
+        }
+        if self.last_event.elapsed() >= JSON_INTERVAL {
+            self.last_event = Instant::now();
+            self.emit("progress");
+        }
+    }
+
+    /// Mark the stage as complete
+    pub fn finish(mut self) {
+        self.finished = true;
+
+        if let Some(ref bar) = self.bar {
+            bar.finish_and_clear();
+        }
+        self.emit("finish");
+    }
+
+    fn emit(&self, event: &'static str) {
+        if mode() != Mode::Json {
+            return;
+        }
+
+        let elapsed = self.started.elapsed().as_secs_f64();
+        let rate = if elapsed > 0.0 { (self.done as f64 / elapsed) as u64 } else { 0 };
+        let eta_secs = match self.total {
+            Some(total) if rate > 0 => Some(total.saturating_sub(self.done) / rate),
+            _ => None,
+        };
+
+        let event = Event {
+            event,
+            stage: self.stage,
+            unit: self.unit,
+            done: self.done,
+            total: self.total,
+            rate,
+            eta_secs,
+        };
+        if let Ok(line) = serde_json::to_string(&event) {
+            eprintln!("{}", line);
+        }
+    }
+}
+
+impl Drop for Progress {
+    fn drop(&mut self) {
+        // An abandoned stage still clears its bar, but isn't reported as finished
+        if !self.finished {
+            if let Some(ref bar) = self.bar {
+                bar.abandon();
+            }
+        }
+    }
+}
+
+/// Copy everything from `reader` to `writer`, reporting bytes as they go
+pub fn copy(reader: &mut impl Read, writer: &mut impl Write, progress: &mut Progress) -> std::io::Result<u64> {
+    let mut buf = vec![0u8; CHUNK_SIZE];
+    let mut copied = 0u64;
+
+    loop {
+        let n = match reader.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        writer.write_all(&buf[..n])?;
+        copied += n as u64;
+        progress.inc(n as u64);
+    }
+
+    writer.flush()?;
+    Ok(copied)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_copy_counts_bytes() {
+        let data = vec![7u8; CHUNK_SIZE * 2 + 17];
+        let mut out = Vec::new();
+        let mut progress = Progress::start(Stage::Upload, Unit::Bytes, Some(data.len() as u64));
+
+        let copied = copy(&mut data.as_slice(), &mut out, &mut progress).unwrap();
+        assert_eq!(copied, data.len() as u64);
+        assert_eq!(progress.done, data.len() as u64);
+        assert_eq!(out, data);
+        progress.finish();
+    }
+}
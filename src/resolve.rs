@@ -1,28 +1,192 @@
-use crate::config::{Config, Host};
+use crate::config::{Config, Host, Rate, TransferMethod, TransportKind};
+use crate::hostkey;
+use crate::transfer::quote;
+use std::path::PathBuf;
+use std::time::Duration;
 use thiserror::Error;
 
+const DEFAULT_RETRY_ATTEMPTS: u32 = 3;
+const DEFAULT_RETRY_BACKOFF_SECS: f64 = 1.0;
+const DEFAULT_RETRY_MAX_DELAY_SECS: f64 = 30.0;
+const DEFAULT_CONNECT_TIMEOUT_SECS: f64 = 10.0;
+const DEFAULT_COMMAND_TIMEOUT_SECS: f64 = 60.0;
+const DEFAULT_STALL_TIMEOUT_SECS: f64 = 60.0;
+
 #[derive(Error, Debug)]
 pub enum ResolveError {
     #[error("unknown host '{name}'{}", suggestion.as_ref().map(|s| format!(" (did you mean '{}'?)", s)).unwrap_or_default())]
     UnknownHost { name: String, suggestion: Option<String> },
     #[error("host '{host}' has no path alias '{alias}'. Run: qc ls")]
     UnknownAlias { host: String, alias: String },
+    #[error("host '{host}' jumps through unknown host '{jump}'. Jump hosts must be configured under hosts")]
+    UnknownJumpHost { host: String, jump: String },
+    #[error("jump hosts form a loop: {0}")]
+    JumpLoop(String),
+    #[error("ssh option '{option}' on host '{host}' conflicts with quick-copy: {hint}")]
+    SshOptionConflict { host: String, option: String, hint: &'static str },
+    #[error("host '{host}' pins its key, but: {source}")]
+    NoKnownHostsFile { host: String, source: std::io::Error },
+}
+
+/// ssh options quick-copy sets itself, and what to use instead
+const RESERVED_SSH_OPTIONS: &[(&str, &str)] = &[
+    ("hostname", "set `host` instead"),
+    ("user", "set `user` instead"),
+    ("port", "set `port` instead"),
+    ("identityfile", "set `identity_file` instead"),
+    ("proxyjump", "set `jump` instead"),
+    ("controlmaster", "connection sharing is set with `multiplex`"),
+    ("controlpath", "connection sharing is set with `multiplex`"),
+    ("controlpersist", "connection sharing is set with `multiplex`"),
+    ("remotecommand", "quick-copy runs its own remote commands"),
+    ("requesttty", "transfers stream data over stdin/stdout and need no tty"),
+    ("sessiontype", "quick-copy runs its own remote commands"),
+    ("stdinnull", "transfers stream data over stdin"),
+    ("forkafterauthentication", "quick-copy waits for each ssh call to finish"),
+    ("connecttimeout", "set `timeouts.connect` instead"),
+];
+
+/// ssh options a pinned host key sets, which a host with `host_keys` or `known_hosts` can't override
+const HOST_KEY_SSH_OPTIONS: &[&str] = &[
+    "userknownhostsfile",
+    "globalknownhostsfile",
+    "stricthostkeychecking",
+    "knownhostscommand",
+];
+
+/// A host an ssh connection hops through on the way to the destination
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Hop {
+    /// The host's name in the config
+    pub name: String,
+    pub user: String,
+    pub host: String,
+    pub port: u16,
+    pub identity_file: Option<std::path::PathBuf>,
+    pub host_key: HostKeyCheck,
+    /// Extra ssh options as (key, value) pairs
+    pub ssh_options: Vec<(String, String)>,
+}
+
+impl Hop {
+    /// Format as user@host[:port] for ProxyJump
+    pub fn spec(&self) -> String {
+        if self.port == 22 {
+            format!("{}@{}", self.user, self.host)
+        } else {
+            format!("{}@{}:{}", self.user, self.host, self.port)
+        }
+    }
+
+    /// Whether reaching the hop takes more than ProxyJump's user, host and port
+    fn needs_own_args(&self) -> bool {
+        self.host_key.is_pinned() || self.identity_file.is_some() || !self.ssh_options.is_empty()
+    }
+}
+
+/// How often and how patiently a failed step is retried
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RetryPolicy {
+    /// Total tries per step, including the first
+    pub attempts: u32,
+    pub backoff: Duration,
+    pub max_delay: Duration,
 }
 
-/// Resolved destination ready for SSH/SCP
+impl RetryPolicy {
+    /// Wait before the `retry`th retry, doubling each time up to the maximum
+    pub fn delay(&self, retry: u32) -> Duration {
+        let factor = 2f64.powi(retry.saturating_sub(1).min(30) as i32);
+        self.backoff.mul_f64(factor).min(self.max_delay)
+    }
+}
+
+/// How long each kind of remote step may run before it is stopped (`None` waits forever)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TimeoutPolicy {
+    pub connect: Option<Duration>,
+    pub command: Option<Duration>,
+    pub zip: Option<Duration>,
+    pub unzip: Option<Duration>,
+    /// Longest a data transfer may go without progress
+    pub stall: Option<Duration>,
+}
+
+/// Which host keys ssh accepts for a host
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum HostKeyCheck {
+    /// ssh's own known_hosts files and settings
+    #[default]
+    System,
+    /// Only keys recorded in `known_hosts` are accepted
+    ///
+    /// With `fingerprints`, `qc trust` only records keys that match one, and
+    /// the native transport checks the key against them as well.
+    Pinned {
+        known_hosts: PathBuf,
+        fingerprints: Vec<String>,
+    },
+}
+
+impl HostKeyCheck {
+    pub fn is_pinned(&self) -> bool {
+        matches!(self, HostKeyCheck::Pinned { .. })
+    }
+
+    fn ssh_args(&self) -> Vec<String> {
+        let HostKeyCheck::Pinned { known_hosts, .. } = self else {
+            return Vec::new();
+        };
+        [
+            format!("UserKnownHostsFile={}", hostkey::ssh_path(known_hosts)),
+            "GlobalKnownHostsFile=/dev/null".to_string(),
+            "StrictHostKeyChecking=yes".to_string(),
+        ]
+        .into_iter()
+        .flat_map(|option| ["-o".to_string(), option])
+        .collect()
+    }
+}
+
+/// Another user to write into the destination as, through non-interactive sudo
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Become {
+    pub user: String,
+    /// Remote directory in which each transfer makes its own private directory
+    /// for uploads to wait in, as the login user, before being copied into place
+    pub staging_dir: String,
+}
+
+/// Resolved destination ready for ssh and rsync
 #[derive(Debug, Clone)]
 pub struct ResolvedDest {
+    /// The host's name in the config
+    pub name: String,
     pub user: String,
     pub host: String,
     pub port: u16,
     pub path: String,
     pub identity_file: Option<std::path::PathBuf>,
+    pub transport: TransportKind,
+    pub multiplex: bool,
+    pub via: TransferMethod,
+    /// Hosts to hop through, in order
+    pub jump: Vec<Hop>,
+    /// Extra ssh options as (key, value) pairs
+    pub ssh_options: Vec<(String, String)>,
+    pub retry: RetryPolicy,
+    pub timeouts: TimeoutPolicy,
+    /// Cap on transfer speed in bytes per second
+    pub bandwidth_limit: Option<u64>,
+    pub host_key: HostKeyCheck,
+    /// Write into the destination as another user
+    pub sudo: Option<Become>,
 }
 
 impl ResolvedDest {
-    /// Format as user@host:/path for scp
-    pub fn scp_target(&self) -> String {
-        format!("{}@{}:{}", self.user, self.host, self.path)
+    /// Format as user@host:/path for rsync
+    pub fn remote_spec(&self, path: &str) -> String {
+        format!("{}@{}:{}", self.user, self.host, path)
     }
 
     /// Format as user@host for ssh
@@ -30,33 +194,98 @@ impl ResolvedDest {
         format!("{}@{}", self.user, self.host)
     }
 
-    /// Get SSH args for port and identity
+    /// Get SSH args for port, identity and host key checking
     pub fn ssh_args(&self) -> Vec<String> {
-        let mut args = Vec::new();
+        let mut args = self.route_args();
+        args.extend(self.host_key.ssh_args());
+        args
+    }
+
+    /// The same args in the form scp takes them, where `-p` preserves times
+    /// and the port is `-P`
+    pub fn scp_args(&self) -> Vec<String> {
+        let mut args = self.ssh_args();
+        // route_args puts the port first
         if self.port != 22 {
-            args.push("-p".to_string());
-            args.push(self.port.to_string());
-        }
-        if let Some(ref id) = self.identity_file {
-            args.push("-i".to_string());
-            args.push(id.display().to_string());
+            args[0] = "-P".to_string();
         }
         args
     }
 
-    /// Get SCP args for port and identity
-    pub fn scp_args(&self) -> Vec<String> {
+    /// SSH args to reach the host, leaving its key to be checked by the caller
+    pub fn route_args(&self) -> Vec<String> {
         let mut args = Vec::new();
         if self.port != 22 {
-            args.push("-P".to_string());
+            args.push("-p".to_string());
             args.push(self.port.to_string());
         }
         if let Some(ref id) = self.identity_file {
             args.push("-i".to_string());
             args.push(id.display().to_string());
         }
+        if let Some(connect) = self.timeouts.connect {
+            // ssh only takes whole seconds
+            args.push("-o".to_string());
+            args.push(format!("ConnectTimeout={}", connect.as_secs_f64().ceil().max(1.0)));
+        }
+        if self.jump.iter().any(Hop::needs_own_args) {
+            // ProxyJump doesn't pass identities or options on to the hops, so spell
+            // out the last hop's ssh command; it reaches the hops before it the same way
+            let last = self.hop(self.jump.len() - 1);
+            let proxy: Vec<String> = std::iter::once("ssh".to_string())
+                .chain(last.ssh_args().iter().map(|a| quote(&a.replace('%', "%%"))))
+                .chain(["-W".to_string(), "%h:%p".to_string(), quote(&last.ssh_target())])
+                .collect();
+            args.push("-o".to_string());
+            args.push(format!("ProxyCommand={}", proxy.join(" ")));
+        } else if !self.jump.is_empty() {
+            args.push("-J".to_string());
+            args.push(self.jump.iter().map(Hop::spec).collect::<Vec<_>>().join(","));
+        }
+        for (key, value) in &self.ssh_options {
+            args.push("-o".to_string());
+            args.push(format!("{}={}", key, value));
+        }
         args
     }
+
+    /// The ssh command that reaches the host the way quick-copy does, for the
+    /// user to run themselves, e.g. to accept its host key
+    pub fn ssh_command(&self) -> String {
+        let mut args = self.route_args();
+        // Waiting on a prompt isn't slow to connect
+        if let Some(i) = args.iter().position(|a| a.starts_with("ConnectTimeout=")) {
+            args.drain(i - 1..=i);
+        }
+        std::iter::once("ssh".to_string())
+            .chain(args.iter().map(|a| quote(a)))
+            .chain([quote(&self.ssh_target())])
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    /// The `n`th jump host as a destination of its own, reached through the hops before it
+    pub fn hop(&self, n: usize) -> ResolvedDest {
+        let hop = &self.jump[n];
+        ResolvedDest {
+            name: hop.name.clone(),
+            user: hop.user.clone(),
+            host: hop.host.clone(),
+            port: hop.port,
+            path: "/".to_string(),
+            identity_file: hop.identity_file.clone(),
+            transport: TransportKind::Ssh,
+            multiplex: false,
+            via: TransferMethod::Ssh,
+            jump: self.jump[..n].to_vec(),
+            ssh_options: hop.ssh_options.clone(),
+            retry: self.retry,
+            timeouts: self.timeouts,
+            bandwidth_limit: None,
+            host_key: hop.host_key.clone(),
+            sudo: None,
+        }
+    }
 }
 
 /// Parse destination string: "host", "host:alias", or "host:/absolute/path"
@@ -79,39 +308,192 @@ pub fn resolve(dest: &str, config: &Config) -> Result<ResolvedDest, ResolveError
         suggestion: config.find_similar_host(&host_name).cloned(),
     })?;
 
-    let path = resolve_path(host, path_or_alias.as_deref(), config)?;
+    let (path, alias_become) = resolve_path(host, path_or_alias.as_deref(), config)?;
+
+    let port = host.port.unwrap_or(config.defaults.port);
+    let host_key = resolve_host_key(&host_name, host, port, config)?;
 
     Ok(ResolvedDest {
+        name: host_name.clone(),
         user: host.user.clone().unwrap_or_else(|| config.defaults.user.clone()),
         host: host.host.clone(),
-        port: host.port.unwrap_or(config.defaults.port),
+        port,
         path,
         identity_file: host.identity_file.clone(),
+        transport: host.transport,
+        multiplex: host.multiplex.unwrap_or(true),
+        via: config.overrides.via.or(host.via).unwrap_or(config.defaults.via),
+        jump: resolve_jumps(&host_name, host, config, &mut vec![host_name.clone()])?,
+        ssh_options: resolve_ssh_options(&host_name, host, &host_key, config)?,
+        retry: resolve_retry(host, config),
+        timeouts: resolve_timeouts(host, config),
+        bandwidth_limit: config
+            .overrides
+            .limit
+            .or(host.bandwidth_limit)
+            .or(config.defaults.bandwidth_limit)
+            .and_then(Rate::bytes_per_sec),
+        host_key,
+        sudo: alias_become.or_else(|| host.become_user.clone()).map(|user| Become {
+            user,
+            staging_dir: host
+                .remote_staging_dir
+                .clone()
+                .unwrap_or_else(|| config.defaults.remote_staging_dir.clone()),
+        }),
     })
 }
 
-fn resolve_path(host: &Host, path_or_alias: Option<&str>, config: &Config) -> Result<String, ResolveError> {
-    match path_or_alias {
-        // Explicit absolute path
-        Some(p) if p.starts_with('/') => Ok(p.to_string()),
-        // Named alias
-        Some(alias) => {
-            host.paths.get(alias).cloned().ok_or_else(|| ResolveError::UnknownAlias {
-                host: host.host.clone(),
-                alias: alias.to_string(),
-            })
-        }
-        // Default alias
+/// Pin the host's key when the config asks for it, or `qc trust` recorded one
+///
+/// A host's own `known_hosts` wins over the defaults'. Fingerprints alone
+/// pin to the file `qc trust` manages, where the matching key is recorded.
+fn resolve_host_key(name: &str, host: &Host, port: u16, config: &Config) -> Result<HostKeyCheck, ResolveError> {
+    let configured = host.known_hosts.as_ref().or(config.defaults.known_hosts.as_ref());
+    let known_hosts = match configured {
+        Some(file) => hostkey::expand_home(file),
         None => {
-            let default_alias = &config.defaults.default_path_alias;
-            host.paths.get(default_alias).cloned().ok_or_else(|| ResolveError::UnknownAlias {
-                host: host.host.clone(),
-                alias: default_alias.clone(),
-            })
+            let managed = hostkey::managed_file();
+            let trusted = managed
+                .as_ref()
+                .is_ok_and(|file| !hostkey::recorded(file, &host.host, port).is_empty());
+            if host.host_keys.is_empty() && !trusted {
+                return Ok(HostKeyCheck::System);
+            }
+            managed.map_err(|source| ResolveError::NoKnownHostsFile {
+                host: name.to_string(),
+                source,
+            })?
         }
+    };
+
+    Ok(HostKeyCheck::Pinned {
+        known_hosts,
+        fingerprints: host.host_keys.clone(),
+    })
+}
+
+fn resolve_retry(host: &Host, config: &Config) -> RetryPolicy {
+    let defaults = &config.defaults.retry;
+    let seconds = |host: Option<f64>, default: Option<f64>, fallback: f64| {
+        Duration::try_from_secs_f64(host.or(default).unwrap_or(fallback)).unwrap_or(Duration::ZERO)
+    };
+
+    RetryPolicy {
+        attempts: host.retry.attempts.or(defaults.attempts).unwrap_or(DEFAULT_RETRY_ATTEMPTS).max(1),
+        backoff: seconds(host.retry.backoff, defaults.backoff, DEFAULT_RETRY_BACKOFF_SECS),
+        max_delay: seconds(host.retry.max_delay, defaults.max_delay, DEFAULT_RETRY_MAX_DELAY_SECS),
     }
 }
 
+fn resolve_timeouts(host: &Host, config: &Config) -> TimeoutPolicy {
+    let defaults = &config.defaults.timeouts;
+    let limit = |host: Option<f64>, default: Option<f64>, fallback: Option<f64>| {
+        host.or(default)
+            .or(fallback)
+            .and_then(|secs| Duration::try_from_secs_f64(secs).ok())
+            .filter(|d| !d.is_zero())
+    };
+
+    TimeoutPolicy {
+        connect: limit(host.timeouts.connect, defaults.connect, Some(DEFAULT_CONNECT_TIMEOUT_SECS)),
+        command: limit(host.timeouts.command, defaults.command, Some(DEFAULT_COMMAND_TIMEOUT_SECS)),
+        zip: limit(host.timeouts.zip, defaults.zip, None),
+        unzip: limit(host.timeouts.unzip, defaults.unzip, None),
+        stall: limit(host.timeouts.stall, defaults.stall, Some(DEFAULT_STALL_TIMEOUT_SECS)),
+    }
+}
+
+/// Merge host ssh options over the defaults, rejecting ones quick-copy controls
+///
+/// ssh option names are case-insensitive, so `compression` on a host replaces
+/// `Compression` from the defaults rather than being passed alongside it.
+fn resolve_ssh_options(
+    name: &str,
+    host: &Host,
+    host_key: &HostKeyCheck,
+    config: &Config,
+) -> Result<Vec<(String, String)>, ResolveError> {
+    let mut merged = std::collections::BTreeMap::new();
+    for (key, value) in config.defaults.ssh_options.iter().chain(&host.ssh_options) {
+        merged.insert(key.to_lowercase(), (key.clone(), value.clone()));
+    }
+
+    for (lower, (key, _)) in &merged {
+        let hint = match RESERVED_SSH_OPTIONS.iter().find(|(option, _)| option == lower) {
+            Some((_, hint)) => *hint,
+            // ProxyCommand and ProxyJump can't both decide how to reach the host
+            None if lower == "proxycommand" && !host.jump.is_empty() => "it can't be combined with `jump`",
+            None if host_key.is_pinned() && HOST_KEY_SSH_OPTIONS.contains(&lower.as_str()) => {
+                "the host key is pinned with `host_keys` or `known_hosts`"
+            }
+            None => continue,
+        };
+
+        return Err(ResolveError::SshOptionConflict {
+            host: name.to_string(),
+            option: key.clone(),
+            hint,
+        });
+    }
+
+    Ok(merged.into_values().collect())
+}
+
+/// Expand a host's `jump` list, including the jump hosts' own jumps
+fn resolve_jumps(name: &str, host: &Host, config: &Config, seen: &mut Vec<String>) -> Result<Vec<Hop>, ResolveError> {
+    let mut hops = Vec::new();
+    for jump_name in &host.jump {
+        if seen.contains(jump_name) {
+            seen.push(jump_name.clone());
+            return Err(ResolveError::JumpLoop(seen.join(" -> ")));
+        }
+        let jump = config.get_host(jump_name).ok_or_else(|| ResolveError::UnknownJumpHost {
+            host: name.to_string(),
+            jump: jump_name.clone(),
+        })?;
+
+        seen.push(jump_name.clone());
+        hops.extend(resolve_jumps(jump_name, jump, config, seen)?);
+        seen.pop();
+
+        let port = jump.port.unwrap_or(config.defaults.port);
+        let host_key = resolve_host_key(jump_name, jump, port, config)?;
+        hops.push(Hop {
+            name: jump_name.clone(),
+            user: jump.user.clone().unwrap_or_else(|| config.defaults.user.clone()),
+            host: jump.host.clone(),
+            port,
+            identity_file: jump.identity_file.clone(),
+            ssh_options: resolve_ssh_options(jump_name, jump, &host_key, config)?,
+            host_key,
+        });
+    }
+    Ok(hops)
+}
+
+/// The path a destination names, and the user an alias asks to write it as
+fn resolve_path(
+    host: &Host,
+    path_or_alias: Option<&str>,
+    config: &Config,
+) -> Result<(String, Option<String>), ResolveError> {
+    let alias = match path_or_alias {
+        // Explicit absolute path
+        Some(p) if p.starts_with('/') => return Ok((p.to_string(), None)),
+        // Named alias
+        Some(alias) => alias,
+        // Default alias
+        None => &config.defaults.default_path_alias,
+    };
+
+    let entry = host.paths.get(alias).ok_or_else(|| ResolveError::UnknownAlias {
+        host: host.host.clone(),
+        alias: alias.to_string(),
+    })?;
+    Ok((entry.path.clone(), entry.become_user.clone()))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -128,4 +510,281 @@ mod tests {
             ("andromeda".to_string(), Some("/home/chris".to_string()))
         );
     }
+
+    #[test]
+    fn test_jump_chain() {
+        let config: Config = serde_yaml::from_str(
+            r#"
+version: 1
+defaults:
+  user: me
+hosts:
+  gate:
+    host: gate.example.com
+    port: 2222
+  bastion:
+    host: 10.0.0.1
+    user: ops
+    jump: gate
+  gpu:
+    host: 10.0.1.5
+    jump: [bastion]
+    paths:
+      scratch: /scratch
+  loop-a:
+    host: a
+    jump: loop-b
+  loop-b:
+    host: b
+    jump: loop-a
+"#,
+        )
+        .unwrap();
+
+        let gpu = resolve("gpu", &config).unwrap();
+        let specs: Vec<_> = gpu.jump.iter().map(Hop::spec).collect();
+        assert_eq!(specs, ["me@gate.example.com:2222", "ops@10.0.0.1"]);
+        assert!(gpu
+            .ssh_args()
+            .ends_with(&["-J".to_string(), "me@gate.example.com:2222,ops@10.0.0.1".to_string()]));
+
+        // scp spells the port -P, but a hop's port stays in its spec
+        let gate = resolve("gate:/tmp", &config).unwrap();
+        assert_eq!(gate.ssh_args()[..2], ["-p", "2222"]);
+        assert_eq!(gate.scp_args()[..2], ["-P", "2222"]);
+        assert_eq!(gpu.scp_args(), gpu.ssh_args());
+        assert_eq!(gpu.ssh_command(), "ssh -J me@gate.example.com:2222,ops@10.0.0.1 me@10.0.1.5");
+
+        // Each hop is reached through the ones before it
+        assert!(gpu.hop(0).jump.is_empty());
+        assert_eq!(gpu.hop(1).jump, gpu.jump[..1]);
+
+        assert!(matches!(resolve("loop-a:/tmp", &config), Err(ResolveError::JumpLoop(_))));
+
+        // A hop with its own key and options is reached with them
+        let config: Config = serde_yaml::from_str(
+            r#"
+version: 1
+defaults:
+  user: me
+hosts:
+  bastion:
+    host: 10.0.0.1
+    identity_file: /keys/bastion
+    ssh_options: { ServerAliveInterval: 15 }
+  gpu:
+    host: 10.0.1.5
+    jump: bastion
+"#,
+        )
+        .unwrap();
+        let gpu = resolve("gpu:/", &config).unwrap();
+        assert!(!gpu.ssh_args().contains(&"-J".to_string()));
+        assert!(!gpu.ssh_args().contains(&"/keys/bastion".to_string()));
+        let proxy = gpu.ssh_args().into_iter().find(|a| a.starts_with("ProxyCommand=")).unwrap();
+        assert!(proxy.contains("-i /keys/bastion"));
+        assert!(proxy.contains("ServerAliveInterval=15"));
+        assert!(proxy.ends_with("-W %h:%p me@10.0.0.1"));
+        assert_eq!(gpu.hop(0).ssh_options, [("ServerAliveInterval".to_string(), "15".to_string())]);
+    }
+
+    #[test]
+    fn test_ssh_options_merge_and_conflicts() {
+        let config: Config = serde_yaml::from_str(
+            r#"
+version: 1
+defaults:
+  ssh_options:
+    Compression: no
+    ServerAliveInterval: 30
+hosts:
+  box:
+    host: box.local
+    ssh_options:
+      compression: yes
+      StrictHostKeyChecking: accept-new
+  bad:
+    host: bad.local
+    ssh_options:
+      controlpath: /tmp/mine
+"#,
+        )
+        .unwrap();
+
+        let args = resolve("box:/tmp", &config).unwrap().ssh_args();
+        assert_eq!(
+            args,
+            [
+                "-o",
+                "ConnectTimeout=10",
+                "-o",
+                "compression=yes",
+                "-o",
+                "ServerAliveInterval=30",
+                "-o",
+                "StrictHostKeyChecking=accept-new"
+            ]
+        );
+
+        match resolve("bad:/tmp", &config) {
+            Err(ResolveError::SshOptionConflict { option, .. }) => assert_eq!(option, "controlpath"),
+            other => panic!("expected a conflict, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_via_precedence() {
+        let mut config: Config = serde_yaml::from_str(
+            r#"
+version: 1
+defaults:
+  via: scp
+hosts:
+  nas:
+    host: nas.local
+    via: rsync
+    paths: { scratch: /scratch }
+  box:
+    host: box.local
+    paths: { scratch: /scratch }
+"#,
+        )
+        .unwrap();
+
+        assert_eq!(resolve("nas", &config).unwrap().via, TransferMethod::Rsync);
+        assert_eq!(resolve("box", &config).unwrap().via, TransferMethod::Scp);
+
+        config.overrides.via = Some(TransferMethod::Ssh);
+        assert_eq!(resolve("nas", &config).unwrap().via, TransferMethod::Ssh);
+    }
+
+    #[test]
+    fn test_timeouts() {
+        let config: Config = serde_yaml::from_str(
+            r#"
+version: 1
+defaults:
+  timeouts: { connect: 5, zip: 600 }
+hosts:
+  nas:
+    host: nas.local
+    timeouts: { connect: 2.5, stall: 0 }
+    paths: { scratch: /scratch }
+"#,
+        )
+        .unwrap();
+
+        let timeouts = resolve("nas", &config).unwrap().timeouts;
+        assert_eq!(timeouts.connect, Some(Duration::from_millis(2500)));
+        assert_eq!(timeouts.command, Some(Duration::from_secs(60)));
+        assert_eq!(timeouts.zip, Some(Duration::from_secs(600)));
+        assert_eq!(timeouts.unzip, None);
+        assert_eq!(timeouts.stall, None);
+
+        // ssh only takes whole seconds, so round up rather than time out early
+        let args = resolve("nas", &config).unwrap().ssh_args();
+        assert!(args.contains(&"ConnectTimeout=3".to_string()));
+    }
+
+    #[test]
+    fn test_bandwidth_limit() {
+        let mut config: Config = serde_yaml::from_str(
+            r#"
+version: 1
+defaults:
+  bandwidth_limit: 1M
+hosts:
+  nas:
+    host: nas.local
+    bandwidth_limit: 500K
+    paths: { scratch: /scratch }
+  box:
+    host: box.local
+    paths: { scratch: /scratch }
+"#,
+        )
+        .unwrap();
+
+        assert_eq!(resolve("nas", &config).unwrap().bandwidth_limit, Some(500 * 1024));
+        assert_eq!(resolve("box", &config).unwrap().bandwidth_limit, Some(1024 * 1024));
+
+        config.overrides.limit = Some(Rate(0));
+        assert_eq!(resolve("nas", &config).unwrap().bandwidth_limit, None);
+    }
+
+    #[test]
+    fn test_pinned_host_keys() {
+        let config: Config = serde_yaml::from_str(
+            r#"
+version: 1
+defaults:
+  user: me
+hosts:
+  gate:
+    host: gate.example.com
+    known_hosts: /etc/qc/known_hosts
+  lab:
+    host: 10.0.1.5
+    jump: gate
+    paths: { scratch: /scratch }
+  vault:
+    host: vault.local
+    host_keys: SHA256:eR9PCyCbKpfInJHWZo4l3+gnDFnRWuYFBpe4QgydOuI
+    ssh_options:
+      StrictHostKeyChecking: no
+"#,
+        )
+        .unwrap();
+
+        let gate = resolve("gate:/", &config).unwrap();
+        assert!(gate.ssh_args().ends_with(&[
+            "-o".to_string(),
+            "UserKnownHostsFile=/etc/qc/known_hosts".to_string(),
+            "-o".to_string(),
+            "GlobalKnownHostsFile=/dev/null".to_string(),
+            "-o".to_string(),
+            "StrictHostKeyChecking=yes".to_string(),
+        ]));
+        assert!(!gate.route_args().iter().any(|a| a.contains("KnownHosts")));
+
+        // A pinned hop is reached through a ProxyCommand carrying its own options
+        let lab = resolve("lab", &config).unwrap();
+        assert!(!lab.ssh_args().contains(&"-J".to_string()));
+        let proxy = lab.ssh_args().into_iter().find(|a| a.starts_with("ProxyCommand=")).unwrap();
+        assert!(proxy.contains("UserKnownHostsFile=/etc/qc/known_hosts"));
+        assert!(proxy.ends_with("-W %h:%p me@gate.example.com"));
+
+        assert!(matches!(
+            resolve("vault:/", &config),
+            Err(ResolveError::SshOptionConflict { option, .. }) if option == "StrictHostKeyChecking"
+        ));
+    }
+
+    #[test]
+    fn test_become_precedence() {
+        let config: Config = serde_yaml::from_str(
+            r#"
+version: 1
+hosts:
+  web:
+    host: web.local
+    become: www-data
+    paths:
+      site: /var/www/site
+      logs: { path: /var/log/app, become: root }
+  box:
+    host: box.local
+    paths:
+      scratch: /scratch
+      etc: { path: /etc/app, become: root }
+"#,
+        )
+        .unwrap();
+
+        let user = |dest: &str| resolve(dest, &config).unwrap().sudo.map(|b| b.user);
+        assert_eq!(user("web:site").as_deref(), Some("www-data"));
+        assert_eq!(user("web:logs").as_deref(), Some("root"));
+        assert_eq!(user("box:etc").as_deref(), Some("root"));
+        assert_eq!(user("box:scratch"), None);
+    }
 }
@@ -0,0 +1,154 @@
+use std::fmt;
+
+/// A command line for a POSIX remote shell, built from individually quoted arguments
+#[derive(Debug, Clone)]
+pub struct RemoteCommand {
+    words: Vec<String>,
+}
+
+impl RemoteCommand {
+    pub fn new(program: &str) -> Self {
+        Self {
+            words: vec![quote(program)],
+        }
+    }
+
+    /// Append a literal argument
+    pub fn arg(mut self, arg: &str) -> Self {
+        self.words.push(quote(arg));
+        self
+    }
+
+    /// Append several literal arguments
+    pub fn args<I, S>(mut self, args: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        self.words.extend(args.into_iter().map(|a| quote(a.as_ref())));
+        self
+    }
+
+    /// Append a path argument, guarding against it being read as an option
+    pub fn path(self, path: &str) -> Self {
+        if path.starts_with('-') {
+            self.arg(&format!("./{}", path))
+        } else {
+            self.arg(path)
+        }
+    }
+
+    /// Redirect stdout into a file
+    pub fn write_to(mut self, path: &str) -> Self {
+        self.words.push(">".to_string());
+        self.words.push(quote(path));
+        self
+    }
+
+    /// Feed this command's stdout into `next`
+    pub fn pipe(mut self, next: RemoteCommand) -> Self {
+        self.words.push("|".to_string());
+        self.words.extend(next.words);
+        self
+    }
+
+    /// Run `next` only if this command succeeds
+    pub fn and(mut self, next: RemoteCommand) -> Self {
+        self.words.push("&&".to_string());
+        self.words.extend(next.words);
+        self
+    }
+
+    /// Render as a single string for `ssh host <command>`
+    pub fn render(&self) -> String {
+        self.words.join(" ")
+    }
+}
+
+impl fmt::Display for RemoteCommand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.render())
+    }
+}
+
+/// Quote a word for a POSIX shell
+///
+/// Words made only of characters with no special meaning are left as-is so
+/// verbose output stays readable; everything else is single-quoted.
+pub fn quote(word: &str) -> String {
+    let is_plain = |c: char| c.is_ascii_alphanumeric() || "_-+=@%:,./".contains(c);
+
+    if !word.is_empty() && word.chars().all(is_plain) {
+        word.to_string()
+    } else {
+        format!("'{}'", word.replace('\'', r"'\''"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process::Command;
+
+    const HOSTILE: &[&str] = &[
+        "it's here.txt",
+        "'",
+        "''",
+        "$(touch /tmp/qc-pwned)",
+        "`id`",
+        "a;rm -rf /",
+        "line\nbreak",
+        "-rf",
+        "--help",
+        "*",
+        "~",
+        "back\\slash",
+        "\"double\"",
+        "tab\there",
+        "",
+    ];
+
+    /// Run a rendered command through sh and return what printf received
+    fn echo_through_sh(word: &str) -> String {
+        let cmd = RemoteCommand::new("printf").arg("%s").arg(word);
+        let output = Command::new("sh").arg("-c").arg(cmd.render()).output().unwrap();
+        assert!(output.status.success(), "sh failed for {:?}", word);
+        String::from_utf8(output.stdout).unwrap()
+    }
+
+    #[test]
+    fn test_hostile_args_survive_shell() {
+        for word in HOSTILE {
+            assert_eq!(&echo_through_sh(word), word);
+        }
+    }
+
+    #[test]
+    fn test_path_with_leading_dash() {
+        assert_eq!(RemoteCommand::new("rm").arg("-f").path("-rf").render(), "rm -f ./-rf");
+        assert_eq!(RemoteCommand::new("cd").path("-").render(), "cd ./-");
+        assert_eq!(
+            RemoteCommand::new("rm").path("/tmp/it's").render(),
+            r"rm '/tmp/it'\''s'"
+        );
+    }
+
+    #[test]
+    fn test_and_chains_commands() {
+        let cmd = RemoteCommand::new("cd")
+            .path("/srv/my dir")
+            .and(RemoteCommand::new("zip").arg("-r").path("/tmp/$(x).zip").arg("."));
+        assert_eq!(cmd.render(), "cd '/srv/my dir' && zip -r '/tmp/$(x).zip' .");
+    }
+
+    #[test]
+    fn test_write_to_hostile_path() {
+        let tmp = tempfile::tempdir().unwrap();
+        let target = tmp.path().join("it's $(x)\nnew");
+        let cmd = RemoteCommand::new("printf").arg("%s").arg("data").write_to(&target.display().to_string());
+
+        let status = Command::new("sh").arg("-c").arg(cmd.render()).status().unwrap();
+        assert!(status.success());
+        assert_eq!(std::fs::read_to_string(&target).unwrap(), "data");
+    }
+}
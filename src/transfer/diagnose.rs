@@ -0,0 +1,161 @@
+use super::TransferError;
+use crate::hostkey::known_hosts_name;
+use crate::resolve::ResolvedDest;
+
+/// Turn a failed remote command into the most specific error its stderr allows
+///
+/// ssh, rsync and the remote tools all report problems as text, so this looks
+/// for the messages behind the failures users can act on and falls back to
+/// `CommandFailed` with the full stderr for anything else.
+pub fn command_failed(cmd: &str, code: i32, stderr: &str, dest: &ResolvedDest) -> TransferError {
+    let stderr = stderr.trim();
+    let lower = stderr.to_lowercase();
+    let has = |pattern: &str| lower.contains(pattern);
+
+    // A pinned host is fixed with `qc trust`, not by editing ssh's known_hosts
+    let pinned = dest.host_key.is_pinned();
+    if has("remote host identification has changed") {
+        return if pinned {
+            TransferError::HostKeyPinMismatch {
+                host: dest.host.clone(),
+                name: dest.name.clone(),
+            }
+        } else {
+            TransferError::HostKeyChanged {
+                host: known_hosts_name(&dest.host, dest.port),
+            }
+        };
+    }
+    if has("host key verification failed") {
+        return if pinned {
+            TransferError::HostKeyNotTrusted {
+                host: dest.host.clone(),
+                name: dest.name.clone(),
+            }
+        } else {
+            TransferError::HostKeyUnknown {
+                host: dest.host.clone(),
+                connect: dest.ssh_command(),
+            }
+        };
+    }
+    // ssh lists the methods it tried: "Permission denied (publickey,password)."
+    if has("permission denied (") || has("too many authentication failures") {
+        return TransferError::AuthFailed { target: dest.ssh_target() };
+    }
+    if has("connection refused") {
+        return TransferError::ConnectionRefused {
+            host: dest.host.clone(),
+            port: dest.port,
+        };
+    }
+
+    // sudo -n refuses instead of prompting, and says why on stderr
+    if let Some(target) = &dest.sudo {
+        const SUDO_REFUSED: &[&str] = &[
+            "password is required",
+            "terminal is required",
+            "not in the sudoers",
+            "is not allowed to",
+            "unknown user",
+        ];
+        if let Some(line) = stderr
+            .lines()
+            .find(|line| SUDO_REFUSED.iter().any(|m| line.to_lowercase().contains(m)))
+        {
+            return TransferError::SudoFailed {
+                user: target.user.clone(),
+                host: dest.host.clone(),
+                detail: line.trim().to_string(),
+            };
+        }
+    }
+
+    let detail = |pattern: &str| {
+        stderr
+            .lines()
+            .find(|line| line.to_lowercase().contains(pattern))
+            .unwrap_or(stderr)
+            .trim()
+            .to_string()
+    };
+    if has("no space left on device") || has("disk quota exceeded") {
+        return TransferError::DiskFull {
+            cmd: cmd.to_string(),
+            host: dest.host.clone(),
+            detail: detail(if has("no space") { "no space" } else { "quota" }),
+        };
+    }
+    if has("permission denied") {
+        return TransferError::PermissionDenied {
+            cmd: cmd.to_string(),
+            detail: detail("permission denied"),
+        };
+    }
+    if has("no such file or directory") {
+        return TransferError::NoSuchFile {
+            cmd: cmd.to_string(),
+            detail: detail("no such file"),
+        };
+    }
+
+    TransferError::CommandFailed {
+        cmd: cmd.to_string(),
+        code,
+        stderr: stderr.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    #[test]
+    fn test_classifies_common_failures() {
+        let config: Config = serde_yaml::from_str(
+            r#"
+version: 1
+defaults:
+  user: me
+hosts:
+  nas:
+    host: nas.local
+    port: 2222
+    paths: { scratch: /scratch }
+"#,
+        )
+        .unwrap();
+        let dest = crate::resolve::resolve("nas", &config).unwrap();
+        let classify = |stderr: &str| command_failed("upload", 1, stderr, &dest);
+
+        let changed = "@@@@@@@@@@@\n@    WARNING: REMOTE HOST IDENTIFICATION HAS CHANGED!     @\n\
+                       Host key verification failed.";
+        assert!(matches!(classify(changed), TransferError::HostKeyChanged { host } if host == "[nas.local]:2222"));
+        assert!(matches!(
+            classify("Host key verification failed."),
+            TransferError::HostKeyUnknown { connect, .. } if connect == "ssh -p 2222 me@nas.local"
+        ));
+        assert!(matches!(
+            classify("me@nas.local: Permission denied (publickey,password)."),
+            TransferError::AuthFailed { .. }
+        ));
+        assert!(matches!(
+            classify("ssh: connect to host nas.local port 2222: Connection refused"),
+            TransferError::ConnectionRefused { port: 2222, .. }
+        ));
+        assert!(matches!(
+            classify("sh: 1: cannot create /scratch/.x.qc-part: Permission denied"),
+            TransferError::PermissionDenied { detail, .. } if detail.ends_with("Permission denied")
+        ));
+        assert!(matches!(
+            classify("Warning: Permanently added 'nas.local'\ncat: write error: No space left on device"),
+            TransferError::DiskFull { detail, .. } if detail == "cat: write error: No space left on device"
+        ));
+        assert!(matches!(
+            classify("mv: cannot stat '/scratch/.x.qc-part': No such file or directory"),
+            TransferError::NoSuchFile { .. }
+        ));
+        assert!(matches!(classify("zip error: Nothing to do!"), TransferError::CommandFailed { .. }));
+    }
+}
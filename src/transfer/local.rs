@@ -0,0 +1,262 @@
+use super::{
+    extract_dir, local_digest, local_has_tool, parent_dir, RemoteCommand, Resume, StreamFn, Throttled, TransferError,
+    Transport,
+};
+use crate::archive::{self, Format};
+use crate::cancel::Artifact;
+use crate::checksum::{Checksum, Digest};
+use crate::exclude::Rules;
+use crate::probe;
+use crate::progress::{self, Progress, Stage, Unit};
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::Duration;
+
+/// Transport for destinations on the local filesystem, such as mounted shares
+pub struct LocalTransport {
+    /// Cap on copy speed in bytes per second, for shares on a slow link
+    bandwidth_limit: Option<u64>,
+    verbose: bool,
+}
+
+impl LocalTransport {
+    pub fn new(bandwidth_limit: Option<u64>, verbose: bool) -> Self {
+        Self { bandwidth_limit, verbose }
+    }
+
+    fn io_err(op: &str, path: &str) -> impl FnOnce(std::io::Error) -> TransferError {
+        let op = format!("{} {}", op, path);
+        move |source| TransferError::IoError { op, source }
+    }
+
+    fn copy(&self, from: &Path, to: &Path, stage: Stage) -> Result<(), TransferError> {
+        if self.verbose {
+            eprintln!("+ cp {} {}", from.display(), to.display());
+        }
+
+        let mut reader =
+            File::open(from).map_err(|_| TransferError::LocalNotFound(from.display().to_string()))?;
+        let total = reader.metadata().map(|m| m.len()).ok();
+        let target = to.display().to_string();
+        let writer = File::create(to).map_err(Self::io_err("cp", &target))?;
+        let mut writer = Throttled::new(writer, self.bandwidth_limit);
+
+        let mut progress = Progress::start(stage, Unit::Bytes, total);
+        progress::copy(&mut reader, &mut writer, &mut progress).map_err(Self::io_err("cp", &target))?;
+        progress.finish();
+        Ok(())
+    }
+}
+
+impl Transport for LocalTransport {
+    fn ensure_dir(&self, path: &str) -> Result<(), TransferError> {
+        let dir = parent_dir(path);
+        if self.verbose {
+            eprintln!("+ mkdir -p {}", dir);
+        }
+        std::fs::create_dir_all(&dir).map_err(Self::io_err("mkdir", &dir))
+    }
+
+    fn exists(&self, path: &str) -> Result<bool, TransferError> {
+        if self.verbose {
+            eprintln!("+ test -e {}", path);
+        }
+        Ok(Path::new(path).exists())
+    }
+
+    fn upload(&self, local_path: &Path, remote_path: &str, _resume: Resume) -> Result<(), TransferError> {
+        self.copy(local_path, Path::new(remote_path), Stage::Upload)
+    }
+
+    fn upload_stream(&self, remote_path: &str, write: StreamFn) -> Result<(), TransferError> {
+        if self.verbose {
+            eprintln!("+ cat > {}", remote_path);
+        }
+        let file = File::create(remote_path).map_err(Self::io_err("create", remote_path))?;
+        write(&mut Throttled::new(file, self.bandwidth_limit))
+    }
+
+    fn extract_stream(&self, dir: &str, format: Format, write: StreamFn) -> Result<(), TransferError> {
+        if self.verbose {
+            eprintln!("+ tar -x ({}) -C {}", format, dir);
+        }
+
+        let (reader, writer) = std::io::pipe().map_err(Self::io_err("pipe for", dir))?;
+        let dest = PathBuf::from(dir);
+        let unpack = std::thread::spawn(move || archive::unpack_tar(reader, format, &dest));
+
+        let mut writer = Throttled::new(writer, self.bandwidth_limit);
+        let written = write(&mut writer);
+        drop(writer);
+
+        // An unpack failure explains a broken pipe better than the write error does
+        match unpack.join() {
+            Ok(result) => result?,
+            Err(panic) => std::panic::resume_unwind(panic),
+        }
+        written
+    }
+
+    fn download(&self, remote_path: &str, local_path: &Path) -> Result<(), TransferError> {
+        self.copy(Path::new(remote_path), local_path, Stage::Download)
+    }
+
+    fn extract(&self, archive_path: &str) -> Result<String, TransferError> {
+        let extract_dir = extract_dir(archive_path);
+        if self.verbose {
+            eprintln!("+ extract {} -d {}", archive_path, extract_dir);
+        }
+        std::fs::create_dir_all(extract_dir).map_err(Self::io_err("mkdir", extract_dir))?;
+        archive::extract_archive(Path::new(archive_path), Path::new(extract_dir))?;
+        Ok(extract_dir.to_string())
+    }
+
+    fn archive(
+        &self,
+        dir_path: &str,
+        excludes: &[String],
+        staging_dir: &str,
+        format: Format,
+        follow_symlinks: bool,
+    ) -> Result<String, TransferError> {
+        if self.verbose {
+            eprintln!("+ archive ({}) {} in {}", format, dir_path, staging_dir);
+        }
+        let archive_path = archive::create_archive(
+            Path::new(dir_path),
+            &Rules {
+                patterns: excludes.to_vec(),
+                ignore_files: false,
+            },
+            staging_dir,
+            None,
+            format,
+            follow_symlinks,
+            archive::default_jobs(),
+        )?;
+        Ok(archive_path.display().to_string())
+    }
+
+    fn remove(&self, path: &str) -> Result<(), TransferError> {
+        if self.verbose {
+            eprintln!("+ rm -f {}", path);
+        }
+        match std::fs::remove_file(path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(Self::io_err("rm", path)(e)),
+            _ => Ok(()),
+        }
+    }
+
+    fn checksum(&self, path: &str, algorithm: Checksum) -> Result<Digest, TransferError> {
+        local_digest(Path::new(path), algorithm)
+    }
+
+    fn rename(&self, from: &str, to: &str) -> Result<(), TransferError> {
+        if self.verbose {
+            eprintln!("+ mv -f {} {}", from, to);
+        }
+        std::fs::rename(from, to).map_err(Self::io_err("mv", from))
+    }
+
+    fn test_connectivity(&self) -> Result<(), TransferError> {
+        Ok(())
+    }
+
+    fn has_tool(&self, name: &str) -> Result<bool, TransferError> {
+        Ok(local_has_tool(name))
+    }
+
+    fn probe(&self, path: &str) -> Result<String, TransferError> {
+        let output = std::process::Command::new("sh")
+            .args(["-c", &probe::script(), "qc-probe", path])
+            .output()
+            .map_err(|source| TransferError::ExecError {
+                cmd: "sh".to_string(),
+                source,
+            })?;
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    }
+
+    fn disk_usage(&self, path: &str) -> Result<u64, TransferError> {
+        Ok(archive::estimate_size(Path::new(path), &Rules::default(), false)?)
+    }
+
+    fn execute(&self, cmd: &RemoteCommand, label: &str, _limit: Option<Duration>) -> Result<String, TransferError> {
+        if self.verbose {
+            eprintln!("+ {}", cmd);
+        }
+        let output = Command::new("sh")
+            .arg("-c")
+            .arg(cmd.render())
+            .output()
+            .map_err(|source| TransferError::ExecError {
+                cmd: "sh".to_string(),
+                source,
+            })?;
+        if !output.status.success() {
+            return Err(TransferError::CommandFailed {
+                cmd: label.to_string(),
+                code: output.status.code().unwrap_or(-1),
+                stderr: String::from_utf8_lossy(&output.stderr).trim().to_string(),
+            });
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    }
+
+    fn track(&self, path: &str) -> Artifact {
+        Artifact::local(Path::new(path))
+    }
+
+    fn track_command(&self, path: &str, cleanup: &RemoteCommand) -> Artifact {
+        let script = cleanup.render();
+        Artifact::new(path, move || {
+            let _ = Command::new("sh").arg("-c").arg(script).status();
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_local_round_trip() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        let transport = LocalTransport::new(None, false);
+
+        let src = root.join("src");
+        std::fs::create_dir_all(src.join("nested")).unwrap();
+        std::fs::write(src.join("nested/data.txt"), b"hello").unwrap();
+
+        // Upload into a directory that doesn't exist yet
+        let remote = root.join("share/dumps/data.txt").display().to_string();
+        transport.ensure_dir(&remote).unwrap();
+        transport
+            .upload(&src.join("nested/data.txt"), &remote, Resume::Append)
+            .unwrap();
+        assert!(transport.exists(&remote).unwrap());
+
+        // Archive on the "remote" side, then pull it back and extract
+        let staging = root.join("staging");
+        std::fs::create_dir_all(&staging).unwrap();
+        let zip_path = transport
+            .archive(&src.display().to_string(), &[], &staging.display().to_string(), Format::TarZst, false)
+            .unwrap();
+        assert!(zip_path.ends_with(".tar.zst"));
+        let local_zip = root.join("pulled.tar.zst");
+        transport.download(&zip_path, &local_zip).unwrap();
+        let extracted = transport.extract(&local_zip.display().to_string()).unwrap();
+        assert!(extracted.ends_with("/pulled"));
+        assert_eq!(
+            std::fs::read(Path::new(&extracted).join("nested/data.txt")).unwrap(),
+            b"hello"
+        );
+
+        transport.remove(&zip_path).unwrap();
+        assert!(!transport.exists(&zip_path).unwrap());
+        // Removing a missing file is not an error, matching `rm -f`
+        transport.remove(&zip_path).unwrap();
+    }
+}
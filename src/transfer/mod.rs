@@ -0,0 +1,787 @@
+mod command;
+mod diagnose;
+mod local;
+#[cfg(feature = "native-ssh")]
+mod native;
+mod retry;
+mod ssh;
+mod sudo;
+mod throttle;
+mod watchdog;
+
+use crate::archive::{self, ArchiveError, Format};
+use crate::cancel::Artifact;
+use crate::checksum::{self, Checksum, Digest, HashWriter};
+use crate::config::TransportKind;
+use crate::output::format_bytes;
+use crate::resolve::ResolvedDest;
+use std::io::Write;
+use std::path::Path;
+use std::process::Command;
+use std::time::Duration;
+use thiserror::Error;
+
+pub use command::{quote, RemoteCommand};
+pub use diagnose::command_failed;
+pub use local::LocalTransport;
+#[cfg(feature = "native-ssh")]
+pub use native::NativeTransport;
+pub use retry::Retrying;
+pub use ssh::SshTransport;
+pub use sudo::Sudo;
+pub use throttle::Throttled;
+pub use watchdog::Watchdog;
+
+#[derive(Error, Debug)]
+pub enum TransferError {
+    #[error("failed to execute {cmd}: {source}")]
+    ExecError { cmd: String, source: std::io::Error },
+    #[error("{cmd} failed with exit code {code}: {stderr}")]
+    CommandFailed { cmd: String, code: i32, stderr: String },
+    #[error("{cmd} was killed by signal")]
+    Killed { cmd: String },
+    #[error("{step} timed out ({reason}) and was stopped")]
+    TimedOut { step: String, reason: String },
+    #[error("host key for {host} has changed (possible man-in-the-middle). If the host was reinstalled, run: ssh-keygen -R {host}")]
+    HostKeyChanged { host: String },
+    #[error("host key for {host} is not in known_hosts. Connect once with: {connect}")]
+    HostKeyUnknown { host: String, connect: String },
+    #[error("host key for {host} does not match the one pinned for it (possible man-in-the-middle). If the host was reinstalled, check its new key and run: qc trust {name}")]
+    HostKeyPinMismatch { host: String, name: String },
+    #[error("{host} has no trusted host key yet. Check its fingerprint and record it with: qc trust {name}")]
+    HostKeyNotTrusted { host: String, name: String },
+    #[error("authentication failed for {target}. Check identity_file in the config, or run: ssh-copy-id {target}")]
+    AuthFailed { target: String },
+    #[error("connection to {host} port {port} refused. Check that sshd is running there and the host's port is right")]
+    ConnectionRefused { host: String, port: u16 },
+    #[error("sudo to {user} on {host} failed: {detail}. quick-copy runs sudo -n, so the login user needs a NOPASSWD sudoers rule for {user}; run: qc doctor --test <host> to check")]
+    SudoFailed { user: String, host: String, detail: String },
+    #[error("{cmd} failed, permission denied: {detail}. Check the owner and mode of the remote directory")]
+    PermissionDenied { cmd: String, detail: String },
+    #[error("{cmd} failed, no such file or directory: {detail}. Run: qc ls to check the path aliases")]
+    NoSuchFile { cmd: String, detail: String },
+    #[error("{cmd} failed, {host} is out of space: {detail}. Free some up or pick another path")]
+    DiskFull { cmd: String, host: String, detail: String },
+    #[error("local file not found: {0}")]
+    LocalNotFound(String),
+    #[error("{op} failed: {source}")]
+    IoError { op: String, source: std::io::Error },
+    #[error("{0}")]
+    ArchiveError(#[from] ArchiveError),
+    #[error("checksum mismatch for {path}: sent {sent}, received {received}")]
+    ChecksumMismatch { path: String, sent: Digest, received: Digest },
+    #[error("{tool} not found on the destination (needed for --verify)")]
+    ChecksumToolMissing { tool: &'static str },
+    #[error("not enough space at {location}: about {} needed, {} free. Free some up, or pass --force to try anyway", format_bytes(*needed), format_bytes(*available))]
+    InsufficientSpace { location: String, needed: u64, available: u64 },
+    #[error("{tool} not found on {host} (needed for {needed_for}). Install it there, or run: qc doctor --test <host> to see what is available")]
+    RemoteToolMissing { tool: &'static str, host: String, needed_for: &'static str },
+    #[error("could not read a digest from {tool}: {output}")]
+    ChecksumUnreadable { tool: &'static str, output: String },
+    #[cfg(feature = "native-ssh")]
+    #[error("{0}")]
+    Native(#[from] native::NativeError),
+}
+
+/// ssh messages for a connection that dropped or timed out, as opposed to one that was refused
+const CONNECTION_LOST: &[&str] = &[
+    "connection reset",
+    "connection timed out",
+    "operation timed out",
+    "timed out",
+    "closed by remote host",
+    "connection closed",
+    "broken pipe",
+    "network is unreachable",
+    "no route to host",
+    "kex_exchange_identification",
+];
+
+impl TransferError {
+    /// Whether the failure is likely transient, so repeating the step may succeed
+    ///
+    /// Lost connections are retried; authentication, permission, host key and
+    /// disk space problems are not, since they fail the same way again. Nor are
+    /// timeouts: a step that ran out of time would most likely do so again, and
+    /// may have been stopped partway through its work.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            // ssh reports its own failures with 255; rsync has dedicated codes for
+            // socket errors (10), a broken data stream (12) and timeouts (30, 35)
+            TransferError::CommandFailed { cmd, code, stderr } => match (cmd.as_str(), code) {
+                ("rsync", 10 | 12 | 30 | 35) => true,
+                (_, 255) => {
+                    let stderr = stderr.to_lowercase();
+                    CONNECTION_LOST.iter().any(|m| stderr.contains(m))
+                }
+                _ => false,
+            },
+            TransferError::IoError { source, .. } => is_transient_io(source),
+            // The data was damaged on the way; sending it again is the fix, which
+            // Transport::upload_verified does as one step
+            TransferError::ChecksumMismatch { .. } => true,
+            #[cfg(feature = "native-ssh")]
+            TransferError::Native(e) => e.is_retryable(),
+            _ => false,
+        }
+    }
+
+    /// Short machine-readable name for the failure, for `--json` output
+    pub fn kind(&self) -> &'static str {
+        match self {
+            TransferError::TimedOut { .. } => "timed_out",
+            TransferError::HostKeyChanged { .. } => "host_key_changed",
+            TransferError::HostKeyUnknown { .. } => "host_key_unknown",
+            TransferError::HostKeyPinMismatch { .. } => "host_key_changed",
+            TransferError::HostKeyNotTrusted { .. } => "host_key_unknown",
+            TransferError::AuthFailed { .. } => "auth_failed",
+            TransferError::ConnectionRefused { .. } => "connection_refused",
+            TransferError::SudoFailed { .. } => "sudo_failed",
+            TransferError::PermissionDenied { .. } => "permission_denied",
+            TransferError::NoSuchFile { .. } | TransferError::LocalNotFound(_) => "no_such_file",
+            TransferError::DiskFull { .. } => "disk_full",
+            TransferError::InsufficientSpace { .. } => "insufficient_space",
+            TransferError::ChecksumMismatch { .. } => "checksum_mismatch",
+            TransferError::ChecksumToolMissing { .. } | TransferError::RemoteToolMissing { .. } => "tool_missing",
+            #[cfg(feature = "native-ssh")]
+            TransferError::Native(e) => e.kind(),
+            _ => "transfer_failed",
+        }
+    }
+}
+
+fn is_transient_io(err: &std::io::Error) -> bool {
+    use std::io::ErrorKind::*;
+    matches!(
+        err.kind(),
+        BrokenPipe | ConnectionReset | ConnectionAborted | TimedOut | UnexpectedEof
+    )
+}
+
+/// How an interrupted upload picks up again, for transfer methods that can resume
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resume {
+    /// Append to a partial copy, then verify the whole file
+    Append,
+    /// Reuse whatever already matches in an existing copy
+    Delta,
+}
+
+/// Produces the data for a streamed upload into the writer it is given
+pub type StreamFn<'a> = &'a mut dyn FnMut(&mut dyn Write) -> Result<(), TransferError>;
+
+/// Operations quick-copy needs from a destination host
+pub trait Transport {
+    /// Ensure the directory holding `path` exists (or `path` itself if it ends with '/')
+    fn ensure_dir(&self, path: &str) -> Result<(), TransferError>;
+
+    /// Check if a file exists
+    fn exists(&self, path: &str) -> Result<bool, TransferError>;
+
+    /// Copy a local file to `remote_path`
+    fn upload(&self, local_path: &Path, remote_path: &str, resume: Resume) -> Result<(), TransferError>;
+
+    /// Whether [`Transport::upload`] picks up a partial copy an earlier upload left at `remote_path`
+    fn can_resume(&self) -> bool {
+        false
+    }
+
+    /// Upload a local file and check that `remote_path` hashes to `expected`
+    fn upload_verified(
+        &self,
+        local_path: &Path,
+        remote_path: &str,
+        resume: Resume,
+        expected: &Digest,
+    ) -> Result<(), TransferError> {
+        self.upload(local_path, remote_path, resume)?;
+        let received = self.checksum(remote_path, expected.algorithm)?;
+        compare_digests(remote_path, expected.clone(), received).map(|_| ())
+    }
+
+    /// Write the data `write` produces into `remote_path` as it is produced
+    fn upload_stream(&self, remote_path: &str, write: StreamFn) -> Result<(), TransferError>;
+
+    /// Unpack the compressed tar `write` produces into `dir` as it arrives
+    fn extract_stream(&self, dir: &str, format: Format, write: StreamFn) -> Result<(), TransferError>;
+
+    /// Copy `remote_path` to a local file
+    fn download(&self, remote_path: &str, local_path: &Path) -> Result<(), TransferError>;
+
+    /// Extract an archive next to itself, returning the extraction directory
+    ///
+    /// The format is taken from the archive's extension.
+    fn extract(&self, archive_path: &str) -> Result<String, TransferError>;
+
+    /// Archive a directory into `staging_dir`, returning the archive path
+    ///
+    /// Symlinks are stored as links unless `follow_symlinks` is set.
+    fn archive(
+        &self,
+        dir_path: &str,
+        excludes: &[String],
+        staging_dir: &str,
+        format: Format,
+        follow_symlinks: bool,
+    ) -> Result<String, TransferError>;
+
+    /// Remove a file
+    fn remove(&self, path: &str) -> Result<(), TransferError>;
+
+    /// Move a file into place, replacing anything already at `to`
+    fn rename(&self, from: &str, to: &str) -> Result<(), TransferError>;
+
+    /// Check that the destination is reachable
+    fn test_connectivity(&self) -> Result<(), TransferError>;
+
+    /// Check whether a program is available on the destination
+    fn has_tool(&self, name: &str) -> Result<bool, TransferError>;
+
+    /// Run the capability probe script for `path` and return what it printed
+    fn probe(&self, path: &str) -> Result<String, TransferError>;
+
+    /// Total size of the files under `path` on the destination, in bytes
+    fn disk_usage(&self, path: &str) -> Result<u64, TransferError>;
+
+    /// Hash a file on the destination
+    fn checksum(&self, path: &str, algorithm: Checksum) -> Result<Digest, TransferError>;
+
+    /// Run a shell command on the destination, returning what it printed
+    fn execute(&self, cmd: &RemoteCommand, label: &str, limit: Option<Duration>) -> Result<String, TransferError>;
+
+    /// Remove `path` from the destination if the command fails or is interrupted
+    fn track(&self, path: &str) -> Artifact;
+
+    /// Like [`Transport::track`], removing `path` by running `cleanup` on the destination
+    fn track_command(&self, path: &str, cleanup: &RemoteCommand) -> Artifact;
+}
+
+/// Open the transport configured for a resolved destination, retrying steps per its policy
+pub fn connect(dest: &ResolvedDest, verbose: bool) -> Retrying {
+    Retrying::new(open(dest, verbose), dest.retry)
+}
+
+fn open(dest: &ResolvedDest, verbose: bool) -> Box<dyn Transport> {
+    let transport = open_login(dest, verbose);
+    match &dest.sudo {
+        Some(target) => Box::new(Sudo::new(transport, target, dest.timeouts)),
+        None => transport,
+    }
+}
+
+/// The transport that acts as the login user
+fn open_login(dest: &ResolvedDest, verbose: bool) -> Box<dyn Transport> {
+    match dest.transport {
+        TransportKind::Ssh => Box::new(SshTransport::new(dest.clone(), verbose)),
+        TransportKind::Local => Box::new(LocalTransport::new(dest.bandwidth_limit, verbose)),
+        #[cfg(feature = "native-ssh")]
+        TransportKind::Native => Box::new(NativeTransport::new(dest.clone(), verbose)),
+        #[cfg(not(feature = "native-ssh"))]
+        TransportKind::Native => {
+            eprintln!("warning: built without the native-ssh feature, using the ssh binary for {}", dest.host);
+            Box::new(SshTransport::new(dest.clone(), verbose))
+        }
+    }
+}
+
+/// Upload to a hidden temporary name next to `remote_path`, then rename it into place
+///
+/// Anything watching the destination directory only ever sees complete files.
+/// The temporary name is fixed per destination, so a transfer method that can
+/// resume picks up a partial upload left by an earlier run; for those the
+/// partial is kept when the upload fails or is interrupted, otherwise it is
+/// removed. With `verify`, the upload is only renamed once its checksum matches
+/// the local file.
+pub fn upload_atomic(
+    transport: &dyn Transport,
+    local_path: &Path,
+    remote_path: &str,
+    resume: Resume,
+    verify: Option<Checksum>,
+) -> Result<Option<Digest>, TransferError> {
+    let temp_path = temp_path(remote_path);
+    warn_if_stale(transport, &temp_path)?;
+
+    let partial = (!transport.can_resume()).then(|| transport.track(&temp_path));
+    let digest = match verify {
+        Some(algorithm) => {
+            let sent = local_digest(local_path, algorithm)?;
+            transport
+                .upload_verified(local_path, &temp_path, resume, &sent)
+                .map_err(|e| match e {
+                    // Name the file the user asked for, not its temporary name
+                    TransferError::ChecksumMismatch { sent, received, .. } => TransferError::ChecksumMismatch {
+                        path: remote_path.to_string(),
+                        sent,
+                        received,
+                    },
+                    e => e,
+                })?;
+            Some(sent)
+        }
+        None => {
+            transport.upload(local_path, &temp_path, resume)?;
+            None
+        }
+    };
+
+    transport.rename(&temp_path, remote_path)?;
+    if let Some(partial) = partial {
+        partial.keep();
+    }
+    Ok(digest)
+}
+
+/// Streamed counterpart of [`upload_atomic`], for data produced on the fly
+///
+/// A stream can't be resumed, so its partial is always removed on failure.
+pub fn upload_stream_atomic(
+    transport: &dyn Transport,
+    remote_path: &str,
+    verify: Option<Checksum>,
+    write: StreamFn,
+) -> Result<Option<Digest>, TransferError> {
+    let temp_path = temp_path(remote_path);
+    warn_if_stale(transport, &temp_path)?;
+
+    let partial = transport.track(&temp_path);
+
+    // Hash what goes into the channel, since there is no local file to hash afterwards
+    let mut sent = None;
+    transport.upload_stream(&temp_path, &mut |out| {
+        let mut out = HashWriter::new(out, verify);
+        write(&mut out)?;
+        sent = out.finish();
+        Ok(())
+    })?;
+
+    let digest = match sent {
+        Some(sent) => {
+            let received = transport.checksum(&temp_path, sent.algorithm)?;
+            Some(compare_digests(remote_path, sent, received)?)
+        }
+        None => None,
+    };
+
+    transport.rename(&temp_path, remote_path)?;
+    partial.keep();
+    Ok(digest)
+}
+
+/// Hash a local file, for comparing against the other end of a transfer
+pub fn local_digest(path: &Path, algorithm: Checksum) -> Result<Digest, TransferError> {
+    checksum::file_digest(path, algorithm).map_err(|source| TransferError::IoError {
+        op: format!("hash {}", path.display()),
+        source,
+    })
+}
+
+/// Fail unless both ends of a transfer hashed to the same digest
+pub fn compare_digests(path: &str, sent: Digest, received: Digest) -> Result<Digest, TransferError> {
+    if sent == received {
+        Ok(sent)
+    } else {
+        Err(TransferError::ChecksumMismatch {
+            path: path.to_string(),
+            sent,
+            received,
+        })
+    }
+}
+
+/// Interpret the output of a remote checksum tool
+fn remote_digest(
+    transport: &dyn Transport,
+    output: Result<String, TransferError>,
+    algorithm: Checksum,
+) -> Result<Digest, TransferError> {
+    let tool = algorithm.tool();
+    let stdout = match output {
+        Ok(stdout) => stdout,
+        Err(_) if !transport.has_tool(tool)? => return Err(TransferError::ChecksumToolMissing { tool }),
+        Err(e) => return Err(e),
+    };
+
+    checksum::parse_digest(&stdout, algorithm).ok_or(TransferError::ChecksumUnreadable { tool, output: stdout })
+}
+
+/// Bytes from the first field of `du -sk` output
+fn parse_du(output: &str) -> Result<u64, TransferError> {
+    output
+        .split_whitespace()
+        .next()
+        .and_then(|kb| kb.parse::<u64>().ok())
+        .map(|kb| kb * 1024)
+        .ok_or_else(|| TransferError::CommandFailed {
+            cmd: "du".to_string(),
+            code: 0,
+            stderr: format!("unexpected output: {}", output.trim()),
+        })
+}
+
+/// Point out a partial upload left behind by an earlier run
+fn warn_if_stale(transport: &dyn Transport, temp_path: &str) -> Result<(), TransferError> {
+    if transport.exists(temp_path)? {
+        eprintln!(
+            "warning: found a partial upload from an earlier run: {} (it will be resumed or replaced)",
+            temp_path
+        );
+    }
+    Ok(())
+}
+
+/// Hidden name an upload is written under until it is complete
+pub fn temp_path(remote_path: &str) -> String {
+    match remote_path.rsplit_once('/') {
+        Some((dir, name)) => format!("{}/.{}.qc-part", dir, name),
+        None => format!(".{}.qc-part", remote_path),
+    }
+}
+
+/// Check whether a program is on the local PATH
+pub fn local_has_tool(name: &str) -> bool {
+    Command::new("which")
+        .arg(name)
+        .output()
+        .map(|o| o.status.success())
+        .unwrap_or(false)
+}
+
+/// Directory that must exist before writing `path`
+fn parent_dir(path: &str) -> String {
+    if path.ends_with('/') {
+        path.to_string()
+    } else {
+        Path::new(path)
+            .parent()
+            .map(|p| p.display().to_string())
+            .unwrap_or_else(|| "/".to_string())
+    }
+}
+
+/// Directory an archive is extracted into: its path without the extension
+pub fn extract_dir(archive_path: &str) -> &str {
+    match Format::from_name(archive_path) {
+        Some(format) => &archive_path[..archive_path.len() - format.extension().len() - 1],
+        None => archive_path,
+    }
+}
+
+/// Path for an archive of `dir_path` made on the destination
+fn remote_archive_path(dir_path: &str, staging_dir: &str, format: Format) -> String {
+    let dir_name = Path::new(dir_path)
+        .file_name()
+        .and_then(|n| n.to_str())
+        .unwrap_or("archive");
+    format!("{}/{}-{}.{}", staging_dir, dir_name, archive::timestamp(), format.extension())
+}
+
+/// tar's option for the compression a format uses
+fn tar_compression(format: Format) -> &'static str {
+    match format {
+        Format::TarZst => "--zstd",
+        _ => "-z",
+    }
+}
+
+/// Remote command that archives the contents of `dir_path` into `archive_path`
+fn pack_command(
+    dir_path: &str,
+    archive_path: &str,
+    excludes: &[String],
+    format: Format,
+    follow_symlinks: bool,
+) -> RemoteCommand {
+    let pack = match format {
+        Format::Zip => {
+            // -y stores symlinks as links rather than the files they point at
+            let flags = if follow_symlinks { "-r" } else { "-ry" };
+            let mut zip = RemoteCommand::new("zip").arg(flags).path(archive_path).arg(".");
+            // Excludes go last since zip reads everything after -x as a pattern
+            if !excludes.is_empty() {
+                zip = zip.arg("-x").args(excludes);
+            }
+            zip
+        }
+        _ => {
+            let mut tar = RemoteCommand::new("tar").args(["-c", tar_compression(format)]);
+            if follow_symlinks {
+                tar = tar.arg("-h");
+            }
+            tar = tar.arg("-f").path(archive_path);
+            // tar matches a pattern against any part of the path, so directory
+            // patterns like "node_modules/" need no trailing slash
+            for pattern in excludes {
+                tar = tar.arg(&format!("--exclude={}", pattern.trim_end_matches('/')));
+            }
+            tar.arg(".")
+        }
+    };
+    RemoteCommand::new("cd").path(dir_path).and(pack)
+}
+
+/// Remote command that extracts `archive_path` into `extract_dir`, printing a line per entry
+///
+/// `program` builds each command, so a wrapper can run them as another user.
+fn unpack_command(
+    archive_path: &str,
+    extract_dir: &str,
+    format: Format,
+    program: impl Fn(&str) -> RemoteCommand,
+) -> RemoteCommand {
+    match format {
+        Format::Zip => program("unzip")
+            .arg("-o")
+            .path(archive_path)
+            .arg("-d")
+            .path(extract_dir),
+        _ => program("mkdir").arg("-p").path(extract_dir).and(
+            program("tar")
+                .args(["-x", "-v", tar_compression(format), "-f"])
+                .path(archive_path)
+                .arg("-C")
+                .path(extract_dir),
+        ),
+    }
+}
+
+/// Remote command that unpacks a tar arriving on stdin into `dir`
+fn unpack_stream_command(dir: &str, format: Format, program: impl Fn(&str) -> RemoteCommand) -> RemoteCommand {
+    program("mkdir").arg("-p").path(dir).and(
+        program("tar")
+            .args(["-x", tar_compression(format), "-f", "-", "-C"])
+            .path(dir),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_upload_atomic_leaves_only_final_file() {
+        assert_eq!(temp_path("/srv/in/data.csv"), "/srv/in/.data.csv.qc-part");
+        assert_eq!(temp_path("data.csv"), ".data.csv.qc-part");
+
+        let tmp = tempfile::tempdir().unwrap();
+        let local = tmp.path().join("data.csv");
+        std::fs::write(&local, b"new").unwrap();
+
+        // A stale partial from an earlier run and an old copy at the destination
+        let dest = tmp.path().join("dest");
+        std::fs::create_dir(&dest).unwrap();
+        let remote = dest.join("data.csv").display().to_string();
+        std::fs::write(temp_path(&remote), b"ne").unwrap();
+        std::fs::write(&remote, b"old").unwrap();
+
+        let transport = LocalTransport::new(None, false);
+        let digest = upload_atomic(&transport, &local, &remote, Resume::Append, Some(Checksum::Sha256)).unwrap();
+
+        assert_eq!(std::fs::read(&remote).unwrap(), b"new");
+        assert_eq!(digest, Some(checksum::file_digest(&local, Checksum::Sha256).unwrap()));
+        let names: Vec<_> = std::fs::read_dir(&dest).unwrap().map(|e| e.unwrap().file_name()).collect();
+        assert_eq!(names, ["data.csv"]);
+    }
+
+    /// Local transport whose uploads go through `upload`, called with the attempt number
+    struct Scripted<F> {
+        inner: LocalTransport,
+        resumable: bool,
+        attempt: std::cell::Cell<u32>,
+        upload: F,
+    }
+
+    impl<F: Fn(u32, &Path, &str, Resume) -> Result<(), TransferError>> Scripted<F> {
+        fn new(resumable: bool, upload: F) -> Self {
+            Self {
+                inner: LocalTransport::new(None, false),
+                resumable,
+                attempt: std::cell::Cell::new(0),
+                upload,
+            }
+        }
+    }
+
+    impl<F: Fn(u32, &Path, &str, Resume) -> Result<(), TransferError>> Transport for Scripted<F> {
+        fn ensure_dir(&self, path: &str) -> Result<(), TransferError> {
+            self.inner.ensure_dir(path)
+        }
+
+        fn exists(&self, path: &str) -> Result<bool, TransferError> {
+            self.inner.exists(path)
+        }
+
+        fn upload(&self, local_path: &Path, remote_path: &str, resume: Resume) -> Result<(), TransferError> {
+            self.attempt.set(self.attempt.get() + 1);
+            (self.upload)(self.attempt.get(), local_path, remote_path, resume)
+        }
+
+        fn can_resume(&self) -> bool {
+            self.resumable
+        }
+
+        fn upload_stream(&self, remote_path: &str, write: StreamFn) -> Result<(), TransferError> {
+            self.inner.upload_stream(remote_path, write)
+        }
+
+        fn extract_stream(&self, dir: &str, format: Format, write: StreamFn) -> Result<(), TransferError> {
+            self.inner.extract_stream(dir, format, write)
+        }
+
+        fn download(&self, remote_path: &str, local_path: &Path) -> Result<(), TransferError> {
+            self.inner.download(remote_path, local_path)
+        }
+
+        fn extract(&self, archive_path: &str) -> Result<String, TransferError> {
+            self.inner.extract(archive_path)
+        }
+
+        fn archive(
+            &self,
+            dir_path: &str,
+            excludes: &[String],
+            staging_dir: &str,
+            format: Format,
+            follow_symlinks: bool,
+        ) -> Result<String, TransferError> {
+            self.inner.archive(dir_path, excludes, staging_dir, format, follow_symlinks)
+        }
+
+        fn remove(&self, path: &str) -> Result<(), TransferError> {
+            self.inner.remove(path)
+        }
+
+        fn rename(&self, from: &str, to: &str) -> Result<(), TransferError> {
+            self.inner.rename(from, to)
+        }
+
+        fn test_connectivity(&self) -> Result<(), TransferError> {
+            self.inner.test_connectivity()
+        }
+
+        fn has_tool(&self, name: &str) -> Result<bool, TransferError> {
+            self.inner.has_tool(name)
+        }
+
+        fn probe(&self, path: &str) -> Result<String, TransferError> {
+            self.inner.probe(path)
+        }
+
+        fn disk_usage(&self, path: &str) -> Result<u64, TransferError> {
+            self.inner.disk_usage(path)
+        }
+
+        fn checksum(&self, path: &str, algorithm: Checksum) -> Result<Digest, TransferError> {
+            self.inner.checksum(path, algorithm)
+        }
+
+        fn execute(&self, cmd: &RemoteCommand, label: &str, limit: Option<Duration>) -> Result<String, TransferError> {
+            self.inner.execute(cmd, label, limit)
+        }
+
+        fn track(&self, path: &str) -> Artifact {
+            self.inner.track(path)
+        }
+
+        fn track_command(&self, path: &str, cleanup: &RemoteCommand) -> Artifact {
+            self.inner.track_command(path, cleanup)
+        }
+    }
+
+    #[test]
+    fn test_interrupted_upload_resumes_from_partial() {
+        let tmp = tempfile::tempdir().unwrap();
+        let local = tmp.path().join("data.bin");
+        let data: Vec<u8> = (0..64 * 1024u32).map(|i| (i % 251) as u8).collect();
+        std::fs::write(&local, &data).unwrap();
+        let remote = tmp.path().join("out.bin").display().to_string();
+
+        // The first attempt dies halfway, the second appends to what it left
+        let upload = |attempt: u32, from: &Path, to: &str, resume: Resume| {
+            let data = std::fs::read(from).unwrap();
+            let have = std::fs::metadata(to).map(|m| m.len() as usize).unwrap_or(0);
+            let end = if attempt == 1 { data.len() / 2 } else { data.len() };
+            assert_eq!(resume, Resume::Append);
+            let mut file = std::fs::OpenOptions::new().create(true).append(true).open(to).unwrap();
+            file.write_all(&data[have..end]).unwrap();
+            match attempt {
+                1 => Err(TransferError::Killed { cmd: "rsync".to_string() }),
+                _ => Ok(()),
+            }
+        };
+
+        let transport = Scripted::new(true, upload);
+        assert!(upload_atomic(&transport, &local, &remote, Resume::Append, None).is_err());
+        assert_eq!(std::fs::read(temp_path(&remote)).unwrap(), &data[..data.len() / 2]);
+        assert!(!Path::new(&remote).exists());
+
+        upload_atomic(&transport, &local, &remote, Resume::Append, Some(Checksum::Sha256)).unwrap();
+        assert_eq!(std::fs::read(&remote).unwrap(), data);
+        assert!(!Path::new(&temp_path(&remote)).exists());
+
+        // A method that can't resume doesn't leave its partial behind
+        let transport = Scripted::new(false, upload);
+        assert!(upload_atomic(&transport, &local, &remote, Resume::Append, None).is_err());
+        assert!(!Path::new(&temp_path(&remote)).exists());
+    }
+
+    #[test]
+    fn test_damaged_upload_is_sent_again() {
+        let tmp = tempfile::tempdir().unwrap();
+        let local = tmp.path().join("data.csv");
+        std::fs::write(&local, b"a,b\n1,2\n").unwrap();
+        let remote = tmp.path().join("out.csv").display().to_string();
+
+        // The first copy arrives with a flipped byte
+        let upload = |attempt: u32, from: &Path, to: &str, _resume: Resume| {
+            let mut data = std::fs::read(from).unwrap();
+            if attempt == 1 {
+                data[0] ^= 1;
+            } else {
+                assert!(!Path::new(to).exists(), "the damaged copy is removed before resending");
+            }
+            std::fs::write(to, data).unwrap();
+            Ok(())
+        };
+        let policy = crate::resolve::RetryPolicy {
+            attempts: 3,
+            backoff: Duration::ZERO,
+            max_delay: Duration::ZERO,
+        };
+        let transport = Retrying::new(Box::new(Scripted::new(false, upload)), policy);
+
+        let digest = upload_atomic(&transport, &local, &remote, Resume::Append, Some(Checksum::Sha256)).unwrap();
+        assert_eq!(std::fs::read(&remote).unwrap(), b"a,b\n1,2\n");
+        assert_eq!(digest, Some(checksum::file_digest(&local, Checksum::Sha256).unwrap()));
+        assert_eq!(transport.attempts(), 2);
+
+        // Without retries the mismatch is reported against the file asked for
+        let once = crate::resolve::RetryPolicy { attempts: 1, ..policy };
+        let transport = Retrying::new(Box::new(Scripted::new(false, upload)), once);
+        match upload_atomic(&transport, &local, &remote, Resume::Append, Some(Checksum::Sha256)) {
+            Err(TransferError::ChecksumMismatch { path, .. }) => assert_eq!(path, remote),
+            other => panic!("expected a checksum mismatch, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn test_archive_commands_follow_format() {
+        assert_eq!(extract_dir("/tmp/app-1.tar.zst"), "/tmp/app-1");
+        assert_eq!(extract_dir("/tmp/app-1.zip"), "/tmp/app-1");
+        assert_eq!(extract_dir("/tmp/app-1"), "/tmp/app-1");
+
+        let excludes = ["node_modules/".to_string()];
+        assert_eq!(
+            pack_command("/srv/app", "/tmp/a.tar.zst", &excludes, Format::TarZst, false).render(),
+            "cd /srv/app && tar -c --zstd -f /tmp/a.tar.zst --exclude=node_modules ."
+        );
+        assert_eq!(
+            pack_command("/srv/app", "/tmp/a.zip", &excludes, Format::Zip, false).render(),
+            "cd /srv/app && zip -ry /tmp/a.zip . -x node_modules/"
+        );
+        assert_eq!(
+            unpack_command("/tmp/a.tar.gz", "/tmp/a", Format::TarGz, RemoteCommand::new).render(),
+            "mkdir -p /tmp/a && tar -x -v -z -f /tmp/a.tar.gz -C /tmp/a"
+        );
+    }
+}
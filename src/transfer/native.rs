@@ -0,0 +1,1193 @@
+use super::{
+    diagnose, extract_dir, pack_command, parse_du, parent_dir, remote_archive_path, remote_digest, unpack_command,
+    unpack_stream_command, RemoteCommand, Resume, StreamFn, Throttled, TransferError, Transport,
+};
+use crate::archive::Format;
+use crate::cancel::Artifact;
+use crate::checksum::{Checksum, Digest};
+use crate::hostkey;
+use crate::probe;
+use crate::progress::{self, Progress, Stage, Unit};
+use crate::resolve::{HostKeyCheck, ResolvedDest};
+use russh::client::{self, Handle};
+use russh::keys::agent::client::AgentClient;
+use russh::keys::agent::AgentIdentity;
+use russh::keys::{Algorithm, PrivateKeyWithHashAlg, PublicKey, PublicKeyOrCertificate};
+use russh::{cipher, compression, kex, mac, ChannelMsg, ChannelReadHalf, ChannelWriteHalf};
+use russh_sftp::client::error::Error as SftpError;
+use russh_sftp::client::SftpSession;
+use russh_sftp::protocol::StatusCode;
+use std::cell::RefCell;
+use std::future::Future;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::runtime::Runtime;
+
+/// How long one SFTP request may wait for its reply. Each step has its own
+/// limit on top, so this only stops a request from waiting forever.
+const SFTP_REQUEST_TIMEOUT_SECS: u64 = 24 * 60 * 60;
+
+#[derive(Error, Debug)]
+pub enum NativeError {
+    #[error("failed to connect to {host}:{port}: {source}")]
+    Connect { host: String, port: u16, source: std::io::Error },
+    #[error("authentication failed for {user}@{host} (tried {tried})")]
+    AuthFailed { user: String, host: String, tried: String },
+    #[error("host key for {0} does not match known_hosts (possible man-in-the-middle). If the host was reinstalled, run: ssh-keygen -R {0}")]
+    HostKeyMismatch(String),
+    #[error("host key for {host} is not in known_hosts. Connect once with: {connect}")]
+    HostKeyUnknown { host: String, connect: String },
+    #[error("host key for {host} does not match the one pinned for it (possible man-in-the-middle). If the host was reinstalled, check its new key and run: qc trust {name}")]
+    HostKeyPinMismatch { host: String, name: String },
+    #[error("{host} has no trusted host key yet. Check its fingerprint and record it with: qc trust {name}")]
+    HostKeyNotTrusted { host: String, name: String },
+    #[error("permission denied: {0}")]
+    PermissionDenied(String),
+    #[error("no such file: {0}")]
+    NoSuchFile(String),
+    #[error("ssh error: {0}")]
+    Ssh(#[from] russh::Error),
+    #[error("sftp error: {0}")]
+    Sftp(#[from] SftpError),
+    #[error("ssh option {option}={value} for {host} is not supported by the native transport. Remove it, or set `transport: ssh` for the host")]
+    UnsupportedOption { host: String, option: String, value: String },
+}
+
+impl NativeError {
+    /// Whether the connection dropped or timed out, rather than being refused for good
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            NativeError::Connect { source, .. } => super::is_transient_io(source),
+            NativeError::Ssh(russh::Error::IO(e)) => super::is_transient_io(e),
+            NativeError::Ssh(e) => matches!(
+                e,
+                russh::Error::Disconnect
+                    | russh::Error::HUP
+                    | russh::Error::ConnectionTimeout
+                    | russh::Error::KeepaliveTimeout
+                    | russh::Error::InactivityTimeout
+                    | russh::Error::SendError
+                    | russh::Error::RecvError
+            ),
+            NativeError::Sftp(e) => match e {
+                SftpError::Status(status) => matches!(
+                    status.status_code,
+                    StatusCode::NoConnection | StatusCode::ConnectionLost
+                ),
+                SftpError::IO(_) | SftpError::Timeout => true,
+                _ => false,
+            },
+            _ => false,
+        }
+    }
+
+    /// Short machine-readable name for the failure, matching `TransferError::kind`
+    pub fn kind(&self) -> &'static str {
+        match self {
+            NativeError::Connect { .. } => "connect_failed",
+            NativeError::AuthFailed { .. } => "auth_failed",
+            NativeError::HostKeyMismatch(_) => "host_key_changed",
+            NativeError::HostKeyUnknown { .. } => "host_key_unknown",
+            NativeError::HostKeyPinMismatch { .. } => "host_key_changed",
+            NativeError::HostKeyNotTrusted { .. } => "host_key_unknown",
+            NativeError::PermissionDenied(_) => "permission_denied",
+            NativeError::NoSuchFile(_) => "no_such_file",
+            NativeError::UnsupportedOption { .. } => "unsupported_option",
+            NativeError::Ssh(_) | NativeError::Sftp(_) => "transfer_failed",
+        }
+    }
+
+    /// Classify an SFTP error for the path it concerns
+    fn sftp(err: SftpError, path: &str) -> Self {
+        match err {
+            SftpError::Status(ref status) if status.status_code == StatusCode::NoSuchFile => {
+                NativeError::NoSuchFile(path.to_string())
+            }
+            SftpError::Status(ref status) if status.status_code == StatusCode::PermissionDenied => {
+                NativeError::PermissionDenied(path.to_string())
+            }
+            _ => NativeError::Sftp(err),
+        }
+    }
+}
+
+/// Run `fut`, failing with `TransferError::TimedOut` if it takes longer than `limit`
+async fn within<T>(
+    step: &str,
+    limit: Option<Duration>,
+    fut: impl Future<Output = Result<T, TransferError>>,
+) -> Result<T, TransferError> {
+    let Some(limit) = limit else {
+        return fut.await;
+    };
+    tokio::time::timeout(limit, fut).await.unwrap_or_else(|_| {
+        Err(TransferError::TimedOut {
+            step: step.to_string(),
+            reason: format!("no response for {}s", limit.as_secs_f64()),
+        })
+    })
+}
+
+/// Turn a read or write that stalled in a `Blocking` stream into `TransferError::TimedOut`
+fn stalled<T>(step: &str, limit: Option<Duration>, result: Result<T, TransferError>) -> Result<T, TransferError> {
+    result.map_err(|e| match (e, limit) {
+        (TransferError::IoError { source, .. }, Some(limit)) if source.kind() == std::io::ErrorKind::TimedOut => {
+            TransferError::TimedOut {
+                step: step.to_string(),
+                reason: format!("no data moved for {}s", limit.as_secs_f64()),
+            }
+        }
+        (e, _) => e,
+    })
+}
+
+/// A blocking `Read`/`Write` over an async SSH stream, for the copy loops
+/// shared with the other transports
+///
+/// Each call gives up with `TimedOut` after `limit`, so a stalled transfer
+/// fails rather than hangs.
+struct Blocking<'a, T> {
+    runtime: &'a Runtime,
+    inner: T,
+    limit: Option<Duration>,
+}
+
+impl<'a, T> Blocking<'a, T> {
+    fn new(runtime: &'a Runtime, inner: T, limit: Option<Duration>) -> Self {
+        Self { runtime, inner, limit }
+    }
+
+    /// Wait for one read or write, giving up after `limit`
+    fn wait<R>(
+        runtime: &Runtime,
+        limit: Option<Duration>,
+        fut: impl Future<Output = std::io::Result<R>>,
+    ) -> std::io::Result<R> {
+        runtime.block_on(async {
+            match limit {
+                Some(limit) => tokio::time::timeout(limit, fut)
+                    .await
+                    .unwrap_or_else(|_| Err(std::io::ErrorKind::TimedOut.into())),
+                None => fut.await,
+            }
+        })
+    }
+}
+
+impl<T: AsyncWrite + Unpin> Blocking<'_, T> {
+    /// Finish the stream, once the remote side has everything written to it
+    fn shutdown(mut self) -> std::io::Result<()> {
+        Self::wait(self.runtime, self.limit, self.inner.shutdown())
+    }
+}
+
+impl<T: AsyncRead + Unpin> Read for Blocking<'_, T> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        Self::wait(self.runtime, self.limit, self.inner.read(buf))
+    }
+}
+
+impl<T: AsyncWrite + Unpin> Write for Blocking<'_, T> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        // Channels refuse empty writes rather than treating them as no-ops
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        Self::wait(self.runtime, self.limit, self.inner.write(buf))
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Self::wait(self.runtime, self.limit, self.inner.flush())
+    }
+}
+
+/// Where an unpinned host's key is checked, as `UserKnownHostsFile` and
+/// `StrictHostKeyChecking` set it
+struct KnownHosts {
+    /// `~/.ssh/known_hosts` unless the host's ssh options name another file
+    file: Option<PathBuf>,
+    /// Record a key seen for the first time instead of refusing it. A key
+    /// that changed is refused either way.
+    accept_new: bool,
+}
+
+/// What a host's `ssh_options` change about the native connection
+struct Options {
+    config: client::Config,
+    known_hosts: KnownHosts,
+}
+
+impl Options {
+    /// Apply the ssh options the native client has an equivalent for, and
+    /// refuse the rest rather than silently connecting without them
+    fn new(dest: &ResolvedDest) -> Result<Self, NativeError> {
+        let mut config = client::Config::default();
+        let mut known_hosts = KnownHosts {
+            file: dirs::home_dir().map(|home| home.join(".ssh/known_hosts")),
+            accept_new: false,
+        };
+
+        for (option, value) in &dest.ssh_options {
+            let unsupported = || NativeError::UnsupportedOption {
+                host: dest.host.clone(),
+                option: option.clone(),
+                value: value.clone(),
+            };
+            let preferred = &mut config.preferred;
+            match option.to_lowercase().as_str() {
+                "serveraliveinterval" => {
+                    let secs: u64 = value.parse().map_err(|_| unsupported())?;
+                    config.keepalive_interval = (secs > 0).then(|| Duration::from_secs(secs));
+                }
+                "serveralivecountmax" => config.keepalive_max = value.parse().map_err(|_| unsupported())?,
+                "compression" => {
+                    preferred.compression = match value.to_lowercase().as_str() {
+                        "yes" => vec![compression::ZLIB_LEGACY, compression::ZLIB, compression::NONE],
+                        "no" => vec![compression::NONE],
+                        _ => return Err(unsupported()),
+                    }
+                    .into()
+                }
+                "ciphers" => {
+                    let names = algorithms(&preferred.cipher, value, |n| cipher::Name::try_from(n).ok());
+                    preferred.cipher = names.ok_or_else(unsupported)?.into();
+                }
+                "kexalgorithms" => {
+                    let names = algorithms(&preferred.kex, value, |n| kex::Name::try_from(n).ok());
+                    preferred.kex = names.ok_or_else(unsupported)?.into();
+                }
+                "macs" => {
+                    let names = algorithms(&preferred.mac, value, |n| mac::Name::try_from(n).ok());
+                    preferred.mac = names.ok_or_else(unsupported)?.into();
+                }
+                "hostkeyalgorithms" => {
+                    let names = algorithms(&preferred.key, value, |n| Algorithm::new(n).ok());
+                    preferred.key = names.ok_or_else(unsupported)?.into();
+                }
+                // ssh checks every file listed; the first is the one it records keys in
+                "userknownhostsfile" => {
+                    let file = value.split_whitespace().next().ok_or_else(unsupported)?;
+                    known_hosts.file = Some(hostkey::expand_home(Path::new(file)));
+                }
+                "stricthostkeychecking" => {
+                    known_hosts.accept_new = match value.to_lowercase().as_str() {
+                        "yes" | "ask" => false,
+                        "accept-new" | "no" | "off" => true,
+                        _ => return Err(unsupported()),
+                    }
+                }
+                // The native client never prompts and keeps no log of its own
+                "batchmode" | "loglevel" => {}
+                _ => return Err(unsupported()),
+            }
+        }
+
+        Ok(Self { config, known_hosts })
+    }
+}
+
+/// Apply an ssh algorithm list to russh's defaults: a plain list replaces
+/// them, and a leading `+`, `-` or `^` appends to, removes from or goes ahead
+/// of them. `None` if any name is one russh doesn't know.
+fn algorithms<T: Clone + PartialEq>(defaults: &[T], value: &str, parse: impl Fn(&str) -> Option<T>) -> Option<Vec<T>> {
+    let (modifier, list) = match value.strip_prefix(['+', '-', '^']) {
+        Some(list) => (value.chars().next(), list),
+        None => (None, value),
+    };
+    let names = list.split(',').map(|n| parse(n.trim())).collect::<Option<Vec<T>>>()?;
+    let others = defaults.iter().filter(|d| !names.contains(d)).cloned();
+    Some(match modifier {
+        Some('+') => defaults.iter().cloned().chain(names.iter().filter(|n| !defaults.contains(n)).cloned()).collect(),
+        Some('-') => others.collect(),
+        Some('^') => names.iter().cloned().chain(others).collect(),
+        _ => names,
+    })
+}
+
+/// Checks the server's host key during the handshake, the way ssh would for the destination
+struct Verifier {
+    dest: ResolvedDest,
+    known_hosts: KnownHosts,
+    /// Why the key was rejected, since russh only reports that it was
+    rejected: Arc<Mutex<Option<NativeError>>>,
+}
+
+impl client::Handler for Verifier {
+    type Error = russh::Error;
+
+    async fn check_server_key(&mut self, key: &PublicKeyOrCertificate) -> Result<bool, Self::Error> {
+        let result = match key {
+            PublicKeyOrCertificate::PublicKey { key, .. } => check_host_key(&self.dest, &self.known_hosts, key),
+            // Host certificates would need a CA to check them against
+            PublicKeyOrCertificate::Certificate(_) => Err(unknown_host_key(&self.dest)),
+        };
+        let accepted = result.is_ok();
+        *self.rejected.lock().unwrap() = result.err();
+        Ok(accepted)
+    }
+}
+
+fn unknown_host_key(dest: &ResolvedDest) -> NativeError {
+    NativeError::HostKeyUnknown {
+        host: dest.host.clone(),
+        connect: dest.ssh_command(),
+    }
+}
+
+fn check_host_key(dest: &ResolvedDest, known_hosts: &KnownHosts, key: &PublicKey) -> Result<(), NativeError> {
+    use russh::keys::{check_known_hosts_path, known_hosts::learn_known_hosts_path, Error::KeyChanged};
+
+    let host = &dest.host;
+    let HostKeyCheck::Pinned { known_hosts: file, fingerprints } = &dest.host_key else {
+        let Some(ref file) = known_hosts.file else {
+            return Err(unknown_host_key(dest));
+        };
+        // A missing known_hosts file just means nothing is known yet
+        return match check_known_hosts_path(host, dest.port, key, file) {
+            Ok(true) => Ok(()),
+            Err(KeyChanged { .. }) => Err(NativeError::HostKeyMismatch(host.clone())),
+            _ if known_hosts.accept_new => learn_known_hosts_path(host, dest.port, key, file)
+                .map_err(|_| unknown_host_key(dest)),
+            _ => Err(unknown_host_key(dest)),
+        };
+    };
+
+    let blob = key.to_bytes().unwrap_or_default();
+    let pinned = fingerprints.is_empty() || fingerprints.contains(&hostkey::fingerprint(&blob));
+    match check_known_hosts_path(host, dest.port, key, file) {
+        Ok(true) if pinned => Ok(()),
+        Ok(true) | Err(KeyChanged { .. }) => Err(NativeError::HostKeyPinMismatch {
+            host: host.clone(),
+            name: dest.name.clone(),
+        }),
+        Ok(false) | Err(_) => Err(NativeError::HostKeyNotTrusted {
+            host: host.clone(),
+            name: dest.name.clone(),
+        }),
+    }
+}
+
+fn connect_err(dest: &ResolvedDest, source: std::io::Error) -> NativeError {
+    NativeError::Connect {
+        host: dest.host.clone(),
+        port: dest.port,
+        source,
+    }
+}
+
+/// Open the TCP connection, trying each address the host resolves to
+async fn dial(dest: &ResolvedDest) -> Result<TcpStream, NativeError> {
+    let addrs = tokio::net::lookup_host((dest.host.as_str(), dest.port))
+        .await
+        .map_err(|e| connect_err(dest, e))?;
+
+    let mut last_err = None;
+    for addr in addrs {
+        let attempt = match dest.timeouts.connect {
+            Some(limit) => tokio::time::timeout(limit, TcpStream::connect(addr))
+                .await
+                .unwrap_or_else(|_| Err(std::io::ErrorKind::TimedOut.into())),
+            None => TcpStream::connect(addr).await,
+        };
+        match attempt {
+            Ok(tcp) => {
+                let _ = tcp.set_nodelay(true);
+                return Ok(tcp);
+            }
+            Err(e) => last_err = Some(e),
+        }
+    }
+    Err(connect_err(dest, last_err.unwrap_or_else(|| std::io::ErrorKind::NotFound.into())))
+}
+
+/// Run the SSH handshake over `stream` and log in as the destination's user
+async fn login<S>(dest: &ResolvedDest, stream: S) -> Result<Handle<Verifier>, NativeError>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let Options { config, known_hosts } = Options::new(dest)?;
+    let rejected = Arc::new(Mutex::new(None));
+    let verifier = Verifier {
+        dest: dest.clone(),
+        known_hosts,
+        rejected: rejected.clone(),
+    };
+    let handshake = client::connect_stream(Arc::new(config), stream, verifier);
+    let handshake = match dest.timeouts.connect {
+        Some(limit) => tokio::time::timeout(limit, handshake)
+            .await
+            .map_err(|_| connect_err(dest, std::io::ErrorKind::TimedOut.into()))?,
+        None => handshake.await,
+    };
+
+    let mut session = match handshake {
+        Ok(session) => session,
+        Err(e) => return Err(rejected.lock().unwrap().take().unwrap_or(NativeError::Ssh(e))),
+    };
+    authenticate(dest, &mut session).await?;
+    Ok(session)
+}
+
+async fn authenticate(dest: &ResolvedDest, session: &mut Handle<Verifier>) -> Result<(), NativeError> {
+    let user = &dest.user;
+    let mut tried = Vec::new();
+    let rsa_hash = session.best_supported_rsa_hash().await?.flatten();
+
+    let keys: Vec<PathBuf> = match dest.identity_file {
+        Some(ref id) => vec![id.clone()],
+        None => {
+            // Same defaults ssh tries, after the agent
+            tried.push("agent".to_string());
+            if authenticate_agent(user, session, rsa_hash).await {
+                return Ok(());
+            }
+            dirs::home_dir()
+                .map(|home| {
+                    ["id_ed25519", "id_ecdsa", "id_rsa"]
+                        .iter()
+                        .map(|k| home.join(".ssh").join(k))
+                        .filter(|p| p.exists())
+                        .collect()
+                })
+                .unwrap_or_default()
+        }
+    };
+
+    for key in keys {
+        tried.push(key.display().to_string());
+        // A key with a passphrase can only be used through the agent
+        let Ok(secret) = russh::keys::load_secret_key(&key, None) else {
+            continue;
+        };
+        let hash = if secret.algorithm().is_rsa() { rsa_hash } else { None };
+        let auth = session
+            .authenticate_publickey(user, PrivateKeyWithHashAlg::new(Arc::new(secret), hash))
+            .await?;
+        if auth.success() {
+            return Ok(());
+        }
+    }
+
+    Err(NativeError::AuthFailed {
+        user: user.clone(),
+        host: dest.host.clone(),
+        tried: tried.join(", "),
+    })
+}
+
+/// Try each key the agent holds; false when none is accepted or there is no agent
+async fn authenticate_agent(
+    user: &str,
+    session: &mut Handle<Verifier>,
+    rsa_hash: Option<russh::keys::HashAlg>,
+) -> bool {
+    let Ok(mut agent) = AgentClient::connect_env().await else {
+        return false;
+    };
+    for identity in agent.request_identities().await.unwrap_or_default() {
+        let AgentIdentity::PublicKey { key, .. } = identity else {
+            continue;
+        };
+        let hash = if key.algorithm().is_rsa() { rsa_hash } else { None };
+        if let Ok(auth) = session.authenticate_publickey_with(user, key, hash, &mut agent).await {
+            if auth.success() {
+                return true;
+            }
+        }
+    }
+    false
+}
+
+/// What a remote command printed, and how it exited (`None` if it was killed)
+struct Finished {
+    code: Option<u32>,
+    stdout: Vec<u8>,
+    stderr: Vec<u8>,
+}
+
+impl Finished {
+    fn result(self, label: &str, dest: &ResolvedDest) -> Result<String, TransferError> {
+        match self.code {
+            Some(0) => Ok(String::from_utf8_lossy(&self.stdout).into_owned()),
+            Some(code) => Err(diagnose::command_failed(
+                label,
+                code as i32,
+                &String::from_utf8_lossy(&self.stderr),
+                dest,
+            )),
+            None => Err(TransferError::Killed { cmd: label.to_string() }),
+        }
+    }
+}
+
+/// Read a command's output until its channel closes
+async fn collect(output: &mut ChannelReadHalf) -> Finished {
+    let mut finished = Finished {
+        code: None,
+        stdout: Vec::new(),
+        stderr: Vec::new(),
+    };
+    while let Some(msg) = output.wait().await {
+        match msg {
+            ChannelMsg::Data { data } => finished.stdout.extend_from_slice(&data),
+            ChannelMsg::ExtendedData { data, ext: 1 } => finished.stderr.extend_from_slice(&data),
+            ChannelMsg::ExitStatus { exit_status } => finished.code = Some(exit_status),
+            _ => {}
+        }
+    }
+    finished
+}
+
+/// Start a command in a channel of its own, returning its output and input
+async fn start(
+    session: &Handle<Verifier>,
+    cmd: &str,
+) -> Result<(ChannelReadHalf, ChannelWriteHalf<client::Msg>), NativeError> {
+    let (output, input) = session.channel_open_session().await?.split();
+    input.exec(true, cmd).await?;
+    Ok((output, input))
+}
+
+/// Wrap a command so closing its channel stops everything it started
+///
+/// sshd only closes the command's pipes when the channel goes, and signals
+/// just the shell it started, so `cd dir && tar ...` would keep running. The
+/// wrapper watches its input instead, which nothing else uses: once that
+/// ends, it kills its own process group.
+fn killable(cmd: &RemoteCommand) -> String {
+    let script = format!(
+        // Background jobs start with no input, so the watcher gets it back through fd 3
+        "exec 3<&0; {{ {}; }} </dev/null 3<&- & pid=$!; \
+         {{ cat >/dev/null; kill 0; }} <&3 3<&- >/dev/null 2>&1 & watch=$!; exec 3<&-; \
+         wait $pid; status=$?; kill $watch 2>/dev/null; exit $status",
+        cmd
+    );
+    RemoteCommand::new("sh").arg("-c").arg(&script).render()
+}
+
+/// Run a command and wait for it to exit
+async fn run(session: &Handle<Verifier>, cmd: &str) -> Result<Finished, NativeError> {
+    let (mut output, _input) = start(session, cmd).await?;
+    Ok(collect(&mut output).await)
+}
+
+/// The SSH session to the destination, and those to the jump hosts it is forwarded through
+struct Connection {
+    session: Handle<Verifier>,
+    _hops: Vec<Handle<Verifier>>,
+}
+
+/// Log in to each jump host in turn, then the destination, each through a
+/// channel the previous one forwards, the way `ssh -J` does
+async fn connect(dest: &ResolvedDest, verbose: bool) -> Result<Connection, NativeError> {
+    let targets = (0..dest.jump.len())
+        .map(|n| dest.hop(n))
+        .chain([dest.clone()]);
+
+    let mut sessions: Vec<Handle<Verifier>> = Vec::new();
+    for target in targets {
+        if verbose {
+            eprintln!("+ connect {}:{} (native)", target.host, target.port);
+        }
+        let session = match sessions.last() {
+            None => login(&target, dial(&target).await?).await?,
+            Some(hop) => {
+                let channel = hop
+                    .channel_open_direct_tcpip(target.host.clone(), target.port.into(), "127.0.0.1", 0)
+                    .await
+                    .map_err(|e| connect_err(&target, std::io::Error::other(e.to_string())))?;
+                login(&target, channel.into_stream()).await?
+            }
+        };
+        sessions.push(session);
+    }
+
+    let session = sessions.pop().expect("the destination is always connected last");
+    Ok(Connection { session, _hops: sessions })
+}
+
+/// Transport that speaks SSH/SFTP in-process instead of spawning ssh and scp
+pub struct NativeTransport {
+    dest: ResolvedDest,
+    verbose: bool,
+    sftp: RefCell<Option<Arc<SftpSession>>>,
+    /// Shared with cleanups, which may run from the interrupt handler
+    connection: Arc<Mutex<Option<Arc<Connection>>>>,
+    /// Whether the host was ever reached, so there may be something to clean up
+    reached: Arc<AtomicBool>,
+    runtime: Arc<Runtime>,
+}
+
+impl NativeTransport {
+    pub fn new(dest: ResolvedDest, verbose: bool) -> Self {
+        // One worker keeps the sessions going while this thread blocks on a
+        // step, and lets an interrupt's cleanup run alongside it
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .enable_all()
+            .build()
+            .expect("failed to start the ssh runtime");
+        Self {
+            dest,
+            verbose,
+            sftp: RefCell::new(None),
+            connection: Arc::new(Mutex::new(None)),
+            reached: Arc::new(AtomicBool::new(false)),
+            runtime: Arc::new(runtime),
+        }
+    }
+
+    /// Connect on first use and reuse the connection afterwards
+    fn connection(&self) -> Result<Arc<Connection>, NativeError> {
+        if let Some(ref connection) = *self.connection.lock().unwrap() {
+            return Ok(connection.clone());
+        }
+
+        let connection = Arc::new(self.runtime.block_on(connect(&self.dest, self.verbose))?);
+        self.reached.store(true, Ordering::SeqCst);
+        *self.connection.lock().unwrap() = Some(connection.clone());
+        Ok(connection)
+    }
+
+    fn sftp(&self) -> Result<Arc<SftpSession>, TransferError> {
+        if let Some(ref sftp) = *self.sftp.borrow() {
+            return Ok(sftp.clone());
+        }
+
+        let connection = self.connection()?;
+        let open = async {
+            let channel = connection.session.channel_open_session().await.map_err(NativeError::from)?;
+            channel.request_subsystem(true, "sftp").await.map_err(NativeError::from)?;
+            let config = russh_sftp::client::Config {
+                request_timeout_secs: SFTP_REQUEST_TIMEOUT_SECS,
+                ..Default::default()
+            };
+            Ok(SftpSession::new_with_config(channel.into_stream(), config)
+                .await
+                .map_err(NativeError::from)?)
+        };
+        let sftp = Arc::new(self.block_on("sftp", self.dest.timeouts.command, open)?);
+        *self.sftp.borrow_mut() = Some(sftp.clone());
+        Ok(sftp)
+    }
+
+    /// Wait for `fut` on the runtime, giving up after `limit`
+    fn block_on<T>(
+        &self,
+        step: &str,
+        limit: Option<Duration>,
+        fut: impl Future<Output = Result<T, TransferError>>,
+    ) -> Result<T, TransferError> {
+        self.checked(self.runtime.block_on(within(step, limit, fut)))
+    }
+
+    /// Drop the cached connection when `result` shows it was lost, so the next
+    /// attempt (such as a retry) connects afresh instead of reusing a dead session
+    fn checked<T>(&self, result: Result<T, TransferError>) -> Result<T, TransferError> {
+        let Err(e) = result else {
+            return result;
+        };
+        let lost = match e {
+            TransferError::Native(ref e) => e.is_retryable(),
+            TransferError::IoError { ref source, .. } => super::is_transient_io(source),
+            // A channel that stopped answering may be wedged along with its session
+            TransferError::TimedOut { .. } => true,
+            _ => false,
+        };
+        let closed = (self.connection.lock().unwrap().as_ref()).is_some_and(|c| c.session.is_closed());
+        if !lost && !closed {
+            return Err(e);
+        }
+
+        self.disconnect();
+        Err(match e {
+            // Streams only say the session closed under them; mark that as the
+            // dropped connection it is, so it is retried like one
+            TransferError::IoError { op, source } if !lost => TransferError::IoError {
+                op,
+                source: std::io::Error::new(std::io::ErrorKind::ConnectionAborted, source),
+            },
+            e => e,
+        })
+    }
+
+    /// Forget the SFTP session and the connection, closing them once nothing uses them
+    fn disconnect(&self) {
+        // Closing the SFTP channel spawns onto the runtime, so it has to happen inside it
+        let _runtime = self.runtime.enter();
+        self.sftp.take();
+        self.connection.lock().unwrap().take();
+    }
+
+    /// Kill a remote command that ran past its time limit, by closing its
+    /// channel. Dropping the future only stops waiting for it, and would leave
+    /// it running on the host.
+    fn kill<T>(
+        &self,
+        input: &ChannelWriteHalf<client::Msg>,
+        result: Result<T, TransferError>,
+    ) -> Result<T, TransferError> {
+        if let Err(TransferError::TimedOut { .. }) = result {
+            let _ = self.runtime.block_on(within("kill", self.dest.timeouts.command, async {
+                Ok(input.close().await.map_err(NativeError::from)?)
+            }));
+        }
+        self.checked(result)
+    }
+
+    /// Run a command on the remote within `limit`, failing on a non-zero exit
+    fn exec(&self, remote_cmd: &RemoteCommand, label: &str, limit: Option<Duration>) -> Result<String, TransferError> {
+        if self.verbose {
+            eprintln!("+ exec {} {}", self.dest.ssh_target(), remote_cmd);
+        }
+
+        let connection = self.connection()?;
+        let cmd = match limit {
+            Some(_) => killable(remote_cmd),
+            None => remote_cmd.render(),
+        };
+        let (mut output, input) = self.block_on(label, self.dest.timeouts.command, async {
+            Ok(start(&connection.session, &cmd).await?)
+        })?;
+        // Keep the output half open past a timeout, since the channel closes with it
+        let finished = self.runtime.block_on(within(label, limit, async { Ok(collect(&mut output).await) }));
+        self.kill(&input, finished)?.result(label, &self.dest)
+    }
+
+    /// Copy between a reader and writer, reporting progress for `stage`
+    fn copy(
+        &self,
+        reader: &mut impl Read,
+        writer: &mut impl Write,
+        total: Option<u64>,
+        stage: Stage,
+        path: &str,
+    ) -> Result<(), TransferError> {
+        let step = format!("transfer {}", path);
+        let mut progress = Progress::start(stage, Unit::Bytes, total);
+        let mut writer = Throttled::new(writer, self.dest.bandwidth_limit);
+        let copied = progress::copy(reader, &mut writer, &mut progress).map_err(|source| TransferError::IoError {
+            op: step.clone(),
+            source,
+        });
+        self.checked(stalled(&step, self.dest.timeouts.stall, copied))?;
+        progress.finish();
+        Ok(())
+    }
+
+    /// Open `remote_path` for writing over SFTP, as a blocking stream
+    fn create(&self, remote_path: &str) -> Result<Blocking<'_, russh_sftp::client::fs::File>, TransferError> {
+        let sftp = self.sftp()?;
+        let create = async { sftp.create(remote_path).await.map_err(|e| NativeError::sftp(e, remote_path).into()) };
+        let file = self.block_on("upload", self.dest.timeouts.command, create)?;
+        Ok(Blocking::new(&self.runtime, file, self.dest.timeouts.stall))
+    }
+
+    /// Close an uploaded file, once the server has confirmed every write
+    fn close(&self, file: Blocking<'_, russh_sftp::client::fs::File>, remote_path: &str) -> Result<(), TransferError> {
+        let step = format!("transfer {}", remote_path);
+        let closed = file.shutdown().map_err(|source| TransferError::IoError {
+            op: step.clone(),
+            source,
+        });
+        self.checked(stalled(&step, self.dest.timeouts.stall, closed))
+    }
+}
+
+impl Drop for NativeTransport {
+    fn drop(&mut self) {
+        self.disconnect();
+    }
+}
+
+impl Transport for NativeTransport {
+    fn ensure_dir(&self, path: &str) -> Result<(), TransferError> {
+        let dir = parent_dir(path);
+        if self.verbose {
+            eprintln!("+ sftp mkdir -p {}", dir);
+        }
+
+        let sftp = self.sftp()?;
+        let mkdir = async {
+            let mut current = PathBuf::new();
+            for component in Path::new(&dir).components() {
+                current.push(component);
+                let current = current.display().to_string();
+                if sftp.metadata(current.as_str()).await.is_err() {
+                    sftp.create_dir(current.as_str())
+                        .await
+                        .map_err(|e| NativeError::sftp(e, &current))?;
+                }
+            }
+            Ok(())
+        };
+        self.block_on("mkdir", self.dest.timeouts.command, mkdir)
+    }
+
+    fn exists(&self, path: &str) -> Result<bool, TransferError> {
+        if self.verbose {
+            eprintln!("+ sftp stat {}", path);
+        }
+
+        let sftp = self.sftp()?;
+        let stat = async {
+            match sftp.metadata(path).await {
+                Ok(_) => Ok(true),
+                Err(e) => match NativeError::sftp(e, path) {
+                    NativeError::NoSuchFile(_) => Ok(false),
+                    other => Err(other.into()),
+                },
+            }
+        };
+        self.block_on("existence check", self.dest.timeouts.command, stat)
+    }
+
+    fn upload(&self, local_path: &Path, remote_path: &str, _resume: Resume) -> Result<(), TransferError> {
+        if self.verbose {
+            eprintln!("+ sftp put {} {}", local_path.display(), remote_path);
+        }
+
+        let mut local = std::fs::File::open(local_path)
+            .map_err(|_| TransferError::LocalNotFound(local_path.display().to_string()))?;
+        let total = local.metadata().map(|m| m.len()).ok();
+
+        let mut remote = self.create(remote_path)?;
+        self.copy(&mut local, &mut remote, total, Stage::Upload, remote_path)?;
+        self.close(remote, remote_path)
+    }
+
+    fn upload_stream(&self, remote_path: &str, write: StreamFn) -> Result<(), TransferError> {
+        if self.verbose {
+            eprintln!("+ sftp put - {}", remote_path);
+        }
+
+        let mut remote = self.create(remote_path)?;
+        let written = write(&mut Throttled::new(&mut remote, self.dest.bandwidth_limit));
+        self.checked(stalled("upload", self.dest.timeouts.stall, written))?;
+        self.close(remote, remote_path)
+    }
+
+    fn extract_stream(&self, dir: &str, format: Format, write: StreamFn) -> Result<(), TransferError> {
+        let untar = unpack_stream_command(dir, format, RemoteCommand::new);
+        if self.verbose {
+            eprintln!("+ exec {} {}", self.dest.ssh_target(), untar);
+        }
+
+        let connection = self.connection()?;
+        let cmd = untar.render();
+        let (mut output, input) = self.block_on("tar", self.dest.timeouts.command, async {
+            Ok(start(&connection.session, &cmd).await?)
+        })?;
+
+        // Read tar's output while writing, so a chatty tar can't stall the channel
+        let finished = self.runtime.spawn(async move { collect(&mut output).await });
+        let mut stdin = Blocking::new(&self.runtime, input.make_writer(), self.dest.timeouts.stall);
+        let written = write(&mut Throttled::new(&mut stdin, self.dest.bandwidth_limit));
+        // tar only exits once its input ends, so end it even when writing failed
+        let closed = stdin.shutdown().map_err(|source| TransferError::IoError {
+            op: "write to tar".to_string(),
+            source,
+        });
+        // A tar that stopped reading won't exit once its input ends either
+        let written = self.kill(&input, stalled("tar", self.dest.timeouts.stall, written.and(closed)));
+
+        // The exit status explains a broken pipe better than the write error does
+        let finished = self.runtime.block_on(within("tar", self.dest.timeouts.unzip, async {
+            finished.await.map_err(|e| TransferError::IoError {
+                op: "tar".to_string(),
+                source: std::io::Error::other(e),
+            })
+        }));
+        self.kill(&input, finished)?.result("tar", &self.dest)?;
+        written
+    }
+
+    fn download(&self, remote_path: &str, local_path: &Path) -> Result<(), TransferError> {
+        if self.verbose {
+            eprintln!("+ sftp get {} {}", remote_path, local_path.display());
+        }
+
+        let sftp = self.sftp()?;
+        let open = async {
+            let file = sftp.open(remote_path).await.map_err(|e| NativeError::sftp(e, remote_path))?;
+            let total = file.metadata().await.ok().and_then(|m| m.size);
+            Ok((file, total))
+        };
+        let (remote, total) = self.block_on("download", self.dest.timeouts.command, open)?;
+        let mut remote = Blocking::new(&self.runtime, remote, self.dest.timeouts.stall);
+
+        let mut local = std::fs::File::create(local_path).map_err(|source| TransferError::IoError {
+            op: format!("create {}", local_path.display()),
+            source,
+        })?;
+        self.copy(&mut remote, &mut local, total, Stage::Download, remote_path)
+    }
+
+    fn extract(&self, archive_path: &str) -> Result<String, TransferError> {
+        let extract_dir = extract_dir(archive_path);
+        let format = Format::from_name(archive_path).unwrap_or_default();
+        let unpack = unpack_command(archive_path, extract_dir, format, RemoteCommand::new);
+        let mut progress = Progress::start(Stage::Extract, Unit::Files, None);
+        let stdout = self.exec(&unpack, "extract", self.dest.timeouts.unzip)?;
+        // unzip indents one line per entry, tar -v prints just the path
+        let entries = stdout.lines().filter(|l| format != Format::Zip || l.starts_with("  "));
+        progress.inc(entries.count() as u64);
+        progress.finish();
+        Ok(extract_dir.to_string())
+    }
+
+    fn archive(
+        &self,
+        dir_path: &str,
+        excludes: &[String],
+        staging_dir: &str,
+        format: Format,
+        follow_symlinks: bool,
+    ) -> Result<String, TransferError> {
+        let archive_path = remote_archive_path(dir_path, staging_dir, format);
+        let pack = pack_command(dir_path, &archive_path, excludes, format, follow_symlinks);
+        let partial = self.track(&archive_path);
+        self.exec(&pack, "archive", self.dest.timeouts.zip)?;
+        partial.keep();
+
+        Ok(archive_path)
+    }
+
+    fn remove(&self, path: &str) -> Result<(), TransferError> {
+        if self.verbose {
+            eprintln!("+ sftp rm {}", path);
+        }
+
+        let sftp = self.sftp()?;
+        let rm = async {
+            match sftp.remove_file(path).await {
+                Ok(()) => Ok(()),
+                Err(e) => match NativeError::sftp(e, path) {
+                    // Match `rm -f`
+                    NativeError::NoSuchFile(_) => Ok(()),
+                    other => Err(other.into()),
+                },
+            }
+        };
+        self.block_on("rm", self.dest.timeouts.command, rm)
+    }
+
+    fn checksum(&self, path: &str, algorithm: Checksum) -> Result<Digest, TransferError> {
+        let output = self.exec(&RemoteCommand::new(algorithm.tool()).path(path), algorithm.tool(), None);
+        remote_digest(self, output, algorithm)
+    }
+
+    fn rename(&self, from: &str, to: &str) -> Result<(), TransferError> {
+        // SFTP v3 rename refuses to replace an existing file, so use mv
+        self.exec(
+            &RemoteCommand::new("mv").arg("-f").path(from).path(to),
+            "mv",
+            self.dest.timeouts.command,
+        )?;
+        Ok(())
+    }
+
+    fn test_connectivity(&self) -> Result<(), TransferError> {
+        let step = format!("ssh to {}", self.dest.host);
+        self.exec(&RemoteCommand::new("echo").arg("ok"), &step, self.dest.timeouts.command)?;
+        Ok(())
+    }
+
+    fn execute(&self, cmd: &RemoteCommand, label: &str, limit: Option<Duration>) -> Result<String, TransferError> {
+        self.exec(cmd, label, limit)
+    }
+
+    fn track(&self, path: &str) -> Artifact {
+        self.track_command(path, &RemoteCommand::new("rm").arg("-f").path(path))
+    }
+
+    fn track_command(&self, path: &str, cleanup: &RemoteCommand) -> Artifact {
+        // An interrupt may land while this thread is mid-transfer, so the
+        // cleanup runs in a channel of its own on the shared runtime
+        let connection = self.connection.clone();
+        let reached = self.reached.clone();
+        let runtime = self.runtime.clone();
+        let dest = self.dest.clone();
+        let cleanup = cleanup.render();
+        let limit = self.dest.timeouts.command;
+
+        Artifact::new(format!("{}:{}", self.dest.host, path), move || {
+            let current = match connection.lock().unwrap().clone() {
+                Some(current) if !current.session.is_closed() => Some(current),
+                // The step that left this behind may have dropped the connection
+                _ if reached.load(Ordering::SeqCst) => None,
+                // Nothing can have been left behind before connecting
+                _ => return,
+            };
+            let _ = runtime.block_on(within("cleanup", limit, async {
+                let current = match current {
+                    Some(current) => current,
+                    None => Arc::new(connect(&dest, false).await?),
+                };
+                Ok(run(&current.session, &cleanup).await?)
+            }));
+        })
+    }
+
+    fn probe(&self, path: &str) -> Result<String, TransferError> {
+        let probe = RemoteCommand::new("sh").arg("-c").arg(&probe::script()).arg("qc-probe").path(path);
+        self.exec(&probe, "probe", self.dest.timeouts.command)
+    }
+
+    fn disk_usage(&self, path: &str) -> Result<u64, TransferError> {
+        let output = self.exec(&RemoteCommand::new("du").arg("-sk").path(path), "du", self.dest.timeouts.zip)?;
+        parse_du(&output)
+    }
+
+    fn has_tool(&self, name: &str) -> Result<bool, TransferError> {
+        let check = RemoteCommand::new("command").arg("-v").arg(name);
+        match self.exec(&check, "command -v", self.dest.timeouts.command) {
+            Ok(_) => Ok(true),
+            Err(TransferError::CommandFailed { .. }) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    const KEY: &str = "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAILrttkpG7Jq0i2gQtL0u28sknhxBrxbzViabh/UTARIT";
+    const FINGERPRINT: &str = "SHA256:eR9PCyCbKpfInJHWZo4l3+gnDFnRWuYFBpe4QgydOuI";
+    const OTHER_KEY: &str = "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAINjMfbccEtZNMUAUzti1Hgzr8X3zSfsjhQHknMlTEbbu";
+
+    fn dest(extra: &str) -> ResolvedDest {
+        let yaml = format!(
+            r#"
+version: 1
+defaults:
+  user: me
+hosts:
+  nas:
+    host: nas.local
+    port: 2222
+    transport: native
+    paths: {{ scratch: /scratch }}
+{}"#,
+            extra
+        );
+        let config: Config = serde_yaml::from_str(&yaml).unwrap();
+        crate::resolve::resolve("nas", &config).unwrap()
+    }
+
+    fn key(line: &str) -> PublicKey {
+        PublicKey::from_openssh(line).unwrap()
+    }
+
+    #[test]
+    fn test_checks_host_key_against_known_hosts() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("known_hosts");
+        let dest = dest("");
+        let check = |accept_new, line| {
+            let known_hosts = KnownHosts {
+                file: Some(file.clone()),
+                accept_new,
+            };
+            check_host_key(&dest, &known_hosts, &key(line))
+        };
+
+        assert!(matches!(check(false, KEY), Err(NativeError::HostKeyUnknown { host, .. }) if host == "nas.local"));
+        std::fs::write(&file, format!("[nas.local]:2222 {}\n", KEY)).unwrap();
+        assert!(check(false, KEY).is_ok());
+        assert!(matches!(check(false, OTHER_KEY), Err(NativeError::HostKeyMismatch(_))));
+        // accept-new records a key it hasn't seen, but never replaces one that changed
+        assert!(matches!(check(true, OTHER_KEY), Err(NativeError::HostKeyMismatch(_))));
+        std::fs::remove_file(&file).unwrap();
+        assert!(check(true, OTHER_KEY).is_ok());
+        assert!(check(false, OTHER_KEY).is_ok());
+    }
+
+    #[test]
+    fn test_checks_pinned_host_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("known_hosts");
+        // A pinned host ignores the ssh-style settings
+        let lenient = KnownHosts {
+            file: None,
+            accept_new: true,
+        };
+        let pinned = dest(&format!("    known_hosts: {}\n    host_keys: [\"{}\"]\n", file.display(), FINGERPRINT));
+        let check = |line| check_host_key(&pinned, &lenient, &key(line));
+
+        assert!(matches!(check(KEY), Err(NativeError::HostKeyNotTrusted { name, .. }) if name == "nas"));
+        std::fs::write(&file, format!("[nas.local]:2222 {}\n", KEY)).unwrap();
+        assert!(check(KEY).is_ok());
+        assert!(matches!(check(OTHER_KEY), Err(NativeError::HostKeyPinMismatch { .. })));
+        // Recorded, but not the fingerprint the host is pinned to
+        std::fs::write(&file, format!("[nas.local]:2222 {}\n", OTHER_KEY)).unwrap();
+        assert!(matches!(check(OTHER_KEY), Err(NativeError::HostKeyPinMismatch { .. })));
+    }
+
+    #[test]
+    fn test_ssh_options_map_to_the_native_client() {
+        let options = Options::new(&dest(
+            r#"    ssh_options:
+      ServerAliveInterval: 15
+      Ciphers: "^aes128-ctr"
+      StrictHostKeyChecking: accept-new
+      UserKnownHostsFile: /tmp/qc-known-hosts
+"#,
+        ))
+        .unwrap();
+        assert_eq!(options.config.keepalive_interval, Some(Duration::from_secs(15)));
+        assert_eq!(options.config.preferred.cipher[0], cipher::AES_128_CTR);
+        assert!(options.config.preferred.cipher.len() > 1);
+        assert_eq!(options.known_hosts.file, Some(PathBuf::from("/tmp/qc-known-hosts")));
+        assert!(options.known_hosts.accept_new);
+
+        for unsupported in ["ForwardAgent: yes", "Ciphers: no-such-cipher", "Compression: maybe"] {
+            let dest = dest(&format!("    ssh_options:\n      {}\n", unsupported));
+            assert!(matches!(Options::new(&dest), Err(NativeError::UnsupportedOption { .. })));
+        }
+    }
+
+    #[test]
+    fn test_only_lost_connections_are_retryable() {
+        use std::io::ErrorKind;
+
+        let connect = |kind: ErrorKind| NativeError::Connect {
+            host: "nas.local".to_string(),
+            port: 22,
+            source: kind.into(),
+        };
+        let status = |status_code| {
+            NativeError::Sftp(SftpError::Status(russh_sftp::protocol::Status {
+                id: 1,
+                status_code,
+                error_message: String::new(),
+                language_tag: String::new(),
+            }))
+        };
+
+        assert!(connect(ErrorKind::TimedOut).is_retryable());
+        assert!(!connect(ErrorKind::ConnectionRefused).is_retryable());
+        assert!(NativeError::Ssh(russh::Error::Disconnect).is_retryable());
+        assert!(NativeError::Ssh(russh::Error::KeepaliveTimeout).is_retryable());
+        assert!(NativeError::Ssh(russh::Error::IO(ErrorKind::ConnectionReset.into())).is_retryable());
+        assert!(!NativeError::Ssh(russh::Error::NotAuthenticated).is_retryable());
+        assert!(status(StatusCode::ConnectionLost).is_retryable());
+        assert!(!status(StatusCode::PermissionDenied).is_retryable());
+        assert!(NativeError::Sftp(SftpError::Timeout).is_retryable());
+        assert!(!NativeError::HostKeyMismatch("nas.local".to_string()).is_retryable());
+        assert!(!NativeError::AuthFailed {
+            user: "me".to_string(),
+            host: "nas.local".to_string(),
+            tried: "agent".to_string(),
+        }
+        .is_retryable());
+    }
+}
@@ -0,0 +1,227 @@
+use super::{RemoteCommand, Resume, StreamFn, TransferError, Transport};
+use crate::archive::Format;
+use crate::cancel::{self, Artifact};
+use crate::checksum::{Checksum, Digest};
+use crate::resolve::RetryPolicy;
+use std::cell::Cell;
+use std::path::Path;
+use std::time::Duration;
+
+/// Wraps a transport so each step is retried when it fails for a transient reason
+///
+/// Every operation retried here is safe to repeat: uploads go to a temporary
+/// name, downloads and archives are rewritten from scratch, mkdir, rm and unzip
+/// are idempotent, and a rename checks whether it already happened. Arbitrary
+/// commands from `execute` may not be, so they run once, and so does the
+/// connectivity probe, so `doctor` reports a down host right away.
+pub struct Retrying {
+    inner: Box<dyn Transport>,
+    policy: RetryPolicy,
+    retries: Cell<u32>,
+}
+
+impl Retrying {
+    pub fn new(inner: Box<dyn Transport>, policy: RetryPolicy) -> Self {
+        Self {
+            inner,
+            policy,
+            retries: Cell::new(0),
+        }
+    }
+
+    /// Tries needed by the step that was retried most (1 when nothing failed)
+    pub fn attempts(&self) -> u32 {
+        self.retries.get() + 1
+    }
+
+    fn retry<T>(&self, step: &str, mut op: impl FnMut() -> Result<T, TransferError>) -> Result<T, TransferError> {
+        let mut attempt = 1;
+        loop {
+            match op() {
+                Err(e) if attempt < self.policy.attempts && e.is_retryable() && !cancel::is_cancelled() => {
+                    let delay = self.policy.delay(attempt);
+                    eprintln!(
+                        "warning: {} failed ({}), retrying in {:.1}s (attempt {}/{})",
+                        step,
+                        e,
+                        delay.as_secs_f64(),
+                        attempt + 1,
+                        self.policy.attempts
+                    );
+                    std::thread::sleep(delay);
+                    self.retries.set(self.retries.get().max(attempt));
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+impl Transport for Retrying {
+    fn ensure_dir(&self, path: &str) -> Result<(), TransferError> {
+        self.retry("mkdir", || self.inner.ensure_dir(path))
+    }
+
+    fn exists(&self, path: &str) -> Result<bool, TransferError> {
+        self.retry("existence check", || self.inner.exists(path))
+    }
+
+    fn upload(&self, local_path: &Path, remote_path: &str, resume: Resume) -> Result<(), TransferError> {
+        self.retry("upload", || self.inner.upload(local_path, remote_path, resume))
+    }
+
+    fn can_resume(&self) -> bool {
+        self.inner.can_resume()
+    }
+
+    fn upload_verified(
+        &self,
+        local_path: &Path,
+        remote_path: &str,
+        resume: Resume,
+        expected: &Digest,
+    ) -> Result<(), TransferError> {
+        let damaged = Cell::new(false);
+        self.retry("upload", || {
+            // Resuming would keep the damaged data, so start from nothing
+            if damaged.take() {
+                self.inner.remove(remote_path)?;
+            }
+            let result = self.inner.upload_verified(local_path, remote_path, resume, expected);
+            damaged.set(matches!(result, Err(TransferError::ChecksumMismatch { .. })));
+            result
+        })
+    }
+
+    fn upload_stream(&self, remote_path: &str, write: StreamFn) -> Result<(), TransferError> {
+        self.retry("upload", || self.inner.upload_stream(remote_path, &mut *write))
+    }
+
+    fn extract_stream(&self, dir: &str, format: Format, write: StreamFn) -> Result<(), TransferError> {
+        self.retry("upload", || self.inner.extract_stream(dir, format, &mut *write))
+    }
+
+    fn download(&self, remote_path: &str, local_path: &Path) -> Result<(), TransferError> {
+        self.retry("download", || self.inner.download(remote_path, local_path))
+    }
+
+    fn extract(&self, archive_path: &str) -> Result<String, TransferError> {
+        self.retry("extract", || self.inner.extract(archive_path))
+    }
+
+    fn archive(
+        &self,
+        dir_path: &str,
+        excludes: &[String],
+        staging_dir: &str,
+        format: Format,
+        follow_symlinks: bool,
+    ) -> Result<String, TransferError> {
+        self.retry("archive", || {
+            self.inner.archive(dir_path, excludes, staging_dir, format, follow_symlinks)
+        })
+    }
+
+    fn remove(&self, path: &str) -> Result<(), TransferError> {
+        self.retry("rm", || self.inner.remove(path))
+    }
+
+    fn rename(&self, from: &str, to: &str) -> Result<(), TransferError> {
+        let mut first = true;
+        self.retry("rename", || {
+            // The connection may have dropped after the rename itself went through
+            if !std::mem::take(&mut first) && !self.inner.exists(from)? && self.inner.exists(to)? {
+                return Ok(());
+            }
+            self.inner.rename(from, to)
+        })
+    }
+
+    fn test_connectivity(&self) -> Result<(), TransferError> {
+        self.inner.test_connectivity()
+    }
+
+    fn has_tool(&self, name: &str) -> Result<bool, TransferError> {
+        self.retry("tool check", || self.inner.has_tool(name))
+    }
+
+    fn probe(&self, path: &str) -> Result<String, TransferError> {
+        self.retry("probe", || self.inner.probe(path))
+    }
+
+    fn disk_usage(&self, path: &str) -> Result<u64, TransferError> {
+        self.retry("du", || self.inner.disk_usage(path))
+    }
+
+    fn checksum(&self, path: &str, algorithm: Checksum) -> Result<Digest, TransferError> {
+        self.retry("checksum", || self.inner.checksum(path, algorithm))
+    }
+
+    fn execute(&self, cmd: &RemoteCommand, label: &str, limit: Option<Duration>) -> Result<String, TransferError> {
+        self.inner.execute(cmd, label, limit)
+    }
+
+    fn track(&self, path: &str) -> Artifact {
+        self.inner.track(path)
+    }
+
+    fn track_command(&self, path: &str, cleanup: &RemoteCommand) -> Artifact {
+        self.inner.track_command(path, cleanup)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_retries_only_transient_failures() {
+        let policy = RetryPolicy {
+            attempts: 3,
+            backoff: Duration::ZERO,
+            max_delay: Duration::ZERO,
+        };
+        let transport = Retrying::new(Box::new(super::super::LocalTransport::new(None, false)), policy);
+
+        let dropped = || TransferError::CommandFailed {
+            cmd: "ssh".to_string(),
+            code: 255,
+            stderr: "Connection to nas.local closed by remote host.".to_string(),
+        };
+        let denied = || TransferError::CommandFailed {
+            cmd: "ssh".to_string(),
+            code: 255,
+            stderr: "me@nas.local: Permission denied (publickey).".to_string(),
+        };
+
+        let mut calls = 0;
+        let result = transport.retry("upload", || {
+            calls += 1;
+            if calls < 3 { Err(dropped()) } else { Ok(()) }
+        });
+        assert!(result.is_ok());
+        assert_eq!(calls, 3);
+        assert_eq!(transport.attempts(), 3);
+
+        let mut calls = 0;
+        let result: Result<(), _> = transport.retry("upload", || {
+            calls += 1;
+            Err(denied())
+        });
+        assert!(result.is_err());
+        assert_eq!(calls, 1);
+
+        let mut calls = 0;
+        let result: Result<(), _> = transport.retry("unzip", || {
+            calls += 1;
+            Err(TransferError::TimedOut {
+                step: "unzip".to_string(),
+                reason: "no result after 60s".to_string(),
+            })
+        });
+        assert!(result.is_err());
+        assert_eq!(calls, 1);
+    }
+}
@@ -0,0 +1,702 @@
+use super::command::quote;
+use super::{
+    diagnose, extract_dir, pack_command, parse_du, local_has_tool, parent_dir, remote_archive_path, remote_digest,
+    unpack_command, unpack_stream_command, RemoteCommand, Resume, StreamFn, Throttled, TransferError, Transport, Watchdog,
+};
+use crate::archive::Format;
+use crate::cancel::{Artifact, ChildGuard};
+use crate::checksum::{Checksum, Digest};
+use crate::config::TransferMethod;
+use crate::probe;
+use crate::progress::{self, Progress, Stage, Unit};
+use crate::resolve::ResolvedDest;
+use std::cell::OnceCell;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, ExitStatus, Output, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+/// How long an orphaned master outlives its last client if quick-copy dies
+/// before it can shut the master down itself
+const CONTROL_PERSIST_SECS: u32 = 60;
+
+/// Transport that shells out to the system `ssh` binary (and `rsync` or `scp`, if selected)
+pub struct SshTransport {
+    dest: ResolvedDest,
+    verbose: bool,
+    control: Option<ControlSocket>,
+    /// Shuts the master down when the transport goes away, or on interrupt
+    _master: Option<Artifact>,
+    rsync_usable: OnceCell<bool>,
+}
+
+/// A private ControlMaster socket shared by every ssh call of one transport
+struct ControlSocket {
+    dir: PathBuf,
+    path: PathBuf,
+}
+
+impl ControlSocket {
+    fn create() -> std::io::Result<Self> {
+        static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+        // Keep the path short: unix sockets are limited to ~100 bytes
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        let dir = std::env::temp_dir().join(format!("qc-{}-{}", std::process::id(), id));
+        std::fs::create_dir(&dir)?;
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(&dir, std::fs::Permissions::from_mode(0o700))?;
+        }
+
+        let path = dir.join("ctl");
+        Ok(Self { dir, path })
+    }
+
+    fn args(&self) -> Vec<String> {
+        vec![
+            "-o".to_string(),
+            "ControlMaster=auto".to_string(),
+            "-o".to_string(),
+            format!("ControlPath={}", self.path.display()),
+            "-o".to_string(),
+            format!("ControlPersist={}", CONTROL_PERSIST_SECS),
+        ]
+    }
+}
+
+impl SshTransport {
+    pub fn new(dest: ResolvedDest, verbose: bool) -> Self {
+        let control = if dest.multiplex && cfg!(unix) {
+            match ControlSocket::create() {
+                Ok(socket) => Some(socket),
+                Err(e) => {
+                    if verbose {
+                        eprintln!("connection sharing disabled: {}", e);
+                    }
+                    None
+                }
+            }
+        } else {
+            None
+        };
+
+        let mut transport = Self {
+            dest,
+            verbose,
+            control,
+            _master: None,
+            rsync_usable: OnceCell::new(),
+        };
+        transport._master = transport.control.as_ref().map(|c| transport.master_teardown(c));
+        transport
+    }
+
+    /// Cleanup that closes the shared connection and removes its socket directory
+    fn master_teardown(&self, control: &ControlSocket) -> Artifact {
+        let (socket, dir) = (control.path.clone(), control.dir.clone());
+        let verbose = self.verbose;
+        let target = self.dest.ssh_target();
+
+        let mut exit = Command::new("ssh");
+        exit.args(self.ssh_args())
+            .args(["-O", "exit"])
+            .arg(&target)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null());
+
+        // An empty label keeps this out of the cancellation report
+        Artifact::new("", move || {
+            // Only a master that was actually started leaves a socket behind
+            if socket.exists() {
+                if verbose {
+                    eprintln!("+ ssh -O exit {}", target);
+                }
+                let _ = exit.status();
+            }
+            let _ = std::fs::remove_dir_all(dir);
+        })
+    }
+
+    /// ssh args for the destination plus connection sharing
+    fn ssh_args(&self) -> Vec<String> {
+        let mut args = self.dest.ssh_args();
+        if let Some(ref control) = self.control {
+            args.extend(control.args());
+        }
+        args
+    }
+
+    /// scp args for the destination plus connection sharing
+    fn scp_args(&self) -> Vec<String> {
+        let mut args = self.dest.scp_args();
+        if let Some(ref control) = self.control {
+            args.extend(control.args());
+        }
+        // scp prints no progress to watch for a stall, so have ssh notice a dead link itself
+        let keepalive = |(key, _): &(String, String)| key.eq_ignore_ascii_case("ServerAliveInterval");
+        if let Some(stall) = self.dest.timeouts.stall {
+            if !self.dest.ssh_options.iter().any(keepalive) {
+                let interval = (stall.as_secs() / 3).max(1);
+                args.extend(["-o".to_string(), format!("ServerAliveInterval={}", interval)]);
+                args.extend(["-o".to_string(), "ServerAliveCountMax=3".to_string()]);
+            }
+        }
+        args
+    }
+
+    /// Build an ssh command that runs `remote_cmd` on the destination
+    fn ssh(&self, remote_cmd: &RemoteCommand) -> Command {
+        let mut cmd = Command::new("ssh");
+        cmd.args(self.ssh_args());
+        cmd.arg(self.dest.ssh_target());
+        cmd.arg(remote_cmd.render());
+
+        if self.verbose {
+            eprintln!("+ ssh {} {}", self.dest.ssh_target(), remote_cmd);
+        }
+
+        cmd
+    }
+
+    /// Run an ssh command to completion within `limit`, failing on a non-zero exit
+    fn run(&self, mut cmd: Command, label: &str, limit: Option<Duration>) -> Result<Output, TransferError> {
+        cmd.stdout(Stdio::piped()).stderr(Stdio::piped());
+        let (child, _guard) = spawn(&mut cmd, label)?;
+        let watchdog = Watchdog::start(&child, label, limit, None);
+        let output = wait(child, label);
+        watchdog.finish()?;
+        let output = output?;
+
+        check_status(&self.dest, output.status, &output.stderr, label)?;
+        Ok(output)
+    }
+
+    /// Run a quick ssh command and report only whether it succeeded
+    fn succeeds(&self, mut cmd: Command, label: &str) -> Result<bool, TransferError> {
+        cmd.stdout(Stdio::null()).stderr(Stdio::null());
+        let (child, _guard) = spawn(&mut cmd, label)?;
+        let watchdog = Watchdog::start(&child, label, self.dest.timeouts.command, None);
+        let output = wait(child, label);
+        watchdog.finish()?;
+        Ok(output?.status.success())
+    }
+
+    /// How transfers should go, falling back to scp if rsync is missing on either end
+    fn method(&self) -> TransferMethod {
+        if self.dest.via != TransferMethod::Rsync {
+            return self.dest.via;
+        }
+
+        let usable = *self.rsync_usable.get_or_init(|| {
+            let missing = if !local_has_tool("rsync") {
+                Some("locally")
+            } else if !self.has_tool("rsync").unwrap_or(false) {
+                Some(self.dest.host.as_str())
+            } else {
+                None
+            };
+
+            match missing {
+                Some(side) => {
+                    eprintln!("rsync not found {}, falling back to scp", side);
+                    false
+                }
+                None => true,
+            }
+        });
+        if usable {
+            TransferMethod::Rsync
+        } else {
+            TransferMethod::Scp
+        }
+    }
+
+    /// Run rsync over ssh, keeping partial files so a rerun can resume
+    fn rsync(&self, from: &str, to: &str, resume: Resume, mut progress: Progress) -> Result<(), TransferError> {
+        // rsync splits the -e value like a shell would
+        let rsh: Vec<String> = std::iter::once("ssh".to_string())
+            .chain(self.ssh_args())
+            .map(|a| quote(&a))
+            .collect();
+
+        let mut cmd = Command::new("rsync");
+        cmd.args(["--partial", "--protect-args", "--progress"]);
+        cmd.arg(resume_flag(resume));
+        if let Some(rate) = self.dest.bandwidth_limit {
+            // rsync takes KiB/s, and treats 0 as no limit
+            cmd.arg(format!("--bwlimit={}", (rate / 1024).max(1)));
+        }
+        cmd.arg("-e").arg(rsh.join(" "));
+        cmd.arg(from);
+        cmd.arg(to);
+        cmd.stdout(Stdio::piped()).stderr(Stdio::piped());
+
+        if self.verbose {
+            eprintln!("+ rsync --partial {} {}", from, to);
+        }
+
+        let (mut child, _guard) = spawn(&mut cmd, "rsync")?;
+        let stderr = drain_stderr(&mut child);
+        let watchdog = Watchdog::start(&child, "rsync", None, self.dest.timeouts.stall);
+
+        // --progress redraws one line with \r: "  1,234,567  45%  1.23MB/s  0:00:12"
+        if let Some(stdout) = child.stdout.take() {
+            let mut last = None;
+            for line in BufReader::new(stdout).split(b'\r') {
+                let Ok(line) = line else { break };
+                if let Some(done) = parse_rsync_progress(&String::from_utf8_lossy(&line)) {
+                    // The line is redrawn even while no data moves
+                    if last != Some(done) {
+                        watchdog.touch();
+                        last = Some(done);
+                    }
+                    progress.set(done);
+                }
+            }
+        }
+
+        let output = wait_drained(child, stderr, "rsync");
+        watchdog.finish()?;
+        let output = output?;
+        check_status(&self.dest, output.status, &output.stderr, "rsync")?;
+
+        progress.finish();
+        Ok(())
+    }
+
+    /// Copy one file with scp, which reports no progress of its own
+    fn scp(&self, from: &str, to: &str, progress: Progress) -> Result<(), TransferError> {
+        let mut cmd = Command::new("scp");
+        cmd.arg("-q");
+        if let Some(rate) = self.dest.bandwidth_limit {
+            // scp takes Kbit/s
+            cmd.arg("-l").arg((rate * 8 / 1000).max(1).to_string());
+        }
+        cmd.args(self.scp_args());
+        cmd.arg("--").arg(from).arg(to);
+
+        if self.verbose {
+            eprintln!("+ scp {} {}", from, to);
+        }
+
+        self.run(cmd, "scp", None)?;
+        progress.finish();
+        Ok(())
+    }
+
+    /// Run `remote_cmd` over ssh with `write` feeding its stdin
+    fn pipe_into(&self, remote_cmd: &RemoteCommand, label: &str, write: StreamFn) -> Result<(), TransferError> {
+        let mut cmd = self.ssh(remote_cmd);
+        cmd.stdin(Stdio::piped()).stdout(Stdio::null()).stderr(Stdio::piped());
+        let (mut child, _guard) = spawn(&mut cmd, label)?;
+        let stderr = drain_stderr(&mut child);
+        let watchdog = Watchdog::start(&child, label, None, self.dest.timeouts.stall);
+
+        let written = match child.stdin.take() {
+            Some(stdin) => write(&mut Throttled::new(watchdog.watch(stdin), self.dest.bandwidth_limit)),
+            None => Ok(()),
+        };
+
+        // A timeout or the exit status explains a broken pipe better than the write error does
+        let output = wait_drained(child, stderr, label);
+        watchdog.finish()?;
+        let output = output?;
+        check_status(&self.dest, output.status, &output.stderr, label)?;
+        written
+    }
+
+    /// Stream a local file into `cat > remote_path` over ssh
+    fn send(&self, local_path: &Path, remote_path: &str) -> Result<(), TransferError> {
+        let mut file = File::open(local_path)
+            .map_err(|_| TransferError::LocalNotFound(local_path.display().to_string()))?;
+        let total = file.metadata().map(|m| m.len()).ok();
+
+        let mut progress = Progress::start(Stage::Upload, Unit::Bytes, total);
+        self.upload_stream(remote_path, &mut |mut stdin| {
+            progress::copy(&mut file, &mut stdin, &mut progress)
+                .map(|_| ())
+                .map_err(|source| TransferError::IoError {
+                    op: format!("upload {}", local_path.display()),
+                    source,
+                })
+        })?;
+
+        progress.finish();
+        Ok(())
+    }
+
+    /// Stream `cat remote_path` over ssh into a local file
+    fn receive(&self, remote_path: &str, local_path: &Path) -> Result<(), TransferError> {
+        let total = self.remote_size(remote_path);
+
+        let mut file = File::create(local_path).map_err(|source| TransferError::IoError {
+            op: format!("create {}", local_path.display()),
+            source,
+        })?;
+
+        let mut cmd = self.ssh(&RemoteCommand::new("cat").path(remote_path));
+        cmd.stdout(Stdio::piped()).stderr(Stdio::piped());
+        let (mut child, _guard) = spawn(&mut cmd, "download")?;
+        let stderr = drain_stderr(&mut child);
+        let watchdog = Watchdog::start(&child, "download", None, self.dest.timeouts.stall);
+
+        let mut progress = Progress::start(Stage::Download, Unit::Bytes, total);
+        let copied = match child.stdout.take() {
+            Some(stdout) => progress::copy(
+                &mut Throttled::new(watchdog.watch(stdout), self.dest.bandwidth_limit),
+                &mut file,
+                &mut progress,
+            ),
+            None => Ok(0),
+        };
+
+        let output = wait_drained(child, stderr, "download");
+        watchdog.finish()?;
+        let output = output?;
+        check_status(&self.dest, output.status, &output.stderr, "download")?;
+        copied.map_err(|source| TransferError::IoError {
+            op: format!("download to {}", local_path.display()),
+            source,
+        })?;
+
+        progress.finish();
+        Ok(())
+    }
+
+    /// Size of a remote file, if it can be determined
+    fn remote_size(&self, path: &str) -> Option<u64> {
+        // wc -c stats regular files rather than reading them
+        let cmd = self.ssh(&RemoteCommand::new("wc").arg("-c").path(path));
+        let output = self.run(cmd, "ssh wc", self.dest.timeouts.command).ok()?;
+        String::from_utf8_lossy(&output.stdout)
+            .split_whitespace()
+            .next()?
+            .parse()
+            .ok()
+    }
+}
+
+/// Start a child process that is killed if quick-copy is interrupted
+fn spawn(cmd: &mut Command, label: &str) -> Result<(Child, ChildGuard), TransferError> {
+    let child = cmd.spawn().map_err(|e| TransferError::ExecError {
+        cmd: label.to_string(),
+        source: e,
+    })?;
+    let guard = ChildGuard::new(&child);
+    Ok((child, guard))
+}
+
+fn wait(child: Child, label: &str) -> Result<Output, TransferError> {
+    child.wait_with_output().map_err(|e| TransferError::ExecError {
+        cmd: label.to_string(),
+        source: e,
+    })
+}
+
+/// Collect a child's stderr on its own thread
+///
+/// Left in the pipe, a remote command with plenty to say (tar or unzip
+/// warning about every entry) would block once the pipe is full, while
+/// quick-copy waits on its stdout or stdin, and look like a stall.
+fn drain_stderr(child: &mut Child) -> JoinHandle<Vec<u8>> {
+    let stderr = child.stderr.take();
+    std::thread::spawn(move || {
+        let mut collected = Vec::new();
+        if let Some(mut stderr) = stderr {
+            let _ = stderr.read_to_end(&mut collected);
+        }
+        collected
+    })
+}
+
+/// Wait for a child whose stderr [`drain_stderr`] is collecting
+fn wait_drained(child: Child, stderr: JoinHandle<Vec<u8>>, label: &str) -> Result<Output, TransferError> {
+    let mut output = wait(child, label)?;
+    output.stderr = stderr.join().unwrap_or_default();
+    Ok(output)
+}
+
+/// Turn a failed exit status into the matching error
+fn check_status(dest: &ResolvedDest, status: ExitStatus, stderr: &[u8], label: &str) -> Result<(), TransferError> {
+    if status.success() {
+        return Ok(());
+    }
+
+    let code = match status.code() {
+        Some(code) => code,
+        None => return Err(TransferError::Killed { cmd: label.to_string() }),
+    };
+    Err(diagnose::command_failed(label, code, &String::from_utf8_lossy(stderr), dest))
+}
+
+/// Bytes transferred so far from one line of rsync --progress output
+fn parse_rsync_progress(line: &str) -> Option<u64> {
+    let mut fields = line.split_whitespace();
+    let bytes = fields.next()?;
+    // Only count lines that look like "<bytes> <pct>%"
+    if !fields.next()?.ends_with('%') {
+        return None;
+    }
+    bytes.replace(',', "").parse().ok()
+}
+
+impl Transport for SshTransport {
+    fn ensure_dir(&self, path: &str) -> Result<(), TransferError> {
+        let dir = parent_dir(path);
+        let cmd = self.ssh(&RemoteCommand::new("mkdir").arg("-p").path(&dir));
+        self.run(cmd, "ssh mkdir", self.dest.timeouts.command)?;
+        Ok(())
+    }
+
+    fn exists(&self, path: &str) -> Result<bool, TransferError> {
+        let cmd = self.ssh(&RemoteCommand::new("test").arg("-e").path(path));
+        self.succeeds(cmd, "ssh")
+    }
+
+    fn upload(&self, local_path: &Path, remote_path: &str, resume: Resume) -> Result<(), TransferError> {
+        if !local_path.exists() {
+            return Err(TransferError::LocalNotFound(local_path.display().to_string()));
+        }
+
+        let total = local_path.metadata().map(|m| m.len()).ok();
+        match self.method() {
+            TransferMethod::Rsync => self.rsync(
+                &local_path.display().to_string(),
+                &self.dest.remote_spec(remote_path),
+                resume,
+                Progress::start(Stage::Upload, Unit::Bytes, total),
+            ),
+            TransferMethod::Scp => self.scp(
+                &local_path.display().to_string(),
+                &self.dest.remote_spec(remote_path),
+                Progress::start(Stage::Upload, Unit::Bytes, total),
+            ),
+            TransferMethod::Ssh => self.send(local_path, remote_path),
+        }
+    }
+
+    fn can_resume(&self) -> bool {
+        // rsync --partial and --append-verify carry on from what is already there
+        self.method() == TransferMethod::Rsync
+    }
+
+    fn upload_stream(&self, remote_path: &str, write: StreamFn) -> Result<(), TransferError> {
+        self.pipe_into(&RemoteCommand::new("cat").write_to(remote_path), "upload", write)
+    }
+
+    fn extract_stream(&self, dir: &str, format: Format, write: StreamFn) -> Result<(), TransferError> {
+        let untar = unpack_stream_command(dir, format, RemoteCommand::new);
+        self.pipe_into(&untar, "tar", write)
+    }
+
+    fn download(&self, remote_path: &str, local_path: &Path) -> Result<(), TransferError> {
+        match self.method() {
+            TransferMethod::Rsync => self.rsync(
+                &self.dest.remote_spec(remote_path),
+                &local_path.display().to_string(),
+                Resume::Delta,
+                Progress::start(Stage::Download, Unit::Bytes, self.remote_size(remote_path)),
+            ),
+            TransferMethod::Scp => self.scp(
+                &self.dest.remote_spec(remote_path),
+                &local_path.display().to_string(),
+                Progress::start(Stage::Download, Unit::Bytes, self.remote_size(remote_path)),
+            ),
+            TransferMethod::Ssh => self.receive(remote_path, local_path),
+        }
+    }
+
+    fn extract(&self, archive_path: &str) -> Result<String, TransferError> {
+        let extract_dir = extract_dir(archive_path);
+        let format = Format::from_name(archive_path).unwrap_or_default();
+        let label = if format == Format::Zip { "ssh unzip" } else { "ssh tar" };
+        let mut cmd = self.ssh(&unpack_command(archive_path, extract_dir, format, RemoteCommand::new));
+        cmd.stdout(Stdio::piped()).stderr(Stdio::piped());
+
+        let (mut child, _guard) = spawn(&mut cmd, label)?;
+        let stderr = drain_stderr(&mut child);
+        let watchdog = Watchdog::start(&child, label, self.dest.timeouts.unzip, None);
+
+        // unzip prints one "  inflating: <path>" style line per entry, tar -v just the path
+        let mut progress = Progress::start(Stage::Extract, Unit::Files, None);
+        if let Some(stdout) = child.stdout.take() {
+            for line in BufReader::new(stdout).lines() {
+                let Ok(line) = line else { break };
+                let action = line.trim_start();
+                if format != Format::Zip
+                    || ["inflating:", "extracting:", "creating:", "linking:"]
+                        .iter()
+                        .any(|a| action.starts_with(a))
+                {
+                    progress.inc(1);
+                }
+            }
+        }
+
+        let output = wait_drained(child, stderr, label);
+        watchdog.finish()?;
+        let output = output?;
+        check_status(&self.dest, output.status, &output.stderr, label)?;
+
+        progress.finish();
+        Ok(extract_dir.to_string())
+    }
+
+    fn archive(
+        &self,
+        dir_path: &str,
+        excludes: &[String],
+        staging_dir: &str,
+        format: Format,
+        follow_symlinks: bool,
+    ) -> Result<String, TransferError> {
+        let archive_path = remote_archive_path(dir_path, staging_dir, format);
+        let cmd = self.ssh(&pack_command(dir_path, &archive_path, excludes, format, follow_symlinks));
+        let partial = self.track(&archive_path);
+        let label = if format == Format::Zip { "ssh zip" } else { "ssh tar" };
+        self.run(cmd, label, self.dest.timeouts.zip)?;
+        partial.keep();
+
+        Ok(archive_path)
+    }
+
+    fn remove(&self, path: &str) -> Result<(), TransferError> {
+        let cmd = self.ssh(&RemoteCommand::new("rm").arg("-f").path(path));
+        self.run(cmd, "ssh rm", self.dest.timeouts.command)?;
+        Ok(())
+    }
+
+    fn checksum(&self, path: &str, algorithm: Checksum) -> Result<Digest, TransferError> {
+        let cmd = self.ssh(&RemoteCommand::new(algorithm.tool()).path(path));
+        let output = self
+            // Hashing takes as long as the file is big, so only the connect timeout applies
+            .run(cmd, algorithm.tool(), None)
+            .map(|o| String::from_utf8_lossy(&o.stdout).into_owned());
+        remote_digest(self, output, algorithm)
+    }
+
+    fn rename(&self, from: &str, to: &str) -> Result<(), TransferError> {
+        let cmd = self.ssh(&RemoteCommand::new("mv").arg("-f").path(from).path(to));
+        self.run(cmd, "ssh mv", self.dest.timeouts.command)?;
+        Ok(())
+    }
+
+    fn test_connectivity(&self) -> Result<(), TransferError> {
+        let mut cmd = Command::new("ssh");
+        cmd.args(self.ssh_args());
+        cmd.args(["-o", "BatchMode=yes"]);
+        cmd.arg(self.dest.ssh_target());
+        cmd.arg("echo ok");
+
+        if self.verbose {
+            eprintln!("+ ssh -o BatchMode=yes {} echo ok", self.dest.ssh_target());
+        }
+
+        self.run(cmd, &format!("ssh to {}", self.dest.host), self.dest.timeouts.command)?;
+        Ok(())
+    }
+
+    fn has_tool(&self, name: &str) -> Result<bool, TransferError> {
+        let cmd = self.ssh(&RemoteCommand::new("command").arg("-v").arg(name));
+        self.succeeds(cmd, "ssh")
+    }
+
+    fn probe(&self, path: &str) -> Result<String, TransferError> {
+        let probe = RemoteCommand::new("sh").arg("-c").arg(&probe::script()).arg("qc-probe").path(path);
+        let mut cmd = Command::new("ssh");
+        cmd.args(self.ssh_args());
+        cmd.arg(self.dest.ssh_target());
+        cmd.arg(probe.render());
+        if self.verbose {
+            eprintln!("+ ssh {} sh -c <probe> {}", self.dest.ssh_target(), path);
+        }
+
+        let output = self.run(cmd, "probe", self.dest.timeouts.command)?;
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    }
+
+    fn disk_usage(&self, path: &str) -> Result<u64, TransferError> {
+        // du walks the whole tree, as zip is about to, so it shares zip's limit
+        let cmd = self.ssh(&RemoteCommand::new("du").arg("-sk").path(path));
+        let output = self.run(cmd, "ssh du", self.dest.timeouts.zip)?;
+        parse_du(&String::from_utf8_lossy(&output.stdout))
+    }
+
+    fn execute(&self, cmd: &RemoteCommand, label: &str, limit: Option<Duration>) -> Result<String, TransferError> {
+        let output = self.run(self.ssh(cmd), &format!("ssh {}", label), limit)?;
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    }
+
+    fn track(&self, path: &str) -> Artifact {
+        self.track_command(path, &RemoteCommand::new("rm").arg("-f").path(path))
+    }
+
+    fn track_command(&self, path: &str, cleanup: &RemoteCommand) -> Artifact {
+        let mut cmd = Command::new("ssh");
+        cmd.args(self.ssh_args())
+            .arg(self.dest.ssh_target())
+            .arg(cleanup.render())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null());
+
+        Artifact::new(format!("{}:{}", self.dest.host, path), move || {
+            let _ = cmd.status();
+        })
+    }
+}
+
+/// rsync's option for how to reuse what the destination already has
+///
+/// Uploads go to a temporary name that is new to the destination unless a
+/// partial is left from an earlier run, and archive names carry a timestamp,
+/// so a delta needs `--fuzzy` to find its basis: the file being replaced, or
+/// the previous archive in the same directory.
+fn resume_flag(resume: Resume) -> &'static str {
+    match resume {
+        Resume::Append => "--append-verify",
+        Resume::Delta => "--fuzzy",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chatty_stderr_does_not_block_stdout() {
+        // Far more than a pipe holds, written before anything reaches stdout
+        let mut cmd = Command::new("sh");
+        cmd.args(["-c", "head -c 1000000 /dev/zero >&2; echo done"])
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        let (mut child, _guard) = spawn(&mut cmd, "sh").unwrap();
+        let stderr = drain_stderr(&mut child);
+
+        let lines: Vec<String> = BufReader::new(child.stdout.take().unwrap()).lines().map(Result::unwrap).collect();
+        let output = wait_drained(child, stderr, "sh").unwrap();
+        assert_eq!(lines, ["done"]);
+        assert_eq!(output.stderr.len(), 1_000_000);
+    }
+
+    #[test]
+    fn test_resume_flags() {
+        assert_eq!(resume_flag(Resume::Append), "--append-verify");
+        assert_eq!(resume_flag(Resume::Delta), "--fuzzy");
+    }
+
+    #[test]
+    fn test_parse_rsync_progress() {
+        assert_eq!(
+            parse_rsync_progress("      1,234,567  45%    1.23MB/s    0:00:12"),
+            Some(1_234_567)
+        );
+        assert_eq!(parse_rsync_progress("data.zip"), None);
+        assert_eq!(parse_rsync_progress("sending incremental file list"), None);
+    }
+}
@@ -0,0 +1,286 @@
+use super::{
+    extract_dir, parent_dir, remote_digest, tar_compression, unpack_command, RemoteCommand, Resume, StreamFn,
+    TransferError, Transport,
+};
+use crate::archive::Format;
+use crate::cancel::Artifact;
+use crate::checksum::{Checksum, Digest};
+use crate::resolve::{Become, TimeoutPolicy};
+use std::cell::{Cell, OnceCell};
+use std::path::Path;
+use std::time::Duration;
+
+/// Wraps a transport so the destination is written as another user, through `sudo -n`
+///
+/// Uploads land in a private staging directory as the login user first, then
+/// are piped into place by the target user, so they end up owned by that user
+/// without the staged copy ever being readable by it. The commands that
+/// change the destination (mkdir, mv, rm, unzip and tar) run as the target
+/// user too. Reads, such as downloads and archiving for `pull`, stay with the
+/// login user, as do files in the staging directory.
+pub struct Sudo {
+    /// The directory `mktemp -d` made for this transfer, removed with it.
+    /// Declared first so it is removed while the connection is still shared.
+    staging: OnceCell<(String, Artifact)>,
+    inner: Box<dyn Transport>,
+    user: String,
+    staging_base: String,
+    staged_count: Cell<u32>,
+    timeouts: TimeoutPolicy,
+}
+
+impl Sudo {
+    pub fn new(inner: Box<dyn Transport>, target: &Become, timeouts: TimeoutPolicy) -> Self {
+        Self {
+            staging: OnceCell::new(),
+            inner,
+            user: target.user.clone(),
+            staging_base: target.staging_dir.trim_end_matches('/').to_string(),
+            staged_count: Cell::new(0),
+            timeouts,
+        }
+    }
+
+    /// `program` run as the target user; sudo fails rather than prompting for a password
+    fn sudo(&self, program: &str) -> RemoteCommand {
+        RemoteCommand::new("sudo").args(["-n", "-u", &self.user, "--", program])
+    }
+
+    /// `program` run as the target user to unpack an archive
+    ///
+    /// tar run by root would otherwise give the files the owners recorded in
+    /// the archive, which are the local user's ids.
+    fn unpacker(&self, program: &str) -> RemoteCommand {
+        match program {
+            "tar" => self.sudo(program).arg("--no-same-owner"),
+            _ => self.sudo(program),
+        }
+    }
+
+    fn run_as(&self, cmd: &RemoteCommand, label: &str, limit: Option<Duration>) -> Result<String, TransferError> {
+        self.inner.execute(cmd, &format!("sudo {}", label), limit)
+    }
+
+    /// Whether `path` is the login user's own, in the staging directory
+    fn is_staged(&self, path: &str) -> bool {
+        self.staging
+            .get()
+            .and_then(|(dir, _)| path.strip_prefix(dir.as_str()))
+            .is_some_and(|rest| rest.starts_with('/'))
+    }
+
+    /// The private staging directory, made on first use
+    ///
+    /// `mktemp -d` picks a name nobody else can have claimed and makes it
+    /// readable by the login user only, so staged files can't be read, replaced
+    /// or redirected through a symlink by other users of a shared `/tmp`.
+    fn staging_dir(&self) -> Result<&str, TransferError> {
+        if let Some((dir, _)) = self.staging.get() {
+            return Ok(dir);
+        }
+        let template = format!("{}/qc-sudo.XXXXXX", self.staging_base);
+        let mktemp = RemoteCommand::new("mktemp").arg("-d").path(&template);
+        let dir = self.inner.execute(&mktemp, "mktemp", self.timeouts.command)?.trim().to_string();
+        let guard = self.inner.track_command(&dir, &RemoteCommand::new("rm").arg("-rf").path(&dir));
+        Ok(&self.staging.get_or_init(|| (dir, guard)).0)
+    }
+
+    /// Staging file for an upload to `remote_path`, removed again when the guard drops
+    fn stage(&self, remote_path: &str) -> Result<(String, Artifact), TransferError> {
+        let dir = self.staging_dir()?;
+        let name = remote_path.rsplit('/').next().unwrap_or(remote_path);
+        self.staged_count.set(self.staged_count.get() + 1);
+        // Numbered, so uploads of one transfer never share a staging file
+        let staged = format!("{}/{}-{}", dir, self.staged_count.get(), name);
+        let guard = self.inner.track(&staged);
+        Ok((staged, guard))
+    }
+
+    /// Copy a staged upload into `remote_path` as the target user
+    ///
+    /// Anything already at `remote_path` is removed first, so the copy is a new
+    /// file owned by the target user with its default mode, rather than taking
+    /// the owner and mode of a leftover.
+    fn install(&self, staged: &str, remote_path: &str) -> Result<(), TransferError> {
+        let write = self
+            .sudo("sh")
+            .args(["-c", "rm -f \"$1\" && cat > \"$1\"", "qc"])
+            .arg(remote_path);
+        // Copying takes as long as the file is big, so only the connect timeout applies
+        self.run_as(&RemoteCommand::new("cat").path(staged).pipe(write), "install", None)?;
+        Ok(())
+    }
+}
+
+impl Transport for Sudo {
+    fn ensure_dir(&self, path: &str) -> Result<(), TransferError> {
+        let mkdir = self.sudo("mkdir").arg("-p").path(&parent_dir(path));
+        self.run_as(&mkdir, "mkdir", self.timeouts.command)?;
+        Ok(())
+    }
+
+    fn exists(&self, path: &str) -> Result<bool, TransferError> {
+        // Print the answer, so a failing sudo isn't mistaken for a missing file
+        let test = self.sudo("sh").args(["-c", "test -e \"$1\" && echo yes || true", "qc"]).arg(path);
+        Ok(self.run_as(&test, "test", self.timeouts.command)?.trim() == "yes")
+    }
+
+    fn upload(&self, local_path: &Path, remote_path: &str, resume: Resume) -> Result<(), TransferError> {
+        let (staged, _guard) = self.stage(remote_path)?;
+        self.inner.upload(local_path, &staged, resume)?;
+        self.install(&staged, remote_path)
+    }
+
+    fn upload_stream(&self, remote_path: &str, write: StreamFn) -> Result<(), TransferError> {
+        let (staged, _guard) = self.stage(remote_path)?;
+        self.inner.upload_stream(&staged, write)?;
+        self.install(&staged, remote_path)
+    }
+
+    fn extract_stream(&self, dir: &str, format: Format, write: StreamFn) -> Result<(), TransferError> {
+        let (staged, _guard) = self.stage(&format!("{}.{}", dir.trim_end_matches('/'), format.extension()))?;
+        self.inner.upload_stream(&staged, write)?;
+
+        let tar = self.unpacker("tar").args(["-x", tar_compression(format), "-f", "-", "-C"]).path(dir);
+        let untar = self
+            .sudo("mkdir")
+            .arg("-p")
+            .path(dir)
+            .and(RemoteCommand::new("cat").path(&staged).pipe(tar));
+        self.run_as(&untar, "tar", self.timeouts.unzip)?;
+        Ok(())
+    }
+
+    fn download(&self, remote_path: &str, local_path: &Path) -> Result<(), TransferError> {
+        self.inner.download(remote_path, local_path)
+    }
+
+    fn extract(&self, archive_path: &str) -> Result<String, TransferError> {
+        let extract_dir = extract_dir(archive_path);
+        let format = Format::from_name(archive_path).unwrap_or_default();
+        let unpack = unpack_command(archive_path, extract_dir, format, |program| self.unpacker(program));
+        self.run_as(&unpack, "extract", self.timeouts.unzip)?;
+        Ok(extract_dir.to_string())
+    }
+
+    fn archive(
+        &self,
+        dir_path: &str,
+        excludes: &[String],
+        staging_dir: &str,
+        format: Format,
+        follow_symlinks: bool,
+    ) -> Result<String, TransferError> {
+        self.inner.archive(dir_path, excludes, staging_dir, format, follow_symlinks)
+    }
+
+    fn remove(&self, path: &str) -> Result<(), TransferError> {
+        if self.is_staged(path) {
+            return self.inner.remove(path);
+        }
+        self.run_as(&self.sudo("rm").arg("-f").path(path), "rm", self.timeouts.command)?;
+        Ok(())
+    }
+
+    fn rename(&self, from: &str, to: &str) -> Result<(), TransferError> {
+        let mv = self.sudo("mv").arg("-f").path(from).path(to);
+        self.run_as(&mv, "mv", self.timeouts.command)?;
+        Ok(())
+    }
+
+    fn test_connectivity(&self) -> Result<(), TransferError> {
+        self.inner.test_connectivity()
+    }
+
+    fn has_tool(&self, name: &str) -> Result<bool, TransferError> {
+        self.inner.has_tool(name)
+    }
+
+    fn probe(&self, path: &str) -> Result<String, TransferError> {
+        self.inner.probe(path)
+    }
+
+    fn disk_usage(&self, path: &str) -> Result<u64, TransferError> {
+        self.inner.disk_usage(path)
+    }
+
+    fn checksum(&self, path: &str, algorithm: Checksum) -> Result<Digest, TransferError> {
+        // Hashing takes as long as the file is big, so only the connect timeout applies
+        let output = self.run_as(&self.sudo(algorithm.tool()).path(path), algorithm.tool(), None);
+        remote_digest(self, output, algorithm)
+    }
+
+    fn execute(&self, cmd: &RemoteCommand, label: &str, limit: Option<Duration>) -> Result<String, TransferError> {
+        self.inner.execute(cmd, label, limit)
+    }
+
+    fn track(&self, path: &str) -> Artifact {
+        if self.is_staged(path) {
+            return self.inner.track(path);
+        }
+        self.inner.track_command(path, &self.sudo("rm").arg("-f").path(path))
+    }
+
+    fn track_command(&self, path: &str, cleanup: &RemoteCommand) -> Artifact {
+        self.inner.track_command(path, cleanup)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transfer::LocalTransport;
+
+    #[test]
+    fn test_only_destination_writes_use_sudo() {
+        let base = tempfile::tempdir().unwrap();
+        let target = Become {
+            user: "svc".to_string(),
+            staging_dir: format!("{}/", base.path().display()),
+        };
+        let timeouts = TimeoutPolicy {
+            connect: None,
+            command: None,
+            zip: None,
+            unzip: None,
+            stall: None,
+        };
+        let transport = Sudo::new(Box::new(LocalTransport::new(None, false)), &target, timeouts);
+
+        assert_eq!(
+            transport.sudo("mkdir").arg("-p").path("/srv/my app").render(),
+            "sudo -n -u svc -- mkdir -p '/srv/my app'"
+        );
+        assert_eq!(
+            transport.unpacker("tar").arg("-x").render(),
+            "sudo -n -u svc -- tar --no-same-owner -x"
+        );
+
+        // Each transfer stages in a fresh directory only the login user can enter
+        let (staged, guard) = transport.stage("/srv/my app/data.txt").unwrap();
+        let (other, other_guard) = transport.stage("/srv/my_app/data.txt").unwrap();
+        let dir = Path::new(&staged).parent().unwrap().to_path_buf();
+        assert_eq!(dir.parent().unwrap(), base.path());
+        assert!(dir.file_name().unwrap().to_string_lossy().starts_with("qc-sudo."));
+        assert_eq!(Path::new(&other).parent().unwrap(), dir);
+        assert_ne!(staged, other);
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::{MetadataExt, PermissionsExt};
+            let meta = std::fs::metadata(&dir).unwrap();
+            assert_eq!(meta.permissions().mode() & 0o777, 0o700);
+            assert_eq!(meta.uid(), std::fs::metadata(base.path()).unwrap().uid());
+        }
+
+        std::fs::write(&staged, b"data").unwrap();
+        drop((guard, other_guard));
+        assert!(!Path::new(&staged).exists());
+        assert!(transport.is_staged(&staged));
+        assert!(!transport.is_staged(&format!("{}x/data.txt", dir.display())));
+
+        // The directory goes with the transport
+        drop(transport);
+        assert!(!dir.exists());
+    }
+}
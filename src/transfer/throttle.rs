@@ -0,0 +1,83 @@
+use std::io::{Read, Write};
+use std::time::{Duration, Instant};
+
+/// How often, at most, a throttled stream pauses to stay under its rate
+const SLICES_PER_SEC: u64 = 10;
+
+/// Caps the average rate of data through a reader or writer
+pub struct Throttled<T> {
+    inner: T,
+    /// Bytes per second; `None` passes data through untouched
+    rate: Option<u64>,
+    started: Instant,
+    bytes: u64,
+}
+
+impl<T> Throttled<T> {
+    pub fn new(inner: T, rate: Option<u64>) -> Self {
+        Self {
+            inner,
+            rate,
+            started: Instant::now(),
+            bytes: 0,
+        }
+    }
+
+    /// Largest chunk to pass in one call, so pauses stay short and progress smooth
+    fn chunk(&self, len: usize) -> usize {
+        match self.rate {
+            Some(rate) => len.min((rate / SLICES_PER_SEC).max(1) as usize),
+            None => len,
+        }
+    }
+
+    /// Count `n` more bytes and sleep until they are within the rate
+    fn account(&mut self, n: usize) {
+        let Some(rate) = self.rate else { return };
+        self.bytes += n as u64;
+
+        let due = Duration::from_secs_f64(self.bytes as f64 / rate as f64);
+        if let Some(wait) = due.checked_sub(self.started.elapsed()) {
+            std::thread::sleep(wait);
+        }
+    }
+}
+
+impl<W: Write> Write for Throttled<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.inner.write(&buf[..self.chunk(buf.len())])?;
+        self.account(n);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<R: Read> Read for Throttled<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let len = self.chunk(buf.len());
+        let n = self.inner.read(&mut buf[..len])?;
+        self.account(n);
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_throttled_write_keeps_to_rate() {
+        let data = vec![0u8; 40 * 1024];
+        let mut out = Throttled::new(Vec::new(), Some(100 * 1024));
+
+        let started = Instant::now();
+        out.write_all(&data).unwrap();
+
+        // 40 KiB at 100 KiB/s takes about 0.4s
+        assert!(started.elapsed() >= Duration::from_millis(350));
+        assert_eq!(out.inner, data);
+    }
+}
@@ -0,0 +1,175 @@
+use super::TransferError;
+use crate::cancel;
+use std::io::{Read, Write};
+use std::process::Child;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+/// How often the watchdog checks on its child
+const TICK: Duration = Duration::from_millis(200);
+
+/// Stops a child process that runs too long or stops making progress
+///
+/// The child is sent SIGTERM, which ends its blocking reads and writes, and
+/// `finish` turns the kill into `TransferError::TimedOut` for the step.
+pub struct Watchdog {
+    step: String,
+    shared: Arc<Shared>,
+    /// Dropping this tells the thread to stop watching
+    stop: Option<Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+struct Shared {
+    started: Instant,
+    /// Milliseconds after `started` of the last sign of progress
+    last_progress: AtomicU64,
+    /// Why the child was stopped, once it was
+    fired: Mutex<Option<String>>,
+}
+
+impl Watchdog {
+    /// Watch `child`, stopping it after `limit` in total or `stall` without progress
+    pub fn start(child: &Child, step: &str, limit: Option<Duration>, stall: Option<Duration>) -> Self {
+        let shared = Arc::new(Shared {
+            started: Instant::now(),
+            last_progress: AtomicU64::new(0),
+            fired: Mutex::new(None),
+        });
+
+        let (stop, thread) = if limit.is_some() || stall.is_some() {
+            let (tx, rx) = mpsc::channel::<()>();
+            let pid = child.id();
+            let shared = Arc::clone(&shared);
+            let thread = std::thread::spawn(move || loop {
+                if rx.recv_timeout(TICK) != Err(RecvTimeoutError::Timeout) {
+                    return;
+                }
+                if let Some(reason) = shared.expired(limit, stall) {
+                    *shared.fired.lock().unwrap_or_else(|e| e.into_inner()) = Some(reason);
+                    cancel::kill(pid);
+                    return;
+                }
+            });
+            (Some(tx), Some(thread))
+        } else {
+            (None, None)
+        };
+
+        Self {
+            step: step.to_string(),
+            shared,
+            stop,
+            thread,
+        }
+    }
+
+    /// Record that data moved, restarting the stall timer
+    pub fn touch(&self) {
+        let now = self.shared.started.elapsed().as_millis() as u64;
+        self.shared.last_progress.store(now, Ordering::Relaxed);
+    }
+
+    /// Wrap a reader or writer so every byte through it counts as progress
+    pub fn watch<T>(&self, inner: T) -> Watched<'_, T> {
+        Watched { inner, watchdog: self }
+    }
+
+    /// Stop watching, failing if the child had to be stopped
+    pub fn finish(mut self) -> Result<(), TransferError> {
+        self.stop_thread();
+        let fired = self.shared.fired.lock().unwrap_or_else(|e| e.into_inner()).take();
+        match fired {
+            Some(reason) => Err(TransferError::TimedOut {
+                step: std::mem::take(&mut self.step),
+                reason,
+            }),
+            None => Ok(()),
+        }
+    }
+
+    fn stop_thread(&mut self) {
+        self.stop.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for Watchdog {
+    fn drop(&mut self) {
+        self.stop_thread();
+    }
+}
+
+impl Shared {
+    /// The limit that has run out, if any, described for the error message
+    fn expired(&self, limit: Option<Duration>, stall: Option<Duration>) -> Option<String> {
+        let elapsed = self.started.elapsed();
+        if let Some(limit) = limit.filter(|l| elapsed >= *l) {
+            return Some(format!("still running after {}s", limit.as_secs_f64()));
+        }
+
+        let last = Duration::from_millis(self.last_progress.load(Ordering::Relaxed));
+        stall
+            .filter(|s| elapsed.saturating_sub(last) >= *s)
+            .map(|s| format!("no progress for {}s", s.as_secs_f64()))
+    }
+}
+
+/// A reader or writer whose traffic keeps a [`Watchdog`] from firing
+pub struct Watched<'a, T> {
+    inner: T,
+    watchdog: &'a Watchdog,
+}
+
+impl<W: Write> Write for Watched<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        if n > 0 {
+            self.watchdog.touch();
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<R: Read> Read for Watched<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        if n > 0 {
+            self.watchdog.touch();
+        }
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process::{Command, Stdio};
+
+    #[test]
+    fn test_stalled_child_is_stopped() {
+        let mut child = Command::new("sleep").arg("30").stdout(Stdio::null()).spawn().unwrap();
+        let watchdog = Watchdog::start(&child, "ssh zip", None, Some(Duration::from_millis(300)));
+
+        let started = Instant::now();
+        child.wait().unwrap();
+        assert!(started.elapsed() < Duration::from_secs(10));
+
+        match watchdog.finish() {
+            Err(TransferError::TimedOut { step, reason }) => {
+                assert_eq!(step, "ssh zip");
+                assert_eq!(reason, "no progress for 0.3s");
+            }
+            other => panic!("expected a timeout, got {:?}", other),
+        }
+    }
+}